@@ -0,0 +1,110 @@
+/*!
+Build script
+
+Records the git commit and build time for the `/version` endpoint.
+
+When the `embed-static` feature is enabled, generates a table of every file
+under `static/` so the frontend can be bundled into the binary and served
+from memory. See `src/assets.rs`.
+*/
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+/// Recursively collect all files under `dir`, skipping `.gitkeep` placeholders
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.file_name().map(|name| name != ".gitkeep").unwrap_or(false) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+
+fn content_etag(path: &Path) -> io::Result<String> {
+    let mut bytes = vec![];
+    fs::File::open(path)?.read_to_end(&mut bytes)?;
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    Ok(format!("\"{:016x}\"", hasher.finish()))
+}
+
+
+fn embed_static(static_dir: &Path, out_file: &Path) -> io::Result<()> {
+    let mut files = vec![];
+    collect_files(static_dir, &mut files)?;
+
+    // sorted by relative path so lookups can binary-search
+    let mut assets = files.iter().map(|path| {
+        let rel = path.strip_prefix(static_dir).expect("file should be under static dir");
+        let rel = rel.components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+            .join("/");
+        let modified = fs::metadata(path)?.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Ok((rel, path.to_string_lossy().into_owned(), content_etag(path)?, modified))
+    }).collect::<io::Result<Vec<_>>>()?;
+    assets.sort();
+
+    let mut out = fs::File::create(out_file)?;
+    writeln!(out, "pub static ASSETS: &'static [Asset] = &[")?;
+    for &(ref rel, ref full, ref etag, modified) in &assets {
+        writeln!(out, "    Asset {{ path: {:?}, etag: {:?}, modified: {}, data: include_bytes!({:?}) }},",
+                 rel, etag, modified, full)?;
+        println!("cargo:rerun-if-changed={}", full);
+    }
+    writeln!(out, "];")?;
+    Ok(())
+}
+
+
+/// Run git with `args`, returning its trimmed output if it succeeded
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git").args(args).output().ok()
+        .and_then(|out| if out.status.success() { String::from_utf8(out.stdout).ok() } else { None })
+        .map(|out| out.trim().to_string())
+}
+
+
+/// Set `ORG_DEMO_GIT_SHA` and `ORG_DEMO_BUILD_TIMESTAMP` (unix seconds) for the crate.
+///
+/// The timestamp is taken from `SOURCE_DATE_EPOCH` if set, otherwise it's the
+/// time of the commit being built so it changes along with the sha. Falls back
+/// to the current time outside of a git checkout.
+fn build_info() {
+    let sha = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    let timestamp = env::var("SOURCE_DATE_EPOCH").ok()
+        .or_else(|| git(&["log", "-1", "--format=%ct"]))
+        .and_then(|secs| secs.trim().parse::<u64>().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+    println!("cargo:rustc-env=ORG_DEMO_GIT_SHA={}", sha);
+    println!("cargo:rustc-env=ORG_DEMO_BUILD_TIMESTAMP={}", timestamp);
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
+
+
+fn main() {
+    build_info();
+    println!("cargo:rerun-if-changed=static");
+    if env::var_os("CARGO_FEATURE_EMBED_STATIC").is_none() { return; }
+
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR not set"));
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR not set"));
+    embed_static(&manifest_dir.join("static"), &out_dir.join("static_assets.rs"))
+        .expect("Failed to embed static files");
+}
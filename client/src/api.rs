@@ -0,0 +1,280 @@
+/*!
+Request and response bodies of the json api
+
+Shared by the server's router and `Client`.
+*/
+use models::{OrgInfo, UserInfo, User as UserRow, LinodeInfo, Webhook, Delivery};
+
+
+// ------------------------------------------
+// ----------- Request bodies ---------------
+// ------------------------------------------
+
+/// `POST /api/create/org`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateOrg {
+    pub name: String,
+}
+
+
+/// `POST /api/create/user`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateUser {
+    pub org_ids: Vec<i64>,
+    pub email: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+}
+
+
+/// `POST /api/create/linode`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateLinode {
+    pub org_id: i64,
+    pub name: String,
+}
+
+
+/// `POST /api/update/org/{id}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateOrg {
+    pub name: String,
+}
+
+
+/// `POST /api/update/user/{id}`, fields left out are unchanged and
+/// an empty `display_name` clears it
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UpdateUser {
+    pub email: Option<String>,
+    pub display_name: Option<String>,
+}
+
+
+/// `POST /api/authenticate`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Authenticate {
+    pub email: String,
+}
+
+
+/// `POST /api/update/linode/{id}`, fields left out are unchanged
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UpdateLinode {
+    pub name: Option<String>,
+    pub org_id: Option<i64>,
+}
+
+
+/// `POST /api/create/member` and `POST /api/delete/member`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Member {
+    pub user_id: i64,
+    pub org_id: i64,
+}
+
+
+/// An id in a batch operation: either a literal id, or `"$N"` for
+/// the id created by the batch's `N`th (zero based) operation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum IdRef {
+    Id(i64),
+    Ref(String),
+}
+
+
+/// A single operation of a `POST /api/batch`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    CreateOrg { name: String },
+    CreateUser { email: String, org_ids: Vec<IdRef> },
+    CreateLinode { name: String, org_id: IdRef },
+    AddMember { user_id: IdRef, org_id: IdRef },
+    UpdateOrg { id: IdRef, name: String },
+    UpdateUser { id: IdRef, email: String },
+    UpdateLinode { id: IdRef, name: Option<String>, org_id: Option<IdRef> },
+    DeleteOrg { id: IdRef },
+    DeleteUser { id: IdRef },
+    DeleteLinode { id: IdRef },
+    RemoveMember { user_id: IdRef, org_id: IdRef },
+}
+impl Operation {
+    /// The operation's `op` tag
+    pub fn name(&self) -> &'static str {
+        use self::Operation::*;
+        match *self {
+            CreateOrg { .. } => "create_org",
+            CreateUser { .. } => "create_user",
+            CreateLinode { .. } => "create_linode",
+            AddMember { .. } => "add_member",
+            UpdateOrg { .. } => "update_org",
+            UpdateUser { .. } => "update_user",
+            UpdateLinode { .. } => "update_linode",
+            DeleteOrg { .. } => "delete_org",
+            DeleteUser { .. } => "delete_user",
+            DeleteLinode { .. } => "delete_linode",
+            RemoveMember { .. } => "remove_member",
+        }
+    }
+}
+
+
+/// `POST /api/batch`, operations are applied in order
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Batch {
+    pub operations: Vec<Operation>,
+}
+
+
+/// `POST /api/admin/create/webhook`. A secret is generated if none is given,
+/// `org_id` limits the subscription to one org's events and an empty `events`
+/// subscribes to every event kind.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateWebhook {
+    pub url: String,
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub org_id: Option<i64>,
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+
+// ------------------------------------------
+// ----------- Response bodies --------------
+// ------------------------------------------
+
+/// `GET /api/orgs`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Orgs {
+    pub orgs: Vec<OrgInfo>,
+}
+
+
+/// `GET /api/org/{id}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Org {
+    pub org: OrgInfo,
+}
+
+
+/// `GET /api/user/{id}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct User {
+    pub user: UserInfo,
+}
+
+
+/// `GET /api/users`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Users {
+    pub users: Vec<UserRow>,
+}
+
+
+/// `GET /api/linodes`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Linodes {
+    pub linodes: Vec<LinodeInfo>,
+}
+
+
+/// `GET /api/linode/{id}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Linode {
+    pub linode: LinodeInfo,
+}
+
+
+/// `GET /api/exists/{org|user|linode}/{name}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Exists {
+    pub exists: bool,
+}
+
+
+/// `POST /api/create/org`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrgCreated {
+    pub org_id: i64,
+}
+
+
+/// `POST /api/create/user`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserCreated {
+    pub user_id: i64,
+}
+
+
+/// `POST /api/create/linode`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LinodeCreated {
+    pub linode_id: i64,
+}
+
+
+/// `POST /api/update/*` and `POST /api/delete/*`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Success {
+    pub success: bool,
+}
+
+
+/// Result of a single batch operation, `id` is set for creates
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OperationResult {
+    pub op: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+}
+
+
+/// `POST /api/batch`, one result per operation
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchApplied {
+    pub results: Vec<OperationResult>,
+}
+
+
+/// `GET /api/admin/webhooks`, secrets are left out
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Webhooks {
+    pub webhooks: Vec<Webhook>,
+}
+
+
+/// `POST /api/admin/create/webhook`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookCreated {
+    pub webhook_id: i64,
+    pub secret: String,
+}
+
+
+/// `GET /api/admin/deliveries`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Deliveries {
+    pub deliveries: Vec<Delivery>,
+}
+
+
+/// `POST /api/admin/replay/deliveries`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Replayed {
+    pub replayed: u64,
+}
+
+
+/// `GET /version`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Version {
+    pub version: String,
+    pub git_sha: String,
+    /// rfc3339
+    pub build_time: String,
+    pub migrations: Vec<String>,
+}
@@ -0,0 +1,406 @@
+/*!
+Typed client for the OrgDemo http api
+
+The request and response bodies (`api`) and the models they carry
+(`models`) are defined here and shared with the server, so this crate
+doesn't depend on the server itself.
+
+Error responses from the server are mapped back to an `ErrorKind` by
+status (`BadRequest`, `Forbidden`, `DoesNotExist`, `Conflict`,
+`PreconditionFailed`, `PayloadTooLarge`, `Unprocessable`, `RateLimited`),
+or `ErrorKind::Server` for anything else.
+
+```rust,ignore
+extern crate org_demo_client;
+use org_demo_client::Client;
+
+let client = Client::new("http://localhost:3002")?;
+let org_id = client.create_org("Cat Collective")?;
+for org in client.orgs()? {
+    println!("{}: {} users", org.name, org.users.len());
+}
+```
+
+The `/api/admin` routes (webhooks and their deliveries) need the server's
+`admin_token`, set with `Client::with_admin_token`.
+*/
+#![recursion_limit = "1024"]
+
+#[macro_use] extern crate error_chain;
+#[macro_use] extern crate serde_derive;
+#[macro_use] extern crate serde_json;
+extern crate reqwest;
+extern crate serde;
+
+pub mod models;
+pub mod api;
+
+use std::io::Read;
+
+pub use models::{OrgInfo, UserInfo, User, Linode, LinodeInfo, Org, UserLinode, Webhook, Delivery};
+
+
+// error-chain 0.11 implements the deprecated `Error::description` and `Error::cause`
+#[allow(deprecated)]
+pub mod errors {
+    use reqwest;
+    use serde_json;
+
+    error_chain! {
+        foreign_links {
+            Http(reqwest::Error);
+            Url(reqwest::UrlError);
+            Json(serde_json::Error);
+            Io(::std::io::Error);
+        }
+        errors {
+            BadRequest(s: String) {
+                description("Bad request")
+                display("BadRequest: {}", s)
+            }
+            Forbidden(s: String) {
+                description("Forbidden")
+                display("Forbidden: {}", s)
+            }
+            DoesNotExist(s: String) {
+                description("Query result does not exist")
+                display("DoesNotExist Error: {}", s)
+            }
+            Conflict(s: String) {
+                description("Conflict")
+                display("Conflict: {}", s)
+            }
+            PreconditionFailed(s: String) {
+                description("Precondition failed")
+                display("PreconditionFailed: {}", s)
+            }
+            PayloadTooLarge(s: String) {
+                description("Payload too large")
+                display("PayloadTooLarge: {}", s)
+            }
+            Unprocessable(s: String) {
+                description("Unprocessable request")
+                display("Unprocessable: {}", s)
+            }
+            RateLimited(retry_after: Option<u64>) {
+                description("Rate limited")
+                display("RateLimited: retry after {:?} seconds", retry_after)
+            }
+            Server(status: u16, s: String) {
+                description("Server error")
+                display("Server Error ({}): {}", status, s)
+            }
+        }
+    }
+}
+use errors::*;
+
+
+/// Client for a running OrgDemo server
+pub struct Client {
+    base: reqwest::Url,
+    http: reqwest::Client,
+    admin_token: Option<String>,
+}
+impl Client {
+    /// Create a new client for the server at `base_url`, e.g. `http://localhost:3002`
+    pub fn new(base_url: &str) -> Result<Self> {
+        let base = reqwest::Url::parse(base_url)?;
+        if base.cannot_be_a_base() {
+            bail!(ErrorKind::BadRequest(format!("Invalid server url: {}", base_url)));
+        }
+        Ok(Self {
+            base: base,
+            http: reqwest::Client::new(),
+            admin_token: None,
+        })
+    }
+
+    /// Send `token` as a bearer token with requests to the `/api/admin` routes
+    pub fn with_admin_token(mut self, token: &str) -> Self {
+        self.admin_token = Some(token.to_string());
+        self
+    }
+
+    /// Build a url from path segments. Segments are percent-encoded.
+    fn url(&self, segments: &[&str]) -> reqwest::Url {
+        let mut url = self.base.clone();
+        url.path_segments_mut()
+            .expect("base url checked in `Client::new`")
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+
+    /// Convert a response into `T`, mapping error statuses to the
+    /// corresponding `ErrorKind`
+    fn parse<T: serde::de::DeserializeOwned>(resp: reqwest::Response) -> Result<T> {
+        let body = Self::text(resp)?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /// Read the body of a successful response, mapping error statuses to the
+    /// corresponding `ErrorKind`
+    fn text(mut resp: reqwest::Response) -> Result<String> {
+        let status = resp.status();
+        let retry_after = resp.headers().get("Retry-After")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|secs| secs.trim().parse::<u64>().ok());
+        let mut body = String::new();
+        resp.read_to_string(&mut body)?;
+        if status.is_success() {
+            return Ok(body)
+        }
+        match status.as_u16() {
+            400 => bail!(ErrorKind::BadRequest(body)),
+            403 => bail!(ErrorKind::Forbidden(body)),
+            404 => bail!(ErrorKind::DoesNotExist(body)),
+            409 => bail!(ErrorKind::Conflict(body)),
+            412 => bail!(ErrorKind::PreconditionFailed(body)),
+            413 => bail!(ErrorKind::PayloadTooLarge(body)),
+            422 => bail!(ErrorKind::Unprocessable(body)),
+            429 => bail!(ErrorKind::RateLimited(retry_after)),
+            code => bail!(ErrorKind::Server(code, body)),
+        }
+    }
+
+    fn get<T: serde::de::DeserializeOwned>(&self, segments: &[&str]) -> Result<T> {
+        let resp = self.http.get(self.url(segments)).send()?;
+        Self::parse(resp)
+    }
+
+    fn post<B: serde::Serialize, T: serde::de::DeserializeOwned>(&self, segments: &[&str], body: &B) -> Result<T> {
+        let resp = self.http.post(self.url(segments)).json(body).send()?;
+        Self::parse(resp)
+    }
+
+    /// Add the admin token, if any, as an `Authorization: Bearer` header
+    fn admin(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self.admin_token {
+            Some(ref token) => req.header("Authorization", format!("Bearer {}", token)),
+            None => req,
+        }
+    }
+
+    fn admin_get<T: serde::de::DeserializeOwned>(&self, url: reqwest::Url) -> Result<T> {
+        let resp = self.admin(self.http.get(url)).send()?;
+        Self::parse(resp)
+    }
+
+    fn admin_post<B: serde::Serialize, T: serde::de::DeserializeOwned>(&self, segments: &[&str], body: &B) -> Result<T> {
+        let resp = self.admin(self.http.post(self.url(segments))).json(body).send()?;
+        Self::parse(resp)
+    }
+
+    // ---- Probes ----
+
+    /// Check that the server is up
+    pub fn healthz(&self) -> Result<()> {
+        Self::text(self.http.get(self.url(&["healthz"])).send()?)?;
+        Ok(())
+    }
+
+    /// The server's version, build and applied migrations
+    pub fn version(&self) -> Result<api::Version> {
+        self.get(&["version"])
+    }
+
+    // ---- Grabbing data ----
+
+    /// All orgs along with their users and linodes
+    pub fn orgs(&self) -> Result<Vec<OrgInfo>> {
+        let resp: api::Orgs = self.get(&["api", "orgs"])?;
+        Ok(resp.orgs)
+    }
+
+    /// An org along with its users and linodes
+    pub fn org(&self, id: i64) -> Result<OrgInfo> {
+        let resp: api::Org = self.get(&["api", "org", &id.to_string()])?;
+        Ok(resp.org)
+    }
+
+    /// A user along with their orgs and available linodes
+    pub fn user(&self, id: i64) -> Result<UserInfo> {
+        let resp: api::User = self.get(&["api", "user", &id.to_string()])?;
+        Ok(resp.user)
+    }
+
+    pub fn users(&self) -> Result<Vec<User>> {
+        let resp: api::Users = self.get(&["api", "users"])?;
+        Ok(resp.users)
+    }
+
+    pub fn linode(&self, id: i64) -> Result<LinodeInfo> {
+        let resp: api::Linode = self.get(&["api", "linode", &id.to_string()])?;
+        Ok(resp.linode)
+    }
+
+    pub fn linodes(&self) -> Result<Vec<LinodeInfo>> {
+        let resp: api::Linodes = self.get(&["api", "linodes"])?;
+        Ok(resp.linodes)
+    }
+
+    /// Check that `email` belongs to an active user, returning the user
+    /// with the linodes they can access. Fails with `Forbidden` for deactivated users.
+    pub fn authenticate(&self, email: &str) -> Result<UserInfo> {
+        let body = api::Authenticate { email: email.to_string() };
+        let resp: api::User = self.post(&["api", "authenticate"], &body)?;
+        Ok(resp.user)
+    }
+
+    // ---- Checking if things exist ----
+
+    pub fn org_exists(&self, name: &str) -> Result<bool> {
+        let resp: api::Exists = self.get(&["api", "exists", "org", name])?;
+        Ok(resp.exists)
+    }
+
+    pub fn user_exists(&self, email: &str) -> Result<bool> {
+        let resp: api::Exists = self.get(&["api", "exists", "user", email])?;
+        Ok(resp.exists)
+    }
+
+    pub fn linode_exists(&self, name: &str) -> Result<bool> {
+        let resp: api::Exists = self.get(&["api", "exists", "linode", name])?;
+        Ok(resp.exists)
+    }
+
+    // ---- Creating things ----
+
+    /// Create an org, returning its id
+    pub fn create_org(&self, name: &str) -> Result<i64> {
+        let body = api::CreateOrg { name: name.to_string() };
+        let resp: api::OrgCreated = self.post(&["api", "create", "org"], &body)?;
+        Ok(resp.org_id)
+    }
+
+    /// Create a user belonging to the given orgs, returning its id
+    pub fn create_user(&self, email: &str, org_ids: &[i64]) -> Result<i64> {
+        let body = api::CreateUser { email: email.to_string(), org_ids: org_ids.to_vec(), display_name: None };
+        let resp: api::UserCreated = self.post(&["api", "create", "user"], &body)?;
+        Ok(resp.user_id)
+    }
+
+    /// Create a linode belonging to an org, returning its id
+    pub fn create_linode(&self, name: &str, org_id: i64) -> Result<i64> {
+        let body = api::CreateLinode { name: name.to_string(), org_id: org_id };
+        let resp: api::LinodeCreated = self.post(&["api", "create", "linode"], &body)?;
+        Ok(resp.linode_id)
+    }
+
+    pub fn add_member(&self, user_id: i64, org_id: i64) -> Result<()> {
+        let body = api::Member { user_id: user_id, org_id: org_id };
+        let _: api::Success = self.post(&["api", "create", "member"], &body)?;
+        Ok(())
+    }
+
+    /// Apply `operations` in a single transaction, returning a result per operation
+    pub fn batch(&self, operations: Vec<api::Operation>) -> Result<Vec<api::OperationResult>> {
+        let body = api::Batch { operations: operations };
+        let resp: api::BatchApplied = self.post(&["api", "batch"], &body)?;
+        Ok(resp.results)
+    }
+
+    // ---- Updating things ----
+
+    pub fn rename_org(&self, id: i64, name: &str) -> Result<()> {
+        let body = api::UpdateOrg { name: name.to_string() };
+        let _: api::Success = self.post(&["api", "update", "org", &id.to_string()], &body)?;
+        Ok(())
+    }
+
+    pub fn update_user_email(&self, id: i64, email: &str) -> Result<()> {
+        let body = api::UpdateUser { email: Some(email.to_string()), ..api::UpdateUser::default() };
+        self.update_user(id, &body)
+    }
+
+    pub fn update_user(&self, id: i64, update: &api::UpdateUser) -> Result<()> {
+        let _: api::Success = self.post(&["api", "update", "user", &id.to_string()], update)?;
+        Ok(())
+    }
+
+    /// Deactivate a user, who keeps their memberships but loses access to their orgs' linodes
+    pub fn deactivate_user(&self, id: i64) -> Result<()> {
+        let _: api::Success = self.post(&["api", "deactivate", "user", &id.to_string()], &json!({}))?;
+        Ok(())
+    }
+
+    pub fn reactivate_user(&self, id: i64) -> Result<()> {
+        let _: api::Success = self.post(&["api", "reactivate", "user", &id.to_string()], &json!({}))?;
+        Ok(())
+    }
+
+    pub fn update_linode(&self, id: i64, update: &api::UpdateLinode) -> Result<()> {
+        let _: api::Success = self.post(&["api", "update", "linode", &id.to_string()], update)?;
+        Ok(())
+    }
+
+    // ---- Deleting things ----
+
+    pub fn delete_org(&self, id: i64) -> Result<()> {
+        let _: api::Success = self.post(&["api", "delete", "org", &id.to_string()], &json!({}))?;
+        Ok(())
+    }
+
+    pub fn delete_user(&self, id: i64) -> Result<()> {
+        let _: api::Success = self.post(&["api", "delete", "user", &id.to_string()], &json!({}))?;
+        Ok(())
+    }
+
+    pub fn delete_linode(&self, id: i64) -> Result<()> {
+        let _: api::Success = self.post(&["api", "delete", "linode", &id.to_string()], &json!({}))?;
+        Ok(())
+    }
+
+    pub fn remove_member(&self, user_id: i64, org_id: i64) -> Result<()> {
+        let body = api::Member { user_id: user_id, org_id: org_id };
+        let _: api::Success = self.post(&["api", "delete", "member"], &body)?;
+        Ok(())
+    }
+
+    // ---- Webhooks, with the admin token ----
+
+    pub fn webhooks(&self) -> Result<Vec<Webhook>> {
+        let resp: api::Webhooks = self.admin_get(self.url(&["api", "admin", "webhooks"]))?;
+        Ok(resp.webhooks)
+    }
+
+    /// Subscribe `webhook.url` to events, returning the new webhook's id and its signing secret
+    pub fn create_webhook(&self, webhook: &api::CreateWebhook) -> Result<api::WebhookCreated> {
+        self.admin_post(&["api", "admin", "create", "webhook"], webhook)
+    }
+
+    pub fn delete_webhook(&self, id: i64) -> Result<()> {
+        let _: api::Success = self.admin_post(&["api", "admin", "delete", "webhook", &id.to_string()], &json!({}))?;
+        Ok(())
+    }
+
+    /// The latest deliveries, optionally only those with the given `status`.
+    /// The server returns up to 100 when `limit` isn't given.
+    pub fn deliveries(&self, status: Option<&str>, limit: Option<i64>) -> Result<Vec<Delivery>> {
+        let mut url = self.url(&["api", "admin", "deliveries"]);
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some(status) = status {
+                query.append_pair("status", status);
+            }
+            if let Some(limit) = limit {
+                query.append_pair("limit", &limit.to_string());
+            }
+        }
+        let resp: api::Deliveries = self.admin_get(url)?;
+        Ok(resp.deliveries)
+    }
+
+    /// Queue a delivery to be sent again
+    pub fn replay_delivery(&self, id: i64) -> Result<()> {
+        let _: api::Success = self.admin_post(&["api", "admin", "replay", "delivery", &id.to_string()], &json!({}))?;
+        Ok(())
+    }
+
+    /// Queue every delivery that ran out of attempts to be sent again, returning how many were
+    pub fn replay_deliveries(&self) -> Result<u64> {
+        let resp: api::Replayed = self.admin_post(&["api", "admin", "replay", "deliveries"], &json!({}))?;
+        Ok(resp.replayed)
+    }
+}
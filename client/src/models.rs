@@ -0,0 +1,311 @@
+/*!
+Models shared by the api's response bodies (see `api`), and by the server,
+whose storage backends return them
+*/
+
+
+/// A row of the `OrgInfo` query:
+/// (org.id, org.name, user.id, user.email, linode.id, linode.name)
+pub type OrgInfoRow = (i64, String, Option<i64>, Option<String>, Option<i64>, Option<String>);
+
+/// A row of the `UserInfo` query:
+/// (user.id, user.email, org.id, org.name, linode.id, linode.name, linode.org, `UserProfile`)
+pub type UserInfoRow = (i64, String, Option<i64>, Option<String>, Option<i64>, Option<String>, Option<i64>, UserProfile);
+
+
+/// Profile columns of a user: (display_name, active, created_at, updated_at, last_seen)
+pub type UserProfile = (Option<String>, bool, i64, i64, Option<i64>);
+
+
+/// A user, or an org member. Members only carry their `id` and `email`,
+/// the profile fields are filled when listing users. Timestamps are unix seconds.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct User {
+    pub id: Option<i64>,
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    /// Deactivated users can't authenticate and aren't listed as org members
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<i64>,
+}
+impl User {
+    /// A user with all of its profile fields
+    pub fn with_profile(id: i64, email: String, profile: UserProfile) -> Self {
+        let (display_name, active, created_at, updated_at, last_seen) = profile;
+        User {
+            id: Some(id),
+            email: Some(email),
+            display_name: display_name,
+            active: Some(active),
+            created_at: Some(created_at),
+            updated_at: Some(updated_at),
+            last_seen: last_seen,
+        }
+    }
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Linode {
+    pub id: Option<i64>,
+    pub name: Option<String>,
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LinodeInfo {
+    pub id: i64,
+    pub name: String,
+    pub org: Option<i64>,
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrgInfo {
+    pub id: i64,
+    pub name: String,
+    pub users: Vec<User>,
+    pub linodes: Vec<Linode>,
+}
+impl OrgInfo {
+    fn add_row(orgs: &mut Vec<OrgInfo>, row: OrgInfoRow) {
+        let (org_id, org_name, user_id, email, linode_id, linode_name) = row;
+        let user = User {
+            id: user_id,
+            email: email,
+            ..User::default()
+        };
+        let linode = Linode {
+            id: linode_id,
+            name: linode_name,
+        };
+        let org = OrgInfo {
+            id: org_id,
+            name: org_name,
+            users: if user.id.is_some() { vec![user] } else { vec![] },
+            linodes: if linode.id.is_some() { vec![linode] } else { vec![] },
+        };
+        orgs.push(org);
+    }
+
+    fn extract_row(orgs: &mut Vec<OrgInfo>, row: OrgInfoRow) {
+        if orgs.last().is_none() {
+            Self::add_row(orgs, row);
+            return;
+        }
+        let add_row = orgs.last().map(|org| org.id != row.0).unwrap_or(true);
+        if add_row { Self::add_row(orgs, row); }
+        else {
+            let (_, _, user_id, email, linode_id, linode_name) = row;
+            let prev = orgs.last_mut().unwrap();
+            let user = User {
+                id: user_id,
+                email: email,
+                ..User::default()
+            };
+            if user.id.is_some() && !prev.users.iter().any(|existing| existing.id == user.id) {
+                prev.users.push(user);
+            }
+            let linode = Linode {
+                id: linode_id,
+                name: linode_name,
+            };
+            if linode.id.is_some() && !prev.linodes.iter().any(|existing| existing.id == linode.id) {
+                prev.linodes.push(linode);
+            }
+        }
+    }
+
+    /// Collect `OrgInfo`s from the rows of a query joining orgs to their users
+    /// and linodes, ordered by `org.id, user.id, linode.id`
+    pub fn from_rows<T: IntoIterator<Item=OrgInfoRow>>(rows: T) -> Vec<OrgInfo> {
+        let mut orgs = vec![];
+        for row in rows {
+            Self::extract_row(&mut orgs, row);
+        }
+        orgs
+    }
+}
+
+
+/// Builds `OrgInfo`s one at a time from the rows of the `OrgInfo` query,
+/// for reading orgs without collecting all of them
+#[derive(Default)]
+pub struct OrgInfoRows {
+    orgs: Vec<OrgInfo>,
+}
+impl OrgInfoRows {
+    pub fn new() -> Self {
+        Self { orgs: vec![] }
+    }
+
+    /// Add the next row, returning the previous org once a row of the next one is seen
+    pub fn push(&mut self, row: OrgInfoRow) -> Option<OrgInfo> {
+        OrgInfo::extract_row(&mut self.orgs, row);
+        if self.orgs.len() > 1 { Some(self.orgs.remove(0)) } else { None }
+    }
+
+    /// The last org, once every row has been added
+    pub fn finish(mut self) -> Option<OrgInfo> {
+        self.orgs.pop()
+    }
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Org {
+    pub id: Option<i64>,
+    pub name: Option<String>,
+}
+
+
+/// Total number of rows in each table
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Counts {
+    pub orgs: i64,
+    pub users: i64,
+    pub linodes: i64,
+}
+
+
+/// Entry in the change feed, `data` is json. `request_id` is the api
+/// request that made the change, if it was made through the api.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub id: i64,
+    pub kind: String,
+    pub data: String,
+    pub request_id: Option<String>,
+}
+
+
+/// Webhook subscription. `org` is `None` for global subscriptions,
+/// an empty `events` matches every event kind. Subscriptions of a deleted
+/// org are detached: `org` is cleared, `detached_org` set, and no new
+/// events match them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    #[serde(skip_serializing, default)]
+    pub secret: String,
+    pub org: Option<i64>,
+    pub events: Vec<String>,
+    #[serde(default)]
+    pub detached_org: Option<i64>,
+}
+impl Webhook {
+    /// Parse the comma separated `events` column
+    pub fn parse_events(events: &str) -> Vec<String> {
+        events.split(',').map(str::trim).filter(|kind| !kind.is_empty()).map(String::from).collect()
+    }
+}
+
+
+/// Attempted delivery of an event to a webhook
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    pub id: i64,
+    pub webhook: i64,
+    pub event: i64,
+    pub kind: String,
+    pub payload: String,
+    /// `pending`, `delivered` or `dead`
+    pub status: String,
+    pub attempts: i64,
+    /// Unix timestamp
+    pub next_attempt: i64,
+    pub last_error: Option<String>,
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserLinode {
+    pub id: Option<i64>,
+    pub name: Option<String>,
+    pub org: Option<i64>,
+}
+
+
+/// A user with the orgs they're a member of and the linodes they can access
+/// through them. Deactivated users keep their orgs but can't access any linodes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub id: i64,
+    pub email: String,
+    pub display_name: Option<String>,
+    pub active: bool,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub last_seen: Option<i64>,
+    pub orgs: Vec<Org>,
+    pub linodes: Vec<UserLinode>,
+}
+impl UserInfo {
+    fn extract_row(user: &mut Option<UserInfo>, row: UserInfoRow) {
+        let (user_id, email, org_id, org_name, linode_id, linode_name, linode_org, profile) = row;
+        let (display_name, active, created_at, updated_at, last_seen) = profile;
+        let org = Org {
+            id: org_id,
+            name: org_name,
+        };
+        let linode = UserLinode {
+            id: linode_id,
+            name: linode_name,
+            org: linode_org,
+        };
+
+        if user.is_none() {
+            let userinfo = UserInfo {
+                id: user_id,
+                email: email,
+                display_name: display_name,
+                active: active,
+                created_at: created_at,
+                updated_at: updated_at,
+                last_seen: last_seen,
+                orgs: if org.id.is_some() { vec![org] } else { vec![] },
+                linodes: if linode.id.is_some() { vec![linode] } else { vec![] },
+            };
+            user.get_or_insert(userinfo);
+        } else {
+            let user = user.get_or_insert_with(|| unreachable!());
+            if org.id.is_some() && !user.orgs.iter().any(|existing| existing.id == org.id) {
+                user.orgs.push(org);
+            }
+            if linode.id.is_some() { user.linodes.push(linode); }
+        }
+    }
+
+    /// Collect a `UserInfo` from the rows of a query joining a single user to
+    /// their orgs and linodes, ordered by `org.id, linode.id`
+    pub fn from_rows<T: IntoIterator<Item=UserInfoRow>>(rows: T) -> Option<UserInfo> {
+        let mut user = None;
+        for row in rows {
+            Self::extract_row(&mut user, row);
+        }
+        user
+    }
+
+    /// Collect `UserInfo`s from the rows of a query joining users to their
+    /// orgs and linodes, ordered by `user.id, org.id, linode.id`
+    pub fn from_user_rows<T: IntoIterator<Item=UserInfoRow>>(rows: T) -> Vec<UserInfo> {
+        let mut users = vec![];
+        let mut user: Option<UserInfo> = None;
+        for row in rows {
+            if user.as_ref().map(|user| user.id != row.0).unwrap_or(false) {
+                users.extend(user.take());
+            }
+            Self::extract_row(&mut user, row);
+        }
+        users.extend(user);
+        users
+    }
+}
@@ -0,0 +1,5 @@
+begin transaction;
+
+drop table event;
+
+commit;
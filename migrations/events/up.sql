@@ -0,0 +1,13 @@
+-- sqlite specific
+
+begin transaction;
+
+-- change feed served by `/api/events`, ids are never reused
+create table event (
+    id integer PRIMARY KEY AUTOINCREMENT,
+    kind text NOT NULL,
+    data text NOT NULL,
+    created text NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+commit;
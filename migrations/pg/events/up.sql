@@ -0,0 +1,9 @@
+-- postgres specific
+
+-- change feed served by `/api/events`
+create table event (
+    id bigserial PRIMARY KEY,
+    kind text NOT NULL,
+    data text NOT NULL,
+    created timestamptz NOT NULL DEFAULT now()
+);
@@ -0,0 +1,29 @@
+-- postgres specific
+-- `user` is a reserved word and must always be quoted
+
+create table org (
+    id bigserial PRIMARY KEY,
+    name text NOT NULL
+);
+
+create table "user" (
+    id bigserial PRIMARY KEY,
+    email text NOT NULL
+);
+
+create table user_org (
+    id bigserial PRIMARY KEY,
+    "user" bigint REFERENCES "user"(id) ON DELETE CASCADE,
+    org bigint REFERENCES org(id) ON DELETE CASCADE
+);
+
+create table linode (
+    id bigserial PRIMARY KEY,
+    name text NOT NULL,
+    org bigint REFERENCES org(id) ON DELETE CASCADE
+);
+
+-- case-insensitive uniqueness, matching sqlite's `COLLATE NOCASE`
+create unique index org_name_index on org (lower(name));
+create unique index user_email_index on "user" (lower(email));
+create unique index linode_name_index on linode (lower(name));
@@ -0,0 +1,6 @@
+alter table "user"
+    drop column display_name,
+    drop column created_at,
+    drop column updated_at,
+    drop column last_seen,
+    drop column active;
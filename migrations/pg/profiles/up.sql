@@ -0,0 +1,10 @@
+-- postgres specific
+
+-- timestamps are unix seconds, `last_seen` is null until the user first authenticates.
+-- Deactivated users (`active = false`) keep their memberships but are left out of access queries.
+alter table "user"
+    add column display_name text,
+    add column created_at bigint NOT NULL DEFAULT extract(epoch from now())::bigint,
+    add column updated_at bigint NOT NULL DEFAULT extract(epoch from now())::bigint,
+    add column last_seen bigint,
+    add column active boolean NOT NULL DEFAULT true;
@@ -0,0 +1,4 @@
+-- postgres specific
+
+-- id of the api request that recorded the event, null for admin commands
+alter table event add column request_id text;
@@ -0,0 +1,5 @@
+-- postgres specific
+
+-- detached subscriptions would become global ones without the column
+delete from webhook where detached_org is not null;
+alter table webhook drop column detached_org;
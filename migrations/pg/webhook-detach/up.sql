@@ -0,0 +1,5 @@
+-- postgres specific
+
+-- org a subscription belonged to before the org was deleted. Detached
+-- subscriptions (`org` null) finish their pending deliveries but match no new events.
+alter table webhook add column detached_org bigint;
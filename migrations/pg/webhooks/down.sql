@@ -0,0 +1,3 @@
+drop index webhook_delivery_due_index;
+drop table webhook_delivery;
+drop table webhook;
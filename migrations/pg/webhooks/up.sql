@@ -0,0 +1,26 @@
+-- postgres specific
+
+-- `org` is null for global subscriptions, `events` is a comma separated
+-- list of event kinds, empty for all events
+create table webhook (
+    id bigserial PRIMARY KEY,
+    url text NOT NULL,
+    secret text NOT NULL,
+    org bigint REFERENCES org(id) ON DELETE CASCADE,
+    events text NOT NULL DEFAULT ''
+);
+
+-- `status` is `pending`, `delivered` or `dead`, `next_attempt` is a unix timestamp
+create table webhook_delivery (
+    id bigserial PRIMARY KEY,
+    webhook bigint NOT NULL REFERENCES webhook(id) ON DELETE CASCADE,
+    event bigint NOT NULL,
+    kind text NOT NULL,
+    payload text NOT NULL,
+    status text NOT NULL DEFAULT 'pending',
+    attempts bigint NOT NULL DEFAULT 0,
+    next_attempt bigint NOT NULL,
+    last_error text
+);
+
+create index webhook_delivery_due_index on webhook_delivery (status, next_attempt);
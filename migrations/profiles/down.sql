@@ -0,0 +1,22 @@
+-- sqlite specific
+-- sqlite can't drop columns, so the table is rebuilt. Foreign keys are turned
+-- off so dropping the old table doesn't cascade to memberships
+pragma foreign_keys = off;
+
+begin transaction;
+
+drop trigger user_created_at;
+
+create table user_without_profile (
+    id integer PRIMARY KEY,
+    email text UNIQUE NOT NULL COLLATE NOCASE
+);
+insert into user_without_profile (id, email) select id, email from user;
+drop table user;
+alter table user_without_profile rename to user;
+
+create index user_email_index on user (email COLLATE NOCASE);
+
+commit;
+
+pragma foreign_keys = on;
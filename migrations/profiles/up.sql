@@ -0,0 +1,24 @@
+-- sqlite specific
+
+begin transaction;
+
+-- timestamps are unix seconds, `last_seen` is null until the user first authenticates.
+-- Deactivated users (`active = 0`) keep their memberships but are left out of access queries.
+alter table user add column display_name text;
+alter table user add column created_at integer NOT NULL DEFAULT 0;
+alter table user add column updated_at integer NOT NULL DEFAULT 0;
+alter table user add column last_seen integer;
+alter table user add column active integer NOT NULL DEFAULT 1;
+
+update user set created_at = cast(strftime('%s', 'now') as integer),
+                updated_at = cast(strftime('%s', 'now') as integer);
+
+-- sqlite can't add columns with a non-constant default, so new users are stamped by a trigger
+create trigger user_created_at after insert on user when new.created_at = 0
+begin
+    update user set created_at = cast(strftime('%s', 'now') as integer),
+                    updated_at = cast(strftime('%s', 'now') as integer)
+        where id = new.id;
+end;
+
+commit;
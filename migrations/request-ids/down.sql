@@ -0,0 +1,16 @@
+-- sqlite specific
+-- sqlite can't drop columns, so the table is rebuilt
+
+begin transaction;
+
+create table event_without_request_id (
+    id integer PRIMARY KEY AUTOINCREMENT,
+    kind text NOT NULL,
+    data text NOT NULL,
+    created text NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+insert into event_without_request_id (id, kind, data, created) select id, kind, data, created from event;
+drop table event;
+alter table event_without_request_id rename to event;
+
+commit;
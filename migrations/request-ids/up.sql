@@ -0,0 +1,8 @@
+-- sqlite specific
+
+begin transaction;
+
+-- id of the api request that recorded the event, null for admin commands
+alter table event add column request_id text;
+
+commit;
@@ -0,0 +1,26 @@
+-- sqlite specific
+-- sqlite can't drop columns, so the table is rebuilt. Detached subscriptions
+-- would become global ones without the column, so they're removed. Foreign keys
+-- are turned off so dropping the old table doesn't cascade to deliveries
+pragma foreign_keys = off;
+
+begin transaction;
+
+delete from webhook_delivery where webhook in (select id from webhook where detached_org is not null);
+delete from webhook where detached_org is not null;
+
+create table webhook_without_detached_org (
+    id integer PRIMARY KEY,
+    url text NOT NULL,
+    secret text NOT NULL,
+    org integer,
+    events text NOT NULL DEFAULT '',
+    FOREIGN KEY (org) REFERENCES org(id) ON DELETE CASCADE
+);
+insert into webhook_without_detached_org (id, url, secret, org, events) select id, url, secret, org, events from webhook;
+drop table webhook;
+alter table webhook_without_detached_org rename to webhook;
+
+commit;
+
+pragma foreign_keys = on;
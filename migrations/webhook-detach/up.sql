@@ -0,0 +1,9 @@
+-- sqlite specific
+
+begin transaction;
+
+-- org a subscription belonged to before the org was deleted. Detached
+-- subscriptions (`org` null) finish their pending deliveries but match no new events.
+alter table webhook add column detached_org integer;
+
+commit;
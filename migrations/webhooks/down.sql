@@ -0,0 +1,7 @@
+begin transaction;
+
+drop index webhook_delivery_due_index;
+drop table webhook_delivery;
+drop table webhook;
+
+commit;
@@ -0,0 +1,32 @@
+-- sqlite specific
+
+begin transaction;
+
+-- `org` is null for global subscriptions, `events` is a comma separated
+-- list of event kinds, empty for all events
+create table webhook (
+    id integer PRIMARY KEY,
+    url text NOT NULL,
+    secret text NOT NULL,
+    org integer,
+    events text NOT NULL DEFAULT '',
+    FOREIGN KEY (org) REFERENCES org(id) ON DELETE CASCADE
+);
+
+-- `status` is `pending`, `delivered` or `dead`, `next_attempt` is a unix timestamp
+create table webhook_delivery (
+    id integer PRIMARY KEY,
+    webhook integer NOT NULL,
+    event integer NOT NULL,
+    kind text NOT NULL,
+    payload text NOT NULL,
+    status text NOT NULL DEFAULT 'pending',
+    attempts integer NOT NULL DEFAULT 0,
+    next_attempt integer NOT NULL,
+    last_error text,
+    FOREIGN KEY (webhook) REFERENCES webhook(id) ON DELETE CASCADE
+);
+
+create index webhook_delivery_due_index on webhook_delivery (status, next_attempt);
+
+commit;
@@ -0,0 +1,374 @@
+/*!
+Admin commands for managing orgs, users, linodes and memberships
+
+Commands run either directly against the configured storage backend or
+remotely against a running server's json api.
+*/
+use std::cmp;
+
+use clap::ArgMatches;
+use serde_json;
+
+use config::{self, Config};
+use org_demo_client::Client;
+use store::Store;
+use models::{OrgInfo, User, LinodeInfo};
+use api;
+use events;
+use errors::*;
+
+
+/// Admin operations available both locally and remotely
+pub trait Admin {
+    fn orgs(&self) -> Result<Vec<OrgInfo>>;
+    fn create_org(&self, name: &str) -> Result<i64>;
+    fn rename_org(&self, id: i64, name: &str) -> Result<()>;
+    fn delete_org(&self, id: i64) -> Result<()>;
+
+    fn users(&self) -> Result<Vec<User>>;
+    fn create_user(&self, email: &str, org_ids: &[i64]) -> Result<i64>;
+    fn update_user_email(&self, id: i64, email: &str) -> Result<()>;
+    fn set_user_active(&self, id: i64, active: bool) -> Result<()>;
+    fn delete_user(&self, id: i64) -> Result<()>;
+
+    fn linodes(&self) -> Result<Vec<LinodeInfo>>;
+    fn create_linode(&self, name: &str, org_id: i64) -> Result<i64>;
+    fn update_linode(&self, id: i64, update: &api::UpdateLinode) -> Result<()>;
+    fn delete_linode(&self, id: i64) -> Result<()>;
+
+    fn add_member(&self, user_id: i64, org_id: i64) -> Result<()>;
+    fn remove_member(&self, user_id: i64, org_id: i64) -> Result<()>;
+}
+
+
+/// Admin operations run directly against the configured storage backend.
+/// Changes record the same events as the api, without a request id.
+impl Admin for Store {
+    fn orgs(&self) -> Result<Vec<OrgInfo>> { self.with(|repo| repo.get_all_orgs()) }
+    fn create_org(&self, name: &str) -> Result<i64> {
+        self.transaction(|repo| {
+            let org_id = repo.create_org(name)?;
+            events::record(repo, "org.created", json!({"id": org_id, "name": name}))?;
+            Ok(org_id)
+        })
+    }
+    fn rename_org(&self, id: i64, name: &str) -> Result<()> {
+        self.transaction(|repo| {
+            repo.change_org_name(id, name)?;
+            events::record(repo, "org.updated", json!({"id": id, "name": name}))
+        })
+    }
+    fn delete_org(&self, id: i64) -> Result<()> {
+        self.transaction(|repo| {
+            // recorded first, while the org's webhooks still match it
+            events::record(repo, "org.deleted", json!({"id": id}))?;
+            repo.delete_org(id)
+        })
+    }
+
+    fn users(&self) -> Result<Vec<User>> { self.with(|repo| repo.get_all_users()) }
+    fn create_user(&self, email: &str, org_ids: &[i64]) -> Result<i64> {
+        self.transaction(|repo| {
+            let user_id = repo.create_user(email, org_ids)?;
+            events::record(repo, "user.created", json!({"id": user_id, "email": email, "org_ids": org_ids}))?;
+            Ok(user_id)
+        })
+    }
+    fn update_user_email(&self, id: i64, email: &str) -> Result<()> {
+        self.transaction(|repo| {
+            repo.change_user_email(id, email)?;
+            events::record(repo, "user.updated", json!({"id": id, "email": email}))
+        })
+    }
+    fn set_user_active(&self, id: i64, active: bool) -> Result<()> {
+        self.transaction(|repo| {
+            repo.set_user_active(id, active)?;
+            let kind = if active { "user.reactivated" } else { "user.deactivated" };
+            events::record(repo, kind, json!({"id": id}))
+        })
+    }
+    fn delete_user(&self, id: i64) -> Result<()> {
+        self.transaction(|repo| {
+            repo.delete_user(id)?;
+            events::record(repo, "user.deleted", json!({"id": id}))
+        })
+    }
+
+    fn linodes(&self) -> Result<Vec<LinodeInfo>> { self.with(|repo| repo.get_all_linodes()) }
+    fn create_linode(&self, name: &str, org_id: i64) -> Result<i64> {
+        self.transaction(|repo| {
+            let linode_id = repo.create_linode(name, org_id)?;
+            events::record(repo, "linode.created", json!({"id": linode_id, "name": name, "org_id": org_id}))?;
+            Ok(linode_id)
+        })
+    }
+    fn update_linode(&self, id: i64, update: &api::UpdateLinode) -> Result<()> {
+        self.transaction(|repo| {
+            let previous_org_id = repo.get_linode(id)?.and_then(|linode| linode.org);
+            repo.update_linode(id, update.name.as_ref().map(String::as_str), update.org_id)?;
+            events::record(repo, "linode.updated", json!({
+                "id": id, "name": update.name, "org_id": update.org_id, "previous_org_id": previous_org_id
+            }))
+        })
+    }
+    fn delete_linode(&self, id: i64) -> Result<()> {
+        self.transaction(|repo| {
+            let org_id = repo.get_linode(id)?.and_then(|linode| linode.org);
+            repo.delete_linode(id)?;
+            events::record(repo, "linode.deleted", json!({"id": id, "org_id": org_id}))
+        })
+    }
+
+    fn add_member(&self, user_id: i64, org_id: i64) -> Result<()> {
+        self.transaction(|repo| {
+            repo.add_member(user_id, org_id)?;
+            events::record(repo, "member.created", json!({"user_id": user_id, "org_id": org_id}))
+        })
+    }
+    fn remove_member(&self, user_id: i64, org_id: i64) -> Result<()> {
+        self.transaction(|repo| {
+            repo.delete_member(user_id, org_id)?;
+            events::record(repo, "member.deleted", json!({"user_id": user_id, "org_id": org_id}))
+        })
+    }
+}
+
+
+impl Admin for Client {
+    fn orgs(&self) -> Result<Vec<OrgInfo>> { Ok(Client::orgs(self)?) }
+    fn create_org(&self, name: &str) -> Result<i64> { Ok(Client::create_org(self, name)?) }
+    fn rename_org(&self, id: i64, name: &str) -> Result<()> { Ok(Client::rename_org(self, id, name)?) }
+    fn delete_org(&self, id: i64) -> Result<()> { Ok(Client::delete_org(self, id)?) }
+
+    fn users(&self) -> Result<Vec<User>> { Ok(Client::users(self)?) }
+    fn create_user(&self, email: &str, org_ids: &[i64]) -> Result<i64> { Ok(Client::create_user(self, email, org_ids)?) }
+    fn update_user_email(&self, id: i64, email: &str) -> Result<()> { Ok(Client::update_user_email(self, id, email)?) }
+    fn set_user_active(&self, id: i64, active: bool) -> Result<()> {
+        if active { Ok(Client::reactivate_user(self, id)?) } else { Ok(Client::deactivate_user(self, id)?) }
+    }
+    fn delete_user(&self, id: i64) -> Result<()> { Ok(Client::delete_user(self, id)?) }
+
+    fn linodes(&self) -> Result<Vec<LinodeInfo>> { Ok(Client::linodes(self)?) }
+    fn create_linode(&self, name: &str, org_id: i64) -> Result<i64> { Ok(Client::create_linode(self, name, org_id)?) }
+    fn update_linode(&self, id: i64, update: &api::UpdateLinode) -> Result<()> { Ok(Client::update_linode(self, id, update)?) }
+    fn delete_linode(&self, id: i64) -> Result<()> { Ok(Client::delete_linode(self, id)?) }
+
+    fn add_member(&self, user_id: i64, org_id: i64) -> Result<()> { Ok(Client::add_member(self, user_id, org_id)?) }
+    fn remove_member(&self, user_id: i64, org_id: i64) -> Result<()> { Ok(Client::remove_member(self, user_id, org_id)?) }
+}
+
+
+/// Connect to the server at `server_url` if one is given, otherwise the configured storage backend
+pub fn connect(config: &Config, server_url: Option<&str>) -> Result<Box<Admin>> {
+    if let Some(url) = server_url {
+        return Ok(Box::new(Client::new(url)?))
+    }
+    if config.backend == config::Backend::Sqlite && !config.database_path.exists() {
+        bail_fmt!(ErrorKind::DoesNotExist, "Database not found at {:?}, see `database migrate`", config.database_path);
+    }
+    Ok(Box::new(Store::from_config(config)?))
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Table,
+    Json,
+}
+impl Format {
+    pub fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "table" => Format::Table,
+            "json" => Format::Json,
+            _ => bail_fmt!(ErrorKind::BadRequest, "Unknown output format: {}", s),
+        })
+    }
+}
+
+
+/// Print rows as a column-aligned table
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths = headers.iter().map(|h| h.len()).collect::<Vec<_>>();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = cmp::max(*width, cell.chars().count());
+        }
+    }
+    let format_row = |cells: Vec<&str>| {
+        cells.iter().zip(widths.iter())
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    println!("{}", format_row(headers.to_vec()));
+    let dashes = widths.iter().map(|w| "-".repeat(*w)).collect::<Vec<_>>();
+    println!("{}", format_row(dashes.iter().map(String::as_str).collect()));
+    for row in rows {
+        println!("{}", format_row(row.iter().map(String::as_str).collect()));
+    }
+}
+
+
+fn print_json(value: &serde_json::Value) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+
+/// Print the result of a command that doesn't return anything
+fn print_done(format: Format, msg: &str) -> Result<()> {
+    match format {
+        Format::Json => print_json(&json!(api::Success { success: true })),
+        Format::Table => { println!("{}", msg); Ok(()) }
+    }
+}
+
+
+fn parse_id(matches: &ArgMatches, name: &str) -> Result<i64> {
+    let value = matches.value_of(name).expect("required arg");
+    value.parse().chain_err(|| format!("`{}` expects an integer id, found: {}", name, value))
+}
+
+
+/// Run an admin `org`, `user`, `linode`, or `member` command
+pub fn run(admin: &Admin, format: Format, command: &str, matches: &ArgMatches) -> Result<()> {
+    match (command, matches.subcommand()) {
+        ("org", ("list", _)) => {
+            let orgs = admin.orgs()?;
+            match format {
+                Format::Json => print_json(&json!(api::Orgs { orgs: orgs }))?,
+                Format::Table => {
+                    let rows = orgs.iter().map(|org| vec![
+                        org.id.to_string(),
+                        org.name.clone(),
+                        org.users.iter().filter_map(|u| u.email.clone()).collect::<Vec<_>>().join(","),
+                        org.linodes.iter().filter_map(|l| l.name.clone()).collect::<Vec<_>>().join(","),
+                    ]).collect::<Vec<_>>();
+                    print_table(&["ID", "NAME", "USERS", "LINODES"], &rows);
+                }
+            }
+        }
+        ("org", ("create", Some(m))) => {
+            let org_id = admin.create_org(m.value_of("name").expect("required arg"))?;
+            match format {
+                Format::Json => print_json(&json!(api::OrgCreated { org_id: org_id }))?,
+                Format::Table => println!("Created org {}", org_id),
+            }
+        }
+        ("org", ("rename", Some(m))) => {
+            let id = parse_id(m, "id")?;
+            admin.rename_org(id, m.value_of("name").expect("required arg"))?;
+            print_done(format, &format!("Renamed org {}", id))?;
+        }
+        ("org", ("delete", Some(m))) => {
+            let id = parse_id(m, "id")?;
+            admin.delete_org(id)?;
+            print_done(format, &format!("Deleted org {}", id))?;
+        }
+
+        ("user", ("list", _)) => {
+            let users = admin.users()?;
+            match format {
+                Format::Json => print_json(&json!(api::Users { users: users }))?,
+                Format::Table => {
+                    let rows = users.iter().map(|user| vec![
+                        user.id.map(|id| id.to_string()).unwrap_or_default(),
+                        user.email.clone().unwrap_or_default(),
+                        user.display_name.clone().unwrap_or_default(),
+                        user.active.map(|active| if active { "yes" } else { "no" }).unwrap_or_default().to_string(),
+                    ]).collect::<Vec<_>>();
+                    print_table(&["ID", "EMAIL", "NAME", "ACTIVE"], &rows);
+                }
+            }
+        }
+        ("user", ("create", Some(m))) => {
+            let org_ids = match m.values_of("org") {
+                Some(ids) => ids.map(|id| id.parse().chain_err(|| format!("`--org` expects an integer id, found: {}", id)))
+                    .collect::<Result<Vec<i64>>>()?,
+                None => vec![],
+            };
+            let user_id = admin.create_user(m.value_of("email").expect("required arg"), &org_ids)?;
+            match format {
+                Format::Json => print_json(&json!(api::UserCreated { user_id: user_id }))?,
+                Format::Table => println!("Created user {}", user_id),
+            }
+        }
+        ("user", ("rename", Some(m))) => {
+            let id = parse_id(m, "id")?;
+            admin.update_user_email(id, m.value_of("email").expect("required arg"))?;
+            print_done(format, &format!("Updated user {}", id))?;
+        }
+        ("user", ("deactivate", Some(m))) => {
+            let id = parse_id(m, "id")?;
+            admin.set_user_active(id, false)?;
+            print_done(format, &format!("Deactivated user {}", id))?;
+        }
+        ("user", ("reactivate", Some(m))) => {
+            let id = parse_id(m, "id")?;
+            admin.set_user_active(id, true)?;
+            print_done(format, &format!("Reactivated user {}", id))?;
+        }
+        ("user", ("delete", Some(m))) => {
+            let id = parse_id(m, "id")?;
+            admin.delete_user(id)?;
+            print_done(format, &format!("Deleted user {}", id))?;
+        }
+
+        ("linode", ("list", _)) => {
+            let linodes = admin.linodes()?;
+            match format {
+                Format::Json => print_json(&json!(api::Linodes { linodes: linodes }))?,
+                Format::Table => {
+                    let rows = linodes.iter().map(|linode| vec![
+                        linode.id.to_string(),
+                        linode.name.clone(),
+                        linode.org.map(|id| id.to_string()).unwrap_or_default(),
+                    ]).collect::<Vec<_>>();
+                    print_table(&["ID", "NAME", "ORG"], &rows);
+                }
+            }
+        }
+        ("linode", ("create", Some(m))) => {
+            let org_id = parse_id(m, "org-id")?;
+            let linode_id = admin.create_linode(m.value_of("name").expect("required arg"), org_id)?;
+            match format {
+                Format::Json => print_json(&json!(api::LinodeCreated { linode_id: linode_id }))?,
+                Format::Table => println!("Created linode {}", linode_id),
+            }
+        }
+        ("linode", ("rename", Some(m))) => {
+            let id = parse_id(m, "id")?;
+            let update = api::UpdateLinode { name: m.value_of("name").map(String::from), ..Default::default() };
+            admin.update_linode(id, &update)?;
+            print_done(format, &format!("Renamed linode {}", id))?;
+        }
+        ("linode", ("move", Some(m))) => {
+            let id = parse_id(m, "id")?;
+            let update = api::UpdateLinode { org_id: Some(parse_id(m, "org-id")?), ..Default::default() };
+            admin.update_linode(id, &update)?;
+            print_done(format, &format!("Moved linode {}", id))?;
+        }
+        ("linode", ("delete", Some(m))) => {
+            let id = parse_id(m, "id")?;
+            admin.delete_linode(id)?;
+            print_done(format, &format!("Deleted linode {}", id))?;
+        }
+
+        ("member", ("add", Some(m))) => {
+            let (user_id, org_id) = (parse_id(m, "user-id")?, parse_id(m, "org-id")?);
+            admin.add_member(user_id, org_id)?;
+            print_done(format, &format!("Added user {} to org {}", user_id, org_id))?;
+        }
+        ("member", ("remove", Some(m))) => {
+            let (user_id, org_id) = (parse_id(m, "user-id")?, parse_id(m, "org-id")?);
+            admin.remove_member(user_id, org_id)?;
+            print_done(format, &format!("Removed user {} from org {}", user_id, org_id))?;
+        }
+        _ => {
+            eprintln!("see `{} --help`", command);
+        }
+    }
+    Ok(())
+}
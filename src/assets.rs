@@ -0,0 +1,205 @@
+/*!
+Frontend static file serving
+
+With the `embed-static` feature enabled, everything under `static/` is bundled
+into the binary at build time (see `build.rs`) and served from memory.
+Otherwise files are read from the configured `static_root`.
+
+Either way, responses carry `ETag` and `Last-Modified` headers and honour
+`If-None-Match`/`If-Modified-Since`. Precompressed siblings (`main.js.br`, `main.js.gz`)
+are served in place of the original file when the client accepts that encoding.
+*/
+use std::borrow::Cow;
+use std::path::Path;
+
+use rouille;
+use chrono::{DateTime, TimeZone, Utc};
+
+use errors::*;
+
+
+/// One year, for assets whose filenames change with their content
+const HASHED_MAX_AGE: u64 = 60 * 60 * 24 * 365;
+
+/// Supported precompressed variants, in order of preference: (`Content-Encoding`, file extension)
+const PRECOMPRESSED: &'static [(&'static str, &'static str)] = &[
+    ("br", "br"),
+    ("gzip", "gz"),
+];
+
+
+/// A static file found in either the embedded bundle or on disk
+struct StaticFile {
+    body: rouille::ResponseBody,
+    etag: String,
+    /// Unix timestamp (seconds) of the file's last modification
+    modified: i64,
+}
+
+
+/// Check if a filename contains a content hash, e.g. `main.3f2a9c1b.js`.
+/// These can be cached forever since any change produces a new filename.
+fn is_hashed(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let parts = name.split('.').collect::<Vec<_>>();
+    parts.len() > 2 && parts[1..parts.len() - 1].iter().any(|part| {
+        part.len() >= 8 && part.chars().all(|c| c.is_digit(16))
+    })
+}
+
+
+/// Add `Cache-Control` headers appropriate for the asset at `path`.
+/// Un-hashed assets must be revalidated (via their `ETag`) on every request.
+fn with_cache_headers(resp: rouille::Response, path: &str) -> rouille::Response {
+    if is_hashed(path) {
+        resp.with_unique_header("Cache-Control", format!("public, max-age={}, immutable", HASHED_MAX_AGE))
+    } else {
+        resp.with_public_cache(0)
+    }
+}
+
+
+fn mime_type(path: &str) -> &'static str {
+    let ext = Path::new(path).extension().and_then(::std::ffi::OsStr::to_str).unwrap_or("");
+    rouille::extension_to_mime(ext)
+}
+
+
+/// Check whether the request accepts the given `Content-Encoding`
+fn accepts_encoding(request: &rouille::Request, encoding: &str) -> bool {
+    rouille::content_encoding::accepted_content_encodings(request).any(|accepted| {
+        let mut parts = accepted.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let rejected = parts.any(|param| {
+            let param = param.trim();
+            param == "q=0" || param == "q=0.0" || param == "q=0.00" || param == "q=0.000"
+        });
+        !rejected && (name.eq_ignore_ascii_case(encoding) || name == "*")
+    })
+}
+
+
+/// Format a unix timestamp as an http-date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+fn http_date(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0).unwrap().format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+
+/// Check the request's conditional headers against the file's current validators.
+/// `If-None-Match` takes precedence over `If-Modified-Since` when both are present.
+fn is_not_modified(request: &rouille::Request, file: &StaticFile) -> bool {
+    if let Some(if_none_match) = request.header("If-None-Match") {
+        return if_none_match.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == file.etag
+        })
+    }
+    if let Some(if_modified_since) = request.header("If-Modified-Since") {
+        if let Ok(since) = DateTime::parse_from_rfc2822(if_modified_since) {
+            return file.modified <= since.timestamp()
+        }
+    }
+    false
+}
+
+
+fn respond(request: &rouille::Request, path: &str, file: StaticFile, encoding: Option<&'static str>) -> rouille::Response {
+    let (status_code, data) = if is_not_modified(request, &file) {
+        (304, rouille::ResponseBody::empty())
+    } else {
+        (200, file.body)
+    };
+    let mut headers: Vec<(Cow<'static, str>, Cow<'static, str>)> = vec![
+        ("Content-Type".into(), mime_type(path).into()),
+        ("ETag".into(), file.etag.into()),
+        ("Last-Modified".into(), http_date(file.modified).into()),
+        ("Vary".into(), "Accept-Encoding".into()),
+    ];
+    if let Some(encoding) = encoding {
+        headers.push(("Content-Encoding".into(), encoding.into()));
+    }
+    let resp = rouille::Response {
+        status_code: status_code,
+        headers: headers,
+        data: data,
+        upgrade: None,
+    };
+    with_cache_headers(resp, path)
+}
+
+
+/// Serve the static file at `path`, relative to the static `root`, preferring any
+/// precompressed variant accepted by the client. Returns `None` if no such file exists.
+pub fn serve(request: &rouille::Request, root: &Path, path: &str) -> Result<Option<rouille::Response>> {
+    let path = path.trim_start_matches('/');
+    if path.is_empty() { return Ok(None) }
+    for &(encoding, ext) in PRECOMPRESSED {
+        if !accepts_encoding(request, encoding) { continue }
+        if let Some(file) = find(root, &format!("{}.{}", path, ext))? {
+            return Ok(Some(respond(request, path, file, Some(encoding))))
+        }
+    }
+    Ok(find(root, path)?.map(|file| respond(request, path, file, None)))
+}
+
+
+#[cfg(feature = "embed-static")]
+mod embedded {
+    /// A file bundled from `static/`
+    pub struct Asset {
+        /// Path relative to `static/`, `/` separated
+        pub path: &'static str,
+        pub etag: &'static str,
+        pub modified: i64,
+        pub data: &'static [u8],
+    }
+
+    include!(concat!(env!("OUT_DIR"), "/static_assets.rs"));
+
+    pub fn find(path: &str) -> Option<&'static Asset> {
+        ASSETS.binary_search_by(|asset| asset.path.cmp(path))
+            .ok()
+            .map(|ind| &ASSETS[ind])
+    }
+}
+
+
+/// Look up `path` in the embedded bundle
+#[cfg(feature = "embed-static")]
+fn find(_root: &Path, path: &str) -> Result<Option<StaticFile>> {
+    Ok(embedded::find(path).map(|asset| {
+        StaticFile {
+            body: rouille::ResponseBody::from_data(asset.data),
+            etag: asset.etag.to_string(),
+            modified: asset.modified,
+        }
+    }))
+}
+
+
+/// Look up `path` under the static `root` on disk
+#[cfg(not(feature = "embed-static"))]
+fn find(root: &Path, path: &str) -> Result<Option<StaticFile>> {
+    use std::fs;
+    use std::time::UNIX_EPOCH;
+    let root = match root.canonicalize() {
+        Ok(root) => root,
+        Err(_) => return Ok(None),
+    };
+    // Make sure we're still inside the static root, e.g. for requests like `/static/../db/org_demo`
+    let file_path = match root.join(path).canonicalize() {
+        Ok(ref p) if p.starts_with(&root) && p.is_file() => p.to_path_buf(),
+        _ => return Ok(None),
+    };
+    let meta = fs::metadata(&file_path)?;
+    let modified = meta.modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let f = fs::File::open(&file_path).map_err(ErrorKind::FileOpen)?;
+    Ok(Some(StaticFile {
+        body: rouille::ResponseBody::from_file(f),
+        etag: format!("\"{:x}-{:x}\"", modified, meta.len()),
+        modified: modified,
+    }))
+}
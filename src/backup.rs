@@ -0,0 +1,150 @@
+/*!
+Online backup and restore of the sqlite database
+
+Backups go through sqlite's online backup api, so they're consistent
+even while the server is running and writing to the database.
+*/
+use std::fs;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Local;
+use rusqlite::{Connection, DatabaseName, OpenFlags, NO_PARAMS};
+use rusqlite::backup::Progress;
+
+use config::{self, Config};
+use store::{self, Repo};
+use MIGRATIONS;
+use errors::*;
+
+
+/// File name prefix and extension of scheduled backups
+static SCHEDULED_PREFIX: &'static str = "org_demo-";
+static SCHEDULED_EXT: &'static str = ".sqlite";
+
+
+fn require_sqlite(config: &Config) -> Result<()> {
+    if config.backend != config::Backend::Sqlite {
+        bail!("Backups are only supported for the `sqlite` backend");
+    }
+    if !config.database_path.exists() {
+        bail_fmt!(ErrorKind::DoesNotExist, "Database not found at {:?}, see `database migrate`", config.database_path);
+    }
+    Ok(())
+}
+
+
+/// `path` with `suffix` appended to its file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+
+/// Check that the database at `path` passes sqlite's integrity check
+/// and has exactly the embedded migrations applied
+pub fn verify<T: AsRef<Path>>(path: T) -> Result<()> {
+    let path = path.as_ref();
+    if !path.exists() {
+        bail_fmt!(ErrorKind::DoesNotExist, "Backup not found at {:?}", path);
+    }
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let mut stmt = conn.prepare("pragma integrity_check")?;
+    let problems = stmt.query_map(NO_PARAMS, |row| row.get(0))?
+        .collect::<::std::result::Result<Vec<String>, _>>()?;
+    if problems.len() != 1 || problems[0] != "ok" {
+        bail!("{:?} failed its integrity check: {}", path, problems.join("; "));
+    }
+
+    let applied = conn.applied_migrations()?;
+    if !store::is_migrated(&applied) {
+        bail!("{:?} has migrations [{}] applied, expected [{}]",
+              path, applied.join(", "), MIGRATIONS.join(", "));
+    }
+    Ok(())
+}
+
+
+/// Copy the live database to `dest`.
+///
+/// The copy is written next to `dest` and only moved into place
+/// once it's complete and passes `verify`.
+pub fn backup<T: AsRef<Path>>(config: &Config, dest: T) -> Result<()> {
+    require_sqlite(config)?;
+    let dest = dest.as_ref();
+    let partial = with_suffix(dest, ".partial");
+    let conn = Connection::open(&config.database_path)?;
+    conn.backup(DatabaseName::Main, &partial, None)?;
+    if let Err(e) = verify(&partial) {
+        fs::remove_file(&partial).ok();
+        return Err(e)
+    }
+    fs::rename(&partial, dest)?;
+    Ok(())
+}
+
+
+/// Replace the contents of the live database with the backup at `src`,
+/// returning the path the previous contents were saved to.
+///
+/// `src` must pass `verify`. The restore goes through the backup api,
+/// so a running server picks up the restored data without restarting.
+pub fn restore<T: AsRef<Path>>(config: &Config, src: T) -> Result<PathBuf> {
+    require_sqlite(config)?;
+    let src = src.as_ref();
+    verify(src)?;
+
+    let previous = with_suffix(&config.database_path, ".pre-restore");
+    let mut conn = Connection::open(&config.database_path)?;
+    conn.backup(DatabaseName::Main, &previous, None)?;
+    conn.restore(DatabaseName::Main, src, None::<fn(Progress)>)?;
+    Ok(previous)
+}
+
+
+/// Take a timestamped backup in `config.backup_dir` and
+/// remove all but the latest `config.backup_keep`
+fn run_scheduled(config: &Config) -> Result<PathBuf> {
+    fs::create_dir_all(&config.backup_dir)?;
+    let name = format!("{}{}{}", SCHEDULED_PREFIX, Local::now().format("%Y%m%dT%H%M%S"), SCHEDULED_EXT);
+    let path = config.backup_dir.join(name);
+    backup(config, &path)?;
+
+    // timestamped names sort oldest first
+    let mut backups = fs::read_dir(&config.backup_dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name().and_then(|name| name.to_str())
+                .map(|name| name.starts_with(SCHEDULED_PREFIX) && name.ends_with(SCHEDULED_EXT))
+                .unwrap_or(false)
+        })
+        .collect::<Vec<_>>();
+    backups.sort();
+    let excess = backups.len().saturating_sub(config.backup_keep as usize);
+    for old in &backups[..excess] {
+        fs::remove_file(old)?;
+    }
+    Ok(path)
+}
+
+
+/// Spawn a thread backing up the database every `config.backup_interval` minutes
+pub fn spawn_scheduled(config: Config) -> Result<thread::JoinHandle<()>> {
+    require_sqlite(&config)?;
+    let interval = Duration::from_secs(config.backup_interval * 60);
+    info!("** Backing up to {:?} every {} minutes, keeping {} **",
+          config.backup_dir, config.backup_interval, config.backup_keep);
+    Ok(thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            match run_scheduled(&config) {
+                Ok(path) => info!("Database backed up to {:?}", path),
+                Err(e) => error!("Scheduled backup failed: {}", e),
+            }
+        }
+    }))
+}
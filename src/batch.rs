@@ -0,0 +1,145 @@
+/*!
+Batched mutations for `POST /api/batch`
+
+Operations are applied in order against a single `Repo`, which the caller
+runs inside a transaction so a failing operation rolls back the whole batch.
+Each operation records the same change feed event as its individual route.
+*/
+use api::{IdRef, Operation, OperationResult};
+use events;
+use store::Repo;
+use errors::*;
+
+
+/// Max number of operations in a batch
+pub const MAX_OPERATIONS: usize = 100;
+
+
+/// Resolve `id` against the results of the operations applied so far
+fn resolve(id: &IdRef, results: &[OperationResult]) -> Result<i64> {
+    match *id {
+        IdRef::Id(id) => Ok(id),
+        IdRef::Ref(ref reference) => {
+            let index = if reference.starts_with('$') { reference[1..].parse::<usize>().ok() } else { None };
+            let index = index
+                .ok_or_else(|| format_err!(ErrorKind::BadRequest, "Invalid reference `{}`, expected `$N`", reference))?;
+            match results.get(index) {
+                Some(&OperationResult { id: Some(id), .. }) => Ok(id),
+                Some(_) => bail_fmt!(ErrorKind::BadRequest, "Operation {} doesn't create anything to reference", index),
+                None => bail_fmt!(ErrorKind::BadRequest, "Reference `{}` to an operation that hasn't run yet", reference),
+            }
+        }
+    }
+}
+
+
+/// Apply a single operation, returning the id of anything created
+fn apply_one(repo: &Repo, op: &Operation, results: &[OperationResult]) -> Result<Option<i64>> {
+    use api::Operation::*;
+    let id = |id: &IdRef| resolve(id, results);
+    Ok(match *op {
+        CreateOrg { ref name } => {
+            let org_id = repo.create_org(name)?;
+            events::record(repo, "org.created", json!({"id": org_id, "name": name}))?;
+            Some(org_id)
+        }
+        CreateUser { ref email, ref org_ids } => {
+            let org_ids = org_ids.iter().map(&id).collect::<Result<Vec<_>>>()?;
+            let user_id = repo.create_user(email, &org_ids)?;
+            events::record(repo, "user.created", json!({"id": user_id, "email": email, "org_ids": org_ids}))?;
+            Some(user_id)
+        }
+        CreateLinode { ref name, ref org_id } => {
+            let org_id = id(org_id)?;
+            let linode_id = repo.create_linode(name, org_id)?;
+            events::record(repo, "linode.created", json!({"id": linode_id, "name": name, "org_id": org_id}))?;
+            Some(linode_id)
+        }
+        AddMember { ref user_id, ref org_id } => {
+            let (user_id, org_id) = (id(user_id)?, id(org_id)?);
+            repo.add_member(user_id, org_id)?;
+            events::record(repo, "member.created", json!({"user_id": user_id, "org_id": org_id}))?;
+            None
+        }
+        UpdateOrg { id: ref org_id, ref name } => {
+            let org_id = id(org_id)?;
+            repo.change_org_name(org_id, name)?;
+            events::record(repo, "org.updated", json!({"id": org_id, "name": name}))?;
+            None
+        }
+        UpdateUser { id: ref user_id, ref email } => {
+            let user_id = id(user_id)?;
+            repo.change_user_email(user_id, email)?;
+            events::record(repo, "user.updated", json!({"id": user_id, "email": email}))?;
+            None
+        }
+        UpdateLinode { id: ref linode_id, ref name, ref org_id } => {
+            let linode_id = id(linode_id)?;
+            let org_id = match *org_id {
+                Some(ref org_id) => Some(id(org_id)?),
+                None => None,
+            };
+            let previous_org_id = repo.get_linode(linode_id)?.and_then(|linode| linode.org);
+            repo.update_linode(linode_id, name.as_ref().map(String::as_str), org_id)?;
+            events::record(repo, "linode.updated", json!({
+                "id": linode_id, "name": name, "org_id": org_id, "previous_org_id": previous_org_id
+            }))?;
+            None
+        }
+        DeleteOrg { id: ref org_id } => {
+            let org_id = id(org_id)?;
+            // recorded first, while the org's webhooks still match it
+            events::record(repo, "org.deleted", json!({"id": org_id}))?;
+            repo.delete_org(org_id)?;
+            None
+        }
+        DeleteUser { id: ref user_id } => {
+            let user_id = id(user_id)?;
+            repo.delete_user(user_id)?;
+            events::record(repo, "user.deleted", json!({"id": user_id}))?;
+            None
+        }
+        DeleteLinode { id: ref linode_id } => {
+            let linode_id = id(linode_id)?;
+            let org_id = repo.get_linode(linode_id)?.and_then(|linode| linode.org);
+            repo.delete_linode(linode_id)?;
+            events::record(repo, "linode.deleted", json!({"id": linode_id, "org_id": org_id}))?;
+            None
+        }
+        RemoveMember { ref user_id, ref org_id } => {
+            let (user_id, org_id) = (id(user_id)?, id(org_id)?);
+            repo.delete_member(user_id, org_id)?;
+            events::record(repo, "member.deleted", json!({"user_id": user_id, "org_id": org_id}))?;
+            None
+        }
+    })
+}
+
+
+/// Prefix an operation's error with its position, keeping its kind
+fn at(index: usize, op: &Operation, e: Error) -> Error {
+    let wrapped = match *e.kind() {
+        ErrorKind::BadRequest(ref s) => {
+            Some(ErrorKind::BadRequest(format!("Operation {} ({}): {}", index, op.name(), s)))
+        }
+        ErrorKind::DoesNotExist(ref s) => {
+            Some(ErrorKind::DoesNotExist(format!("Operation {} ({}): {}", index, op.name(), s)))
+        }
+        _ => None,
+    };
+    wrapped.map(Error::from).unwrap_or(e)
+}
+
+
+/// Apply `operations` in order, stopping at the first failure
+pub fn apply(repo: &Repo, operations: &[Operation]) -> Result<Vec<OperationResult>> {
+    if operations.len() > MAX_OPERATIONS {
+        bail_fmt!(ErrorKind::BadRequest, "Batches are limited to {} operations", MAX_OPERATIONS);
+    }
+    let mut results = Vec::with_capacity(operations.len());
+    for (index, op) in operations.iter().enumerate() {
+        let id = apply_one(repo, op, &results).map_err(|e| at(index, op, e))?;
+        results.push(OperationResult { op: op.name().to_string(), id: id });
+    }
+    Ok(results)
+}
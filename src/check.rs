@@ -0,0 +1,162 @@
+/*!
+Integrity checks and repairs for the sqlite database
+
+Foreign keys weren't enforced on pooled connections, so existing databases
+may contain memberships and linodes referencing deleted users and orgs.
+*/
+use rusqlite::{Connection, NO_PARAMS};
+
+use config::{self, Config};
+use errors::*;
+
+
+/// A row violating a foreign key constraint, from `pragma foreign_key_check`
+#[derive(Debug)]
+pub struct ForeignKeyViolation {
+    pub table: String,
+    pub rowid: i64,
+    pub parent: String,
+}
+
+
+/// Problems found in the database
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Messages from `pragma integrity_check`, empty if it passed
+    pub integrity: Vec<String>,
+    pub foreign_keys: Vec<ForeignKeyViolation>,
+    /// (user, org, count) of memberships listed more than once
+    pub duplicate_memberships: Vec<(i64, i64, i64)>,
+    /// Ids of `user_org` rows referencing a missing user or org
+    pub orphan_memberships: Vec<i64>,
+    /// Ids of linodes referencing a missing org
+    pub orphan_linodes: Vec<i64>,
+}
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.integrity.is_empty() && self.foreign_keys.is_empty()
+            && self.duplicate_memberships.is_empty()
+            && self.orphan_memberships.is_empty()
+            && self.orphan_linodes.is_empty()
+    }
+
+    /// Print a summary of any problems found
+    pub fn print(&self) {
+        if self.is_ok() {
+            println!("No problems found");
+            return
+        }
+        if !self.integrity.is_empty() {
+            println!("Integrity check failed:");
+            for msg in &self.integrity { println!("  {}", msg); }
+        }
+        if !self.foreign_keys.is_empty() {
+            println!("Foreign key violations:");
+            for v in &self.foreign_keys { println!("  {} row {} -> missing {}", v.table, v.rowid, v.parent); }
+        }
+        if !self.duplicate_memberships.is_empty() {
+            println!("Duplicate memberships:");
+            for &(user, org, count) in &self.duplicate_memberships {
+                println!("  user {} in org {} ({} times)", user, org, count);
+            }
+        }
+        if !self.orphan_memberships.is_empty() {
+            println!("Memberships referencing a missing user or org: {:?}", self.orphan_memberships);
+        }
+        if !self.orphan_linodes.is_empty() {
+            println!("Linodes referencing a missing org: {:?}", self.orphan_linodes);
+        }
+    }
+}
+
+
+fn open(config: &Config) -> Result<Connection> {
+    if config.backend != config::Backend::Sqlite {
+        bail!("Database checks are only supported for the `sqlite` backend");
+    }
+    if !config.database_path.exists() {
+        bail_fmt!(ErrorKind::DoesNotExist, "Database not found at {:?}, see `database migrate`", config.database_path);
+    }
+    Ok(Connection::open(&config.database_path)?)
+}
+
+
+fn query_ids(conn: &Connection, stmt: &str) -> Result<Vec<i64>> {
+    let mut stmt = conn.prepare(stmt)?;
+    let ids = stmt.query_map(NO_PARAMS, |row| row.get(0))?;
+    Ok(ids.collect::<::std::result::Result<Vec<i64>, _>>()?)
+}
+
+
+fn inspect(conn: &Connection) -> Result<Report> {
+    let mut stmt = conn.prepare("pragma integrity_check")?;
+    let integrity = stmt.query_map(NO_PARAMS, |row| row.get(0))?
+        .collect::<::std::result::Result<Vec<String>, _>>()?
+        .into_iter()
+        .filter(|msg| msg != "ok")
+        .collect();
+
+    let mut stmt = conn.prepare("pragma foreign_key_check")?;
+    let foreign_keys = stmt.query_map(NO_PARAMS, |row| {
+        Ok(ForeignKeyViolation { table: row.get(0)?, rowid: row.get(1)?, parent: row.get(2)? })
+    })?.collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    let stmt = "select user, org, count(*) from user_org \
+                    group by user, org having count(*) > 1 \
+                    order by user, org";
+    let mut stmt = conn.prepare(stmt)?;
+    let duplicate_memberships = stmt.query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    let orphan_memberships = query_ids(conn, "select id from user_org \
+                                                  where user is null or org is null \
+                                                  or user not in (select id from user) \
+                                                  or org not in (select id from org) \
+                                                  order by id")?;
+    let orphan_linodes = query_ids(conn, "select id from linode \
+                                              where org is not null and org not in (select id from org) \
+                                              order by id")?;
+    Ok(Report {
+        integrity: integrity,
+        foreign_keys: foreign_keys,
+        duplicate_memberships: duplicate_memberships,
+        orphan_memberships: orphan_memberships,
+        orphan_linodes: orphan_linodes,
+    })
+}
+
+
+/// Check the configured database for corruption, foreign key violations,
+/// duplicate memberships, and orphaned memberships and linodes
+pub fn check(config: &Config) -> Result<Report> {
+    inspect(&open(config)?)
+}
+
+
+/// Repair what `check` finds, in a single transaction, returning the issues
+/// found before repairing and any remaining afterwards.
+///
+/// - duplicate memberships are removed, keeping the earliest
+/// - memberships referencing a missing user or org are removed
+/// - linodes referencing a missing org are detached from it
+///
+/// Failures of sqlite's own integrity check can't be repaired here,
+/// restore from a backup instead.
+pub fn fix(config: &Config) -> Result<(Report, Report)> {
+    let mut conn = open(config)?;
+    let before = inspect(&conn)?;
+    {
+        let trans = conn.transaction()?;
+        trans.execute("delete from user_org where id not in \
+                           (select min(id) from user_org group by user, org)", NO_PARAMS)?;
+        trans.execute("delete from user_org \
+                           where user is null or org is null \
+                           or user not in (select id from user) \
+                           or org not in (select id from org)", NO_PARAMS)?;
+        trans.execute("update linode set org = null \
+                           where org is not null and org not in (select id from org)", NO_PARAMS)?;
+        trans.commit()?;
+    }
+    let after = inspect(&conn)?;
+    Ok((before, after))
+}
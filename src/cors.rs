@@ -0,0 +1,92 @@
+/*!
+Cross-origin resource sharing
+
+Browsers on origins listed in `cors_origins` may call the api. Simple
+requests get `Access-Control-Allow-*` headers added to their response,
+preflight `OPTIONS` requests are answered directly.
+*/
+use rouille;
+
+use config::Config;
+
+
+/// Response headers browsers may read from cross-origin responses
+static EXPOSE_HEADERS: &'static str = "ETag, X-Request-Id, Retry-After, Idempotent-Replayed";
+
+
+/// The `Access-Control-Allow-Origin` value for `request`, if its origin is allowed
+fn allowed_origin(config: &Config, request: &rouille::Request) -> Option<String> {
+    let origin = request.header("Origin")?;
+    if config.cors_origins.iter().any(|allowed| allowed == origin) {
+        return Some(origin.to_string())
+    }
+    if config.cors_origins.iter().any(|allowed| allowed == "*") {
+        // never echoed back, `Config::validate` rejects a wildcard with credentials
+        return Some("*".to_string())
+    }
+    None
+}
+
+
+fn with_origin(config: &Config, response: rouille::Response, origin: String) -> rouille::Response {
+    let response = if origin == "*" {
+        response
+    } else {
+        // responses differ by origin, don't let caches mix them up
+        response.with_additional_header("Vary", "Origin")
+    };
+    let response = response.with_unique_header("Access-Control-Allow-Origin", origin);
+    if config.cors_credentials {
+        response.with_unique_header("Access-Control-Allow-Credentials", "true")
+    } else {
+        response
+    }
+}
+
+
+/// Add cors headers to the response of a (non-preflight) request
+pub fn apply(config: &Config, request: &rouille::Request, response: rouille::Response) -> rouille::Response {
+    // preflights are answered by `preflight`
+    if request.method() == "OPTIONS" { return response }
+    match allowed_origin(config, request) {
+        Some(origin) => {
+            with_origin(config, response, origin)
+                .with_unique_header("Access-Control-Expose-Headers", EXPOSE_HEADERS)
+        }
+        None => response,
+    }
+}
+
+
+/// Answer a preflight `OPTIONS` request. Disallowed origins, methods or
+/// headers get a response without any `Access-Control-Allow-*` headers,
+/// which the browser treats as a refusal.
+pub fn preflight(config: &Config, request: &rouille::Request) -> rouille::Response {
+    let allow = config.cors_methods.join(", ");
+    let response = rouille::Response::empty_204().with_unique_header("Allow", allow.clone());
+
+    let origin = match allowed_origin(config, request) {
+        Some(origin) => origin,
+        None => return response,
+    };
+    let method_allowed = request.header("Access-Control-Request-Method")
+        .map(|method| config.cors_methods.iter().any(|allowed| allowed.eq_ignore_ascii_case(method)))
+        .unwrap_or(false);
+    let headers_allowed = request.header("Access-Control-Request-Headers")
+        .map(|headers| {
+            headers.split(',')
+                .map(str::trim)
+                .filter(|header| !header.is_empty())
+                .all(|header| config.cors_headers.iter().any(|allowed| allowed.eq_ignore_ascii_case(header)))
+        })
+        .unwrap_or(true);
+    if !method_allowed || !headers_allowed {
+        warn!("Refused cors preflight from {} for {} {}", origin, request.method(), request.raw_url());
+        return response
+    }
+
+    with_origin(config, response, origin)
+        .with_unique_header("Access-Control-Allow-Methods", allow)
+        .with_unique_header("Access-Control-Allow-Headers", config.cors_headers.join(", "))
+        .with_unique_header("Access-Control-Max-Age", config.cors_max_age.to_string())
+}
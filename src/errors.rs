@@ -1,12 +1,22 @@
+// error-chain 0.11 implements the deprecated `Error::description` and `Error::cause`
+#![allow(deprecated)]
 use std::io;
 use log;
 use migrant_lib;
 use rusqlite;
 use r2d2;
 use serde_json;
+use toml;
+use reqwest;
+use org_demo_client;
+#[cfg(feature = "pg")]
+use postgres;
 
 
 error_chain! {
+    links {
+        Client(org_demo_client::errors::Error, org_demo_client::errors::ErrorKind);
+    }
     foreign_links {
         LogInit(log::SetLoggerError);
         FileOpen(io::Error);
@@ -14,6 +24,11 @@ error_chain! {
         Sqlite(rusqlite::Error);
         R2D2(r2d2::Error);
         Json(serde_json::Error);
+        TomlSer(toml::ser::Error);
+        TomlDe(toml::de::Error);
+        Http(reqwest::Error);
+        Url(reqwest::UrlError);
+        Postgres(postgres::Error) #[cfg(feature = "pg")];
     }
     errors {
         DoesNotExist(s: String) {
@@ -24,6 +39,30 @@ error_chain! {
             description("Bad request")
             display("BadRequest: {}", s)
         }
+        Conflict(s: String) {
+            description("Conflict")
+            display("Conflict: {}", s)
+        }
+        Unprocessable(s: String) {
+            description("Unprocessable request")
+            display("Unprocessable: {}", s)
+        }
+        Forbidden(s: String) {
+            description("Forbidden")
+            display("Forbidden: {}", s)
+        }
+        PreconditionFailed(s: String) {
+            description("Precondition failed")
+            display("PreconditionFailed: {}", s)
+        }
+        PayloadTooLarge(limit: u64) {
+            description("Request body too large")
+            display("PayloadTooLarge: request body exceeds {} bytes", limit)
+        }
+        RateLimited(retry_after: u64) {
+            description("Too many requests")
+            display("RateLimited: retry after {} seconds", retry_after)
+        }
     }
 }
 
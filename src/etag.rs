@@ -0,0 +1,70 @@
+/*!
+Weak ETags and conditional request headers
+
+ETags are a hash of a response's serialized json, so they change whenever
+anything in the response does. `If-None-Match` lets readers skip unchanged
+responses, `If-Match` lets writers detect that an entity changed since they
+last read it.
+*/
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+use rouille;
+use serde::Serialize;
+use serde_json;
+
+use errors::*;
+
+
+/// Weak ETag of `value`'s json representation, e.g. `W/"5c1bd3fa0e9d6a44"`
+pub fn of<T: Serialize>(value: &T) -> Result<String> {
+    let bytes = serde_json::to_vec(value)?;
+    let mut hasher = DefaultHasher::new();
+    hasher.write(&bytes);
+    Ok(format!("W/\"{:016x}\"", hasher.finish()))
+}
+
+
+/// Check if `etag` is listed in an `If-Match`/`If-None-Match` header value,
+/// using weak comparison
+fn listed(header: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+    header.split(',').any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+
+/// Check if the client's cached copy (`If-None-Match`) is still current
+pub fn not_modified(request: &rouille::Request, etag: &str) -> bool {
+    request.header("If-None-Match").map(|header| listed(header, etag)).unwrap_or(false)
+}
+
+
+/// An empty `304` for a request whose cached copy is current
+pub fn not_modified_response(etag: String) -> rouille::Response {
+    rouille::Response::empty_204()
+        .with_status_code(304)
+        .with_unique_header("ETag", etag)
+}
+
+
+/// Check any `If-Match` precondition against the entity's current representation,
+/// loaded with `current` only when the header is present. `None` means the entity
+/// doesn't exist, which fails every precondition.
+pub fn check_if_match<T, F>(request: &rouille::Request, current: F) -> Result<()>
+    where T: Serialize,
+          F: FnOnce() -> Result<Option<T>>,
+{
+    let header = match request.header("If-Match") {
+        Some(header) => header,
+        None => return Ok(()),
+    };
+    let matched = match current()? {
+        Some(entity) => listed(header, &of(&entity)?),
+        None => false,
+    };
+    if !matched {
+        bail_fmt!(ErrorKind::PreconditionFailed, "The entity was modified since it was last read");
+    }
+    Ok(())
+}
@@ -0,0 +1,214 @@
+/*!
+Change feed served as Server-Sent Events by `GET /api/events`
+
+Mutations `record` an event in the same transaction as the change itself,
+so the feed's ids follow commit order and survive restarts. Recording an
+event also queues its webhook deliveries. Streams read
+events from the database, resuming after the client's `Last-Event-ID`,
+and are woken through a `Notifier` when new events are committed.
+
+Streams are written on their own thread into a chunked response body.
+tiny_http holds back a chunked body until it has a full 8 KiB chunk, so each
+flush is padded out with an ignored comment line (see `Padded`). Streams count
+as in flight until they end, and end when the server shuts down (see
+`Notifier::close`), after which clients reconnect elsewhere.
+*/
+use std::io::{self, Write};
+use std::sync::{Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use rouille;
+use serde_json::{self, Value};
+
+use models::Event;
+use service::{State, InFlight};
+use formats;
+use store::Repo;
+use webhooks;
+use logging;
+use errors::*;
+
+
+/// Number of events kept for clients resuming with `Last-Event-ID`
+const HISTORY: i64 = 10_000;
+
+/// Events written to a stream per database read
+const PAGE_SIZE: i64 = 100;
+
+/// How often an idle stream checks the database for events committed by other
+/// processes (e.g. `admin` commands) and sends a keep-alive comment
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Size of the chunks tiny_http writes a chunked body in
+const TRANSFER_CHUNK: usize = 8 * 1024;
+
+/// Flushed writes buffered ahead of a slow client before the stream blocks
+const FLUSHES_AHEAD: usize = 4;
+
+
+/// Record an event, e.g. `org.created`, as part of the current transaction.
+/// Events recorded while handling a request carry its id (see `logging::request_id`).
+pub fn record(repo: &Repo, kind: &str, data: Value) -> Result<()> {
+    let request_id = logging::request_id();
+    let request_id = request_id.as_ref().map(String::as_str);
+    let id = repo.insert_event(kind, &data.to_string(), request_id)?;
+    webhooks::enqueue(repo, id, kind, &data, request_id)?;
+    if id > HISTORY {
+        repo.delete_events_through(id - HISTORY)?;
+    }
+    Ok(())
+}
+
+
+/// Wakes streams waiting for new events
+#[derive(Default)]
+pub struct Notifier {
+    generation: Mutex<u64>,
+    changed: Condvar,
+    closed: AtomicBool,
+}
+impl Notifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wake all waiting streams, call once events are committed
+    pub fn notify(&self) {
+        let mut generation = self.generation.lock().expect("notifier lock poisoned");
+        *generation += 1;
+        self.changed.notify_all();
+    }
+
+    /// End every stream, call when shutting down
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.notify();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    fn generation(&self) -> u64 {
+        *self.generation.lock().expect("notifier lock poisoned")
+    }
+
+    /// Wait until notified after `seen`, or for `timeout`
+    fn wait(&self, seen: u64, timeout: Duration) {
+        let generation = self.generation.lock().expect("notifier lock poisoned");
+        if *generation != seen { return }
+        self.changed.wait_timeout(generation, timeout).ok();
+    }
+}
+
+
+/// Format an event in the `text/event-stream` wire format. The request
+/// id, if any, is added to the event's data as `request_id`.
+fn frame(event: &Event) -> String {
+    let data = match (event.request_id.as_ref(), serde_json::from_str::<Value>(&event.data)) {
+        (Some(request_id), Ok(Value::Object(mut data))) => {
+            data.insert("request_id".into(), Value::String(request_id.clone()));
+            Value::Object(data).to_string()
+        }
+        _ => event.data.clone(),
+    };
+    format!("id: {}\nevent: {}\ndata: {}\n\n", event.id, event.kind, data)
+}
+
+
+/// Write events to `out` as they're committed, until the client disconnects
+/// or the `Notifier` is closed
+fn stream<W: Write>(state: &State, mut last_id: i64, out: &mut W) -> Result<()> {
+    // tell clients how long to wait before reconnecting
+    out.write_all(b"retry: 3000\n\n")?;
+    out.flush()?;
+    loop {
+        if state.events.is_closed() {
+            return Ok(())
+        }
+        let seen = state.events.generation();
+        let events = state.store.with(|repo| repo.events_after(last_id, PAGE_SIZE))?;
+        for event in &events {
+            out.write_all(frame(event).as_bytes())?;
+            last_id = event.id;
+        }
+        if events.len() as i64 == PAGE_SIZE {
+            continue
+        }
+        if events.is_empty() {
+            out.write_all(b": keep-alive\n\n")?;
+        }
+        out.flush()?;
+        state.events.wait(seen, KEEP_ALIVE);
+    }
+}
+
+
+/// Buffers writes, and on `flush` pads them with a comment line to whole
+/// `TRANSFER_CHUNK`s before passing them on, so none are held back
+struct Padded<W: Write> {
+    out: W,
+    pending: Vec<u8>,
+}
+impl<W: Write> Write for Padded<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(())
+        }
+        let mut padding = (TRANSFER_CHUNK - self.pending.len() % TRANSFER_CHUNK) % TRANSFER_CHUNK;
+        if padding == 1 {
+            // too short for `:\n`
+            padding += TRANSFER_CHUNK;
+        }
+        if padding > 0 {
+            let padded = self.pending.len() + padding;
+            self.pending.push(b':');
+            self.pending.resize(padded - 1, b' ');
+            self.pending.push(b'\n');
+        }
+        self.out.write_all(&self.pending)?;
+        self.pending.clear();
+        self.out.flush()
+    }
+}
+
+
+/// Check if writing to a client failed because it went away
+pub fn is_disconnect(e: &Error) -> bool {
+    match *e.kind() {
+        ErrorKind::FileOpen(ref e) => match e.kind() {
+            io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+
+/// Respond with a stream of the events after `last_id`, written on its own thread
+pub fn response(state: State, last_id: i64) -> rouille::Response {
+    let (writer, reader) = formats::channel(FLUSHES_AHEAD);
+    thread::spawn(move || {
+        let _in_flight = InFlight::enter(&state.in_flight);
+        let mut out = Padded { out: writer, pending: vec![] };
+        match stream(&state, last_id, &mut out) {
+            Err(ref e) if is_disconnect(e) => debug!("Event stream closed by client"),
+            Err(e) => error!("Event stream failed: {}", e),
+            Ok(()) => debug!("Event stream closed for shutdown"),
+        }
+    });
+    let mut response = rouille::Response::text("")
+        .with_unique_header("Content-Type", "text/event-stream")
+        .with_unique_header("Cache-Control", "no-cache")
+        // nothing to compress, keep `content_encoding::apply` off the stream
+        .with_unique_header("Content-Encoding", "identity");
+    response.data = rouille::ResponseBody::from_reader(reader);
+    response
+}
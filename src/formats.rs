@@ -0,0 +1,374 @@
+/*!
+Content negotiation for list routes
+
+`/api/orgs`, `/api/users` and `/api/linodes` respond with json by default,
+or with csv or newline delimited json when asked for by a `format` query
+parameter (`json`, `csv`, `ndjson`) or the `Accept` header (`text/csv`,
+`application/x-ndjson`). The parameter takes precedence.
+
+Csv and ndjson rows are read from the database a page at a time and written
+to the response as they're read (see `Rows`), instead of collecting the whole
+list first. The first page is read before responding, so failing to read it
+gets the usual error status. The status of a streamed list is sent before the
+later pages are read, so if one of them fails the rows written so far are
+followed by an error marker and the response ends:
+- csv: a row with `error` in its first field and the message in its second
+- ndjson: a `{"error": "..."}` line
+
+A list that was written completely never ends with a marker.
+*/
+use std::cmp::Ordering;
+use std::i64;
+use std::io::{self, Read, Write, BufWriter};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+
+use rouille;
+use serde::Serialize;
+use serde_json;
+
+use {ToCsvResponse, ToNdjsonResponse};
+use models::{OrgInfo, User, LinodeInfo};
+use service::State;
+use store::Repo;
+use events;
+use logging;
+use errors::*;
+
+
+/// Size of the chunks handed from a stream's writer thread to the response
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Chunks buffered ahead of a slow client before the writer thread blocks
+const CHUNKS_AHEAD: usize = 8;
+
+/// Rows read with a pooled connection before it's released and they're written
+const PAGE_SIZE: i64 = 500;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+    Ndjson,
+}
+impl Format {
+    fn from_param(format: &str) -> Option<Self> {
+        match format {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            "ndjson" => Some(Format::Ndjson),
+            _ => None,
+        }
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "application/json" | "application/*" | "*/*" => Some(Format::Json),
+            "text/csv" => Some(Format::Csv),
+            "application/x-ndjson" => Some(Format::Ndjson),
+            _ => None,
+        }
+    }
+}
+
+
+/// The `q` parameter of a media range, 1 if it has none and 0 if it's invalid
+fn quality<'a, I: Iterator<Item=&'a str>>(params: I) -> f32 {
+    for param in params {
+        let mut parts = param.splitn(2, '=');
+        if parts.next().map(|name| name.trim().eq_ignore_ascii_case("q")).unwrap_or(false) {
+            return match parts.next().and_then(|q| q.trim().parse::<f32>().ok()) {
+                Some(q) if (0. ..=1.).contains(&q) => q,
+                _ => 0.,
+            }
+        }
+    }
+    1.
+}
+
+
+/// Pick the response format for a list request: the supported type in `Accept`
+/// with the highest quality value, the first listed of equally preferred ones.
+/// Types with `q=0` are never picked, json is the default.
+pub fn negotiate(request: &rouille::Request) -> Result<Format> {
+    if let Some(format) = request.get_param("format") {
+        return Ok(Format::from_param(&format)
+            .ok_or_else(|| format_err!(ErrorKind::BadRequest, "Unsupported format `{}`, expected json, csv or ndjson", format))?)
+    }
+    let accept = match request.header("Accept") {
+        Some(accept) => accept,
+        None => return Ok(Format::Json),
+    };
+    let mut accepted = accept.split(',')
+        .filter_map(|media_range| {
+            let mut parts = media_range.split(';');
+            let media_type = parts.next().unwrap_or("").trim().to_lowercase();
+            Format::from_media_type(&media_type).map(|format| (format, quality(parts)))
+        })
+        .filter(|&(_, quality)| quality > 0.)
+        .collect::<Vec<_>>();
+    // the sort is stable, keeping ties in the order they're listed
+    accepted.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    Ok(accepted.first().map(|&(format, _)| format).unwrap_or(Format::Json))
+}
+
+
+// ---------------
+// Csv
+// ---------------
+
+/// Types that flatten into a single csv row
+pub trait CsvRecord {
+    fn csv_header() -> &'static [&'static str];
+    fn csv_row(&self) -> Vec<String>;
+}
+
+
+/// Separates the values of nested lists within a csv field
+const LIST_SEPARATOR: &'static str = ";";
+
+
+/// Members and linodes are flattened into parallel `;` separated lists,
+/// e.g. `user_ids` of `1;4` with `user_emails` of `wile@acme.com;road@acme.com`
+impl CsvRecord for OrgInfo {
+    fn csv_header() -> &'static [&'static str] {
+        &["id", "name", "user_ids", "user_emails", "linode_ids", "linode_names"]
+    }
+
+    fn csv_row(&self) -> Vec<String> {
+        let join = |values: Vec<String>| values.join(LIST_SEPARATOR);
+        vec![
+            self.id.to_string(),
+            self.name.clone(),
+            join(self.users.iter().filter_map(|user| user.id).map(|id| id.to_string()).collect()),
+            join(self.users.iter().filter_map(|user| user.email.clone()).collect()),
+            join(self.linodes.iter().filter_map(|linode| linode.id).map(|id| id.to_string()).collect()),
+            join(self.linodes.iter().filter_map(|linode| linode.name.clone()).collect()),
+        ]
+    }
+}
+
+impl CsvRecord for User {
+    fn csv_header() -> &'static [&'static str] {
+        &["id", "email", "display_name", "active", "created_at", "updated_at", "last_seen"]
+    }
+
+    fn csv_row(&self) -> Vec<String> {
+        let number = |n: Option<i64>| n.map(|n| n.to_string()).unwrap_or_default();
+        vec![
+            number(self.id),
+            self.email.clone().unwrap_or_default(),
+            self.display_name.clone().unwrap_or_default(),
+            self.active.map(|active| active.to_string()).unwrap_or_default(),
+            number(self.created_at),
+            number(self.updated_at),
+            number(self.last_seen),
+        ]
+    }
+}
+
+impl CsvRecord for LinodeInfo {
+    fn csv_header() -> &'static [&'static str] {
+        &["id", "name", "org_id"]
+    }
+
+    fn csv_row(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            self.name.clone(),
+            self.org.map(|id| id.to_string()).unwrap_or_default(),
+        ]
+    }
+}
+
+
+/// Quote a csv field if needed (RFC 4180). Fields that a spreadsheet would
+/// evaluate as a formula are prefixed with `'`.
+fn csv_field(field: &str) -> String {
+    let field = if field.starts_with(|c: char| "=+-@\t\r".contains(c)) {
+        format!("'{}", field)
+    } else {
+        field.to_string()
+    };
+    if field.contains(|c: char| ",\"\r\n".contains(c)) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+
+fn write_csv_row<S: AsRef<str>>(out: &mut Write, fields: &[S]) -> Result<()> {
+    let row = fields.iter().map(|field| csv_field(field.as_ref())).collect::<Vec<_>>().join(",");
+    out.write_all(row.as_bytes())?;
+    out.write_all(b"\r\n")?;
+    Ok(())
+}
+
+
+// ---------------
+// Streaming
+// ---------------
+
+/// Passes up to `limit` rows of a list with ids greater than `after` to its
+/// callback, e.g. `|repo, after, limit, f| repo.each_org(after, limit, f)`
+pub type Visit<T> = fn(&Repo, i64, i64, &mut FnMut(T) -> Result<()>) -> Result<Option<i64>>;
+
+
+/// A list read page by page while it's written to the response.
+///
+/// The first page is read by the handler, the rest on their own thread and
+/// handed to the response in chunks. Each page is read with a pooled connection
+/// that's released before the page is written, so a slow client doesn't keep
+/// a connection from other requests.
+pub struct Rows<T> {
+    state: State,
+    visit: Visit<T>,
+}
+impl<T> Rows<T> {
+    pub fn new(state: State, visit: Visit<T>) -> Self {
+        Self { state: state, visit: visit }
+    }
+
+    /// Read the rows after `after`, up to a page of them, and the id of the last one
+    fn page(&self, after: i64) -> Result<(Vec<T>, Option<i64>)> {
+        let visit = self.visit;
+        let mut page = vec![];
+        let last = self.state.store.with(|repo| visit(repo, after, PAGE_SIZE, &mut |row| { page.push(row); Ok(()) }))?;
+        Ok((page, last))
+    }
+
+    /// Pass the rows of `first` and every page after it to `f`,
+    /// without holding a connection while `f` runs
+    fn each(self, first: (Vec<T>, Option<i64>), f: &mut FnMut(T) -> Result<()>) -> Result<()> {
+        let (mut page, mut last) = first;
+        loop {
+            for row in page {
+                f(row)?;
+            }
+            match last {
+                Some(after) => {
+                    let next = self.page(after)?;
+                    page = next.0;
+                    last = next.1;
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+impl<T: CsvRecord + Send + 'static> ToCsvResponse for Rows<T> {
+    fn to_csv_resp(self) -> Result<rouille::Response> {
+        let first = self.page(i64::MIN)?;
+        Ok(stream("text/csv; charset=utf-8", move |out| {
+            write_csv_row(out, T::csv_header())?;
+            self.each(first, &mut |row| write_csv_row(out, &row.csv_row()))
+        }, |out, message| write_csv_row(out, &["error", message])))
+    }
+}
+
+impl<T: Serialize + Send + 'static> ToNdjsonResponse for Rows<T> {
+    fn to_ndjson_resp(self) -> Result<rouille::Response> {
+        let first = self.page(i64::MIN)?;
+        Ok(stream("application/x-ndjson", move |out| {
+            self.each(first, &mut |row| {
+                out.write_all(&serde_json::to_vec(&row)?)?;
+                out.write_all(b"\n")?;
+                Ok(())
+            })
+        }, |out, message| {
+            out.write_all(json!({"error": message}).to_string().as_bytes())?;
+            out.write_all(b"\n")?;
+            Ok(())
+        }))
+    }
+}
+
+
+/// A response body read from chunks written on another thread, buffering up
+/// to `bound` chunks ahead of the client
+pub fn channel(bound: usize) -> (ChannelWriter, ChannelReader) {
+    let (sender, receiver) = mpsc::sync_channel(bound);
+    (ChannelWriter(sender), ChannelReader { chunks: receiver, chunk: vec![], pos: 0 })
+}
+
+
+/// Hands chunks written on the producing thread to `ChannelReader`,
+/// each write as one chunk
+pub struct ChannelWriter(SyncSender<Vec<u8>>);
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "response was dropped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+/// Response body reading chunks until the producing thread is done
+pub struct ChannelReader {
+    chunks: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                // the producer is done
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = (&self.chunk[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+
+/// Respond with a body written by `produce` on its own thread. If `produce`
+/// fails for any reason but the client going away, `mark_error` ends the body
+/// with an error message in the format of the rows.
+fn stream<F, E>(content_type: &'static str, produce: F, mark_error: E) -> rouille::Response
+    where F: FnOnce(&mut Write) -> Result<()> + Send + 'static,
+          E: FnOnce(&mut Write, &str) -> Result<()> + Send + 'static,
+{
+    let (writer, reader) = channel(CHUNKS_AHEAD);
+    let request_id = logging::request_id();
+    thread::spawn(move || {
+        let mut out = BufWriter::with_capacity(CHUNK_SIZE, writer);
+        let res = produce(&mut out).and_then(|_| Ok(out.flush()?));
+        match res {
+            Err(ref e) if events::is_disconnect(e) => debug!("List stream closed by client"),
+            Err(e) => {
+                error!("List stream failed: {}", e);
+                let message = match request_id {
+                    Some(id) => format!("Something went wrong, request id: {}", id),
+                    None => "Something went wrong".to_string(),
+                };
+                // rows are written whole, so the marker starts on its own line
+                let res = mark_error(&mut out, &message).and_then(|_| Ok(out.flush()?));
+                if let Err(e) = res {
+                    debug!("Failed to mark the end of a failed list stream: {}", e);
+                }
+            }
+            Ok(()) => (),
+        }
+    });
+    let mut response = rouille::Response::text("")
+        .with_unique_header("Content-Type", content_type)
+        // compressing would read the whole body first, keep `content_encoding::apply` off the stream
+        .with_unique_header("Content-Encoding", "identity");
+    response.data = rouille::ResponseBody::from_reader(reader);
+    response
+}
@@ -0,0 +1,507 @@
+/*!
+GraphQL api served at `/graphql`
+
+Queries cover orgs, users and linodes and the relations between them,
+mutations mirror the `/api/create/...` routes and record the same events.
+
+Entities are loaded by id in batches (see `Context`): resolvers queue the ids
+of the entities they return, and the first lookup loads every queued id in one
+query. A query's cost grows with the depth of its selections rather than with
+the number of entities it returns. A user's orgs and linodes are those of
+`GET /api/user/{id}`, so deactivated users keep their orgs but have no linodes.
+
+`POST /graphql` runs queries and mutations, `GET /graphql?query=...` runs queries only.
+*/
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use chrono::{Utc, TimeZone};
+use juniper::{self, FieldResult, ID, RootNode, EmptyMutation};
+use juniper::meta::MetaType;
+
+use models::{OrgInfo, User as UserRow, UserInfo, LinodeInfo};
+use service::{self, State};
+use store::Repo;
+use events;
+use errors::*;
+
+
+pub type Schema = RootNode<'static, Query, Mutation>;
+
+/// Schema served to `GET` requests, which can't run mutations
+pub type ReadOnlySchema = RootNode<'static, Query, EmptyMutation<Context>>;
+
+
+pub fn schema() -> Schema {
+    Schema::new(Query, Mutation)
+}
+
+
+pub fn read_only_schema() -> ReadOnlySchema {
+    ReadOnlySchema::new(Query, EmptyMutation::new())
+}
+
+
+/// The schema in GraphQL's schema definition language, as exported by `org_demo schema`.
+/// Types are listed by name, leaving out the built-in scalars and introspection types.
+pub fn schema_language() -> String {
+    let schema = schema();
+    let mut types = schema.schema.concrete_type_list().into_iter()
+        .filter_map(|meta| match *meta {
+            MetaType::Object(ref object) if !object.name.starts_with("__") => Some(object),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    types.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut sdl = String::from("schema {\n  query: Query\n  mutation: Mutation\n}\n");
+    for object in types {
+        sdl.push('\n');
+        if let Some(ref description) = object.description {
+            sdl.push_str(&format!("\"\"\"{}\"\"\"\n", description));
+        }
+        sdl.push_str(&format!("type {} {{\n", object.name));
+        for field in &object.fields {
+            if field.name.starts_with("__") { continue }
+            let arguments = field.arguments.as_ref()
+                .map(|arguments| arguments.iter()
+                     .map(|argument| format!("{}: {}", argument.name, argument.arg_type))
+                     .collect::<Vec<_>>())
+                .unwrap_or_default();
+            if arguments.is_empty() {
+                sdl.push_str(&format!("  {}: {}\n", field.name, field.field_type));
+            } else {
+                sdl.push_str(&format!("  {}({}): {}\n", field.name, arguments.join(", "), field.field_type));
+            }
+        }
+        sdl.push_str("}\n");
+    }
+    sdl
+}
+
+
+// ------------------------------------------
+// ----------- Context ----------------------
+// ------------------------------------------
+
+/// Most ids loaded by a single query, well below sqlite's limit on bound parameters
+const MAX_BATCH: usize = 500;
+
+
+/// Rows of one kind, by id. Ids are queued until one of them is looked up,
+/// then all of them are loaded together.
+struct Loader<T> {
+    queued: HashSet<i64>,
+    /// `None` for ids that don't exist
+    loaded: HashMap<i64, Option<Arc<T>>>,
+}
+impl<T> Loader<T> {
+    fn new() -> Self {
+        Self { queued: HashSet::new(), loaded: HashMap::new() }
+    }
+
+    fn queue(&mut self, id: i64) {
+        if !self.loaded.contains_key(&id) {
+            self.queued.insert(id);
+        }
+    }
+
+    fn prime(&mut self, id: i64, row: T) {
+        self.queued.remove(&id);
+        self.loaded.insert(id, Some(Arc::new(row)));
+    }
+}
+
+
+fn lock<T>(loader: &Mutex<Loader<T>>) -> MutexGuard<Loader<T>> {
+    loader.lock().expect("graphql loader lock poisoned")
+}
+
+
+/// Per-request context, batching the loads of every resolver in the request.
+/// Loaded rows are shared by every resolver after that, until a mutation changes them.
+pub struct Context {
+    state: State,
+    orgs: Mutex<Loader<OrgInfo>>,
+    users: Mutex<Loader<UserInfo>>,
+    linodes: Mutex<Loader<LinodeInfo>>,
+    /// Linodes by the id of the org that owns them
+    org_linodes: Mutex<Loader<Vec<LinodeInfo>>>,
+}
+impl juniper::Context for Context {}
+
+impl Context {
+    pub fn new(state: State) -> Self {
+        Self {
+            state: state,
+            orgs: Mutex::new(Loader::new()),
+            users: Mutex::new(Loader::new()),
+            linodes: Mutex::new(Loader::new()),
+            org_linodes: Mutex::new(Loader::new()),
+        }
+    }
+
+    /// Look up `id`, loading it along with every queued id if it isn't loaded yet
+    fn load<T, F>(&self, loader: &Mutex<Loader<T>>, id: i64, query: F) -> Result<Option<Arc<T>>>
+        where F: Fn(&Repo, &[i64]) -> Result<Vec<(i64, T)>>
+    {
+        let mut loader = lock(loader);
+        if let Some(loaded) = loader.loaded.get(&id) {
+            return Ok(loaded.clone())
+        }
+        loader.queued.insert(id);
+        let ids = loader.queued.drain().collect::<Vec<_>>();
+        let rows = self.state.store.with(|repo| {
+            let mut rows = vec![];
+            for batch in ids.chunks(MAX_BATCH) {
+                rows.extend(query(repo, batch)?);
+            }
+            Ok(rows)
+        })?;
+        for id in &ids {
+            loader.loaded.insert(*id, None);
+        }
+        for (id, row) in rows {
+            loader.loaded.insert(id, Some(Arc::new(row)));
+        }
+        Ok(loader.loaded.get(&id).cloned().unwrap_or(None))
+    }
+
+    /// An org with its members and linodes
+    fn org(&self, id: i64) -> Result<Option<Arc<OrgInfo>>> {
+        self.load(&self.orgs, id, |repo, ids| {
+            Ok(repo.get_orgs(ids)?.into_iter().map(|org| (org.id, org)).collect())
+        })
+    }
+
+    /// A user with their profile, orgs and linodes
+    fn user(&self, id: i64) -> Result<Option<Arc<UserInfo>>> {
+        self.load(&self.users, id, |repo, ids| {
+            Ok(repo.get_users(ids)?.into_iter().map(|user| (user.id, user)).collect())
+        })
+    }
+
+    fn linode(&self, id: i64) -> Result<Option<Arc<LinodeInfo>>> {
+        self.load(&self.linodes, id, |repo, ids| {
+            Ok(repo.get_linodes(ids)?.into_iter().map(|linode| (linode.id, linode)).collect())
+        })
+    }
+
+    /// Linodes owned by org `id`
+    fn org_linodes(&self, id: i64) -> Result<Option<Arc<Vec<LinodeInfo>>>> {
+        self.load(&self.org_linodes, id, |repo, ids| {
+            let mut by_org = HashMap::new();
+            for linode in repo.linodes_for_orgs(ids)? {
+                if let Some(org) = linode.org {
+                    by_org.entry(org).or_insert_with(Vec::new).push(linode);
+                }
+            }
+            Ok(by_org.into_iter().collect())
+        })
+    }
+
+    /// Run a mutation in a transaction and drop the loaded rows,
+    /// so fields selected on its result see the change
+    fn mutate<T, F>(&self, f: F) -> Result<T>
+        where F: FnOnce(&Repo) -> Result<T>
+    {
+        let out = service::mutate(&self.state, f)?;
+        lock(&self.orgs).loaded.clear();
+        lock(&self.users).loaded.clear();
+        lock(&self.linodes).loaded.clear();
+        lock(&self.org_linodes).loaded.clear();
+        Ok(out)
+    }
+}
+
+
+fn to_id(id: i64) -> ID {
+    ID::from(id.to_string())
+}
+
+
+fn parse_id(id: &ID) -> Result<i64> {
+    Ok(id.parse().map_err(|_| format_err!(ErrorKind::BadRequest, "Invalid id `{}`", &**id))?)
+}
+
+
+// ------------------------------------------
+// ----------- Types ------------------------
+// ------------------------------------------
+
+// Types queue the ids their relations are looked up by when they're
+// created, so that the relations of sibling entities are loaded together.
+
+pub struct Org {
+    id: i64,
+    name: String,
+}
+impl Org {
+    fn new(context: &Context, id: i64, name: String) -> Self {
+        lock(&context.orgs).queue(id);
+        lock(&context.org_linodes).queue(id);
+        Org { id: id, name: name }
+    }
+}
+
+
+pub struct User {
+    id: i64,
+    email: String,
+}
+impl User {
+    fn new(context: &Context, id: i64, email: String) -> Self {
+        lock(&context.users).queue(id);
+        User { id: id, email: email }
+    }
+
+    /// Users are only partially filled when they come from an outer join
+    fn from_row(context: &Context, user: &UserRow) -> Option<Self> {
+        match (user.id, user.email.as_ref()) {
+            (Some(id), Some(email)) => Some(User::new(context, id, email.clone())),
+            _ => None,
+        }
+    }
+}
+
+
+/// Unix seconds as an RFC 3339 date, GraphQL's `Int` being too small for timestamps
+fn timestamp(secs: i64) -> String {
+    Utc.timestamp_opt(secs, 0).unwrap().to_rfc3339()
+}
+
+
+pub struct Linode {
+    id: i64,
+    name: String,
+    org: Option<i64>,
+}
+impl Linode {
+    fn new(context: &Context, id: i64, name: String, org: Option<i64>) -> Self {
+        if let Some(org) = org {
+            lock(&context.orgs).queue(org);
+        }
+        Linode { id: id, name: name, org: org }
+    }
+
+    fn from_info(context: &Context, linode: &LinodeInfo) -> Self {
+        Linode::new(context, linode.id, linode.name.clone(), linode.org)
+    }
+}
+
+
+graphql_object!(Org: Context |&self| {
+    description: "An organization, which owns linodes and has users as members"
+
+    field id() -> ID {
+        to_id(self.id)
+    }
+
+    field name() -> &str {
+        &self.name
+    }
+
+    field users(&executor) -> FieldResult<Vec<User>> {
+        let context = executor.context();
+        Ok(match context.org(self.id)? {
+            Some(org) => org.users.iter().filter_map(|user| User::from_row(context, user)).collect(),
+            None => vec![],
+        })
+    }
+
+    field linodes(&executor) -> FieldResult<Vec<Linode>> {
+        let context = executor.context();
+        Ok(match context.org_linodes(self.id)? {
+            Some(linodes) => linodes.iter().map(|linode| Linode::from_info(context, linode)).collect(),
+            None => vec![],
+        })
+    }
+});
+
+
+graphql_object!(User: Context |&self| {
+    description: "A user, with access to the linodes of the orgs they're a member of"
+
+    field id() -> ID {
+        to_id(self.id)
+    }
+
+    field email() -> &str {
+        &self.email
+    }
+
+    field display_name(&executor) -> FieldResult<Option<String>> {
+        Ok(executor.context().user(self.id)?.and_then(|user| user.display_name.clone()))
+    }
+
+    field active(&executor) -> FieldResult<bool> {
+        Ok(executor.context().user(self.id)?.map(|user| user.active).unwrap_or(true))
+    }
+
+    field created_at(&executor) -> FieldResult<Option<String>> {
+        Ok(executor.context().user(self.id)?.map(|user| timestamp(user.created_at)))
+    }
+
+    field updated_at(&executor) -> FieldResult<Option<String>> {
+        Ok(executor.context().user(self.id)?.map(|user| timestamp(user.updated_at)))
+    }
+
+    field last_seen(&executor) -> FieldResult<Option<String>> {
+        Ok(executor.context().user(self.id)?.and_then(|user| user.last_seen).map(timestamp))
+    }
+
+    field orgs(&executor) -> FieldResult<Vec<Org>> {
+        let context = executor.context();
+        let user = match context.user(self.id)? {
+            Some(user) => user,
+            None => return Ok(vec![]),
+        };
+        Ok(user.orgs.iter()
+            .filter_map(|org| match (org.id, org.name.as_ref()) {
+                (Some(id), Some(name)) => Some(Org::new(context, id, name.clone())),
+                _ => None,
+            })
+            .collect())
+    }
+
+    field linodes(&executor) -> FieldResult<Vec<Linode>> {
+        let context = executor.context();
+        let user = match context.user(self.id)? {
+            Some(user) => user,
+            None => return Ok(vec![]),
+        };
+        Ok(user.linodes.iter()
+            .filter_map(|linode| match (linode.id, linode.name.as_ref()) {
+                (Some(id), Some(name)) => Some(Linode::new(context, id, name.clone(), linode.org)),
+                _ => None,
+            })
+            .collect())
+    }
+});
+
+
+graphql_object!(Linode: Context |&self| {
+    description: "A linode, owned by at most one org"
+
+    field id() -> ID {
+        to_id(self.id)
+    }
+
+    field name() -> &str {
+        &self.name
+    }
+
+    field org(&executor) -> FieldResult<Option<Org>> {
+        let org_id = match self.org {
+            Some(org_id) => org_id,
+            None => return Ok(None),
+        };
+        let context = executor.context();
+        Ok(context.org(org_id)?.map(|org| Org::new(context, org.id, org.name.clone())))
+    }
+});
+
+
+// ------------------------------------------
+// ----------- Queries ----------------------
+// ------------------------------------------
+
+pub struct Query;
+
+graphql_object!(Query: Context |&self| {
+    field orgs(&executor) -> FieldResult<Vec<Org>> {
+        let context = executor.context();
+        let orgs = context.state.store.with(|repo| repo.get_all_orgs())?;
+        Ok(orgs.into_iter().map(|org| {
+            let gql_org = Org::new(context, org.id, org.name.clone());
+            lock(&context.orgs).prime(org.id, org);
+            gql_org
+        }).collect())
+    }
+
+    field org(&executor, id: ID) -> FieldResult<Option<Org>> {
+        let context = executor.context();
+        Ok(context.org(parse_id(&id)?)?.map(|org| Org::new(context, org.id, org.name.clone())))
+    }
+
+    field users(&executor) -> FieldResult<Vec<User>> {
+        let context = executor.context();
+        let users = context.state.store.with(|repo| repo.get_all_users())?;
+        Ok(users.iter().filter_map(|user| User::from_row(context, user)).collect())
+    }
+
+    field user(&executor, id: ID) -> FieldResult<Option<User>> {
+        let context = executor.context();
+        Ok(context.user(parse_id(&id)?)?.map(|user| User::new(context, user.id, user.email.clone())))
+    }
+
+    field linodes(&executor) -> FieldResult<Vec<Linode>> {
+        let context = executor.context();
+        let linodes = context.state.store.with(|repo| repo.get_all_linodes())?;
+        Ok(linodes.into_iter().map(|linode| {
+            let gql_linode = Linode::from_info(context, &linode);
+            lock(&context.linodes).prime(linode.id, linode);
+            gql_linode
+        }).collect())
+    }
+
+    field linode(&executor, id: ID) -> FieldResult<Option<Linode>> {
+        let context = executor.context();
+        Ok(context.linode(parse_id(&id)?)?.map(|linode| Linode::from_info(context, &linode)))
+    }
+});
+
+
+// ------------------------------------------
+// ----------- Mutations --------------------
+// ------------------------------------------
+
+pub struct Mutation;
+
+graphql_object!(Mutation: Context |&self| {
+    field create_org(&executor, name: String) -> FieldResult<Org> {
+        let org_id = executor.context().mutate(|repo| {
+            let org_id = repo.create_org(&name)?;
+            events::record(repo, "org.created", json!({"id": org_id, "name": name}))?;
+            Ok(org_id)
+        })?;
+        Ok(Org::new(executor.context(), org_id, name))
+    }
+
+    field create_user(&executor, email: String, org_ids: Option<Vec<ID>>, display_name: Option<String>) -> FieldResult<User> {
+        let org_ids = org_ids.unwrap_or_default().iter().map(parse_id).collect::<Result<Vec<_>>>()?;
+        let user_id = executor.context().mutate(|repo| {
+            let user_id = repo.create_user(&email, &org_ids)?;
+            if display_name.is_some() {
+                repo.update_user(user_id, None, display_name.as_ref().map(String::as_str))?;
+            }
+            events::record(repo, "user.created", json!({
+                "id": user_id, "email": email, "display_name": display_name, "org_ids": org_ids
+            }))?;
+            Ok(user_id)
+        })?;
+        Ok(User::new(executor.context(), user_id, email))
+    }
+
+    field create_linode(&executor, name: String, org_id: ID) -> FieldResult<Linode> {
+        let org_id = parse_id(&org_id)?;
+        let linode_id = executor.context().mutate(|repo| {
+            let linode_id = repo.create_linode(&name, org_id)?;
+            events::record(repo, "linode.created",
+                           json!({"id": linode_id, "name": name, "org_id": org_id}))?;
+            Ok(linode_id)
+        })?;
+        Ok(Linode::new(executor.context(), linode_id, name, Some(org_id)))
+    }
+
+    field add_member(&executor, user_id: ID, org_id: ID) -> FieldResult<Option<User>> {
+        let user_id = parse_id(&user_id)?;
+        let org_id = parse_id(&org_id)?;
+        let context = executor.context();
+        context.mutate(|repo| {
+            repo.add_member(user_id, org_id)?;
+            events::record(repo, "member.created", json!({"user_id": user_id, "org_id": org_id}))
+        })?;
+        Ok(context.user(user_id)?.map(|user| User::new(context, user.id, user.email.clone())))
+    }
+});
@@ -0,0 +1,158 @@
+/*!
+Idempotency keys for create requests
+
+A request sent with an `Idempotency-Key` header has its successful response
+remembered for `idempotency_window` seconds. Retries with the same key and
+payload get the original response back instead of being run again. Keys
+are kept in memory, so they don't survive a restart, and at most the
+10,000 most recent are remembered.
+*/
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+
+/// Max length of a client provided key
+pub const MAX_KEY_LEN: usize = 255;
+
+/// Keys remembered before the oldest are forgotten, even if still in their window
+const MAX_TRACKED: usize = 10_000;
+
+
+enum Outcome {
+    InProgress,
+    Done(u16, Value),
+}
+
+
+struct Entry {
+    /// Distinguishes this claim from earlier or later claims of the same key
+    claim: u64,
+    fingerprint: u64,
+    outcome: Outcome,
+}
+
+
+/// Hash of the parts of a request that must match for it to be a replay
+pub fn fingerprint(method: &str, url: &str, body: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    method.hash(&mut hasher);
+    url.hash(&mut hasher);
+    body.hash(&mut hasher);
+    hasher.finish()
+}
+
+
+/// Result of starting a request under an idempotency key
+pub enum Begin<'a> {
+    /// First use of the key, run the request and `complete` it
+    New(Pending<'a>),
+    /// The key already completed with this status and response
+    Replay(u16, Value),
+    /// The key was used with a different payload
+    Conflict,
+    /// A request with the key is still running
+    InProgress,
+}
+
+
+/// A request running under a newly claimed key.
+/// The key is released if dropped without being completed.
+pub struct Pending<'a> {
+    store: &'a IdempotencyStore,
+    key: String,
+    claim: u64,
+    completed: bool,
+}
+impl<'a> Pending<'a> {
+    /// Remember the response's status and body for replays
+    pub fn complete(mut self, status: u16, value: Value) {
+        let mut keys = self.store.keys.lock().expect("idempotency lock poisoned");
+        if let Some(entry) = keys.entries.get_mut(&self.key) {
+            // the claim may have been evicted and the key claimed again since
+            if entry.claim == self.claim {
+                entry.outcome = Outcome::Done(status, value);
+            }
+        }
+        self.completed = true;
+    }
+}
+impl<'a> Drop for Pending<'a> {
+    fn drop(&mut self) {
+        if self.completed { return }
+        if let Ok(mut keys) = self.store.keys.lock() {
+            keys.remove(&self.key, self.claim);
+        }
+    }
+}
+
+
+#[derive(Default)]
+struct Keys {
+    entries: HashMap<String, Entry>,
+    /// Claims in the order they were made, oldest first
+    claimed: VecDeque<(Instant, String, u64)>,
+    next_claim: u64,
+}
+impl Keys {
+    /// Forget `key` if it's still held by `claim`
+    fn remove(&mut self, key: &str, claim: u64) {
+        let current = self.entries.get(key).map(|entry| entry.claim == claim).unwrap_or(false);
+        if current {
+            self.entries.remove(key);
+        }
+    }
+
+    /// Forget claims older than `window`, and the oldest ones past `MAX_TRACKED`.
+    /// Only the front of `claimed` is looked at, so this is cheap per request.
+    fn expire(&mut self, now: Instant, window: Duration) {
+        loop {
+            let expired = match self.claimed.front() {
+                Some(&(created, _, _)) => {
+                    now.duration_since(created) >= window || self.claimed.len() >= MAX_TRACKED
+                }
+                None => false,
+            };
+            if !expired { break }
+            let (_, key, claim) = self.claimed.pop_front().expect("front checked above");
+            self.remove(&key, claim);
+        }
+    }
+}
+
+
+#[derive(Default)]
+pub struct IdempotencyStore {
+    keys: Mutex<Keys>,
+}
+impl IdempotencyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claim `key` for a request with `fingerprint`, or look up its earlier outcome.
+    /// Keys older than `window` are forgotten.
+    pub fn begin(&self, key: &str, fingerprint: u64, window: Duration) -> Begin {
+        let now = Instant::now();
+        let mut keys = self.keys.lock().expect("idempotency lock poisoned");
+        keys.expire(now, window);
+        if let Some(entry) = keys.entries.get(key) {
+            if entry.fingerprint != fingerprint {
+                return Begin::Conflict
+            }
+            return match entry.outcome {
+                Outcome::InProgress => Begin::InProgress,
+                Outcome::Done(status, ref value) => Begin::Replay(status, value.clone()),
+            }
+        }
+        let claim = keys.next_claim;
+        keys.next_claim += 1;
+        keys.entries.insert(key.to_string(), Entry { claim: claim, fingerprint: fingerprint, outcome: Outcome::InProgress });
+        keys.claimed.push_back((now, key.to_string(), claim));
+        Begin::New(Pending { store: self, key: key.to_string(), claim: claim, completed: false })
+    }
+}
@@ -0,0 +1,265 @@
+/*!
+OrgDemo server library
+
+Storage backends, configuration and migration helpers, the http request
+router used by the `org_demo` binary and its GraphQL schema. The api's
+models and request bodies live in the `org_demo_client` crate.
+*/
+#![recursion_limit = "1024"]
+
+#[macro_use] extern crate error_chain;
+extern crate clap;
+#[macro_use] extern crate rouille;
+#[macro_use] extern crate log;
+#[macro_use] extern crate serde_derive;
+#[macro_use] extern crate serde_json;
+extern crate serde;
+extern crate env_logger;
+extern crate chrono;
+extern crate migrant_lib;
+#[macro_use] extern crate rusqlite;
+extern crate r2d2;
+extern crate r2d2_sqlite;
+extern crate toml;
+extern crate reqwest;
+extern crate hmac;
+extern crate sha2;
+extern crate rand;
+extern crate signal_hook;
+#[macro_use] extern crate juniper;
+extern crate org_demo_client;
+#[cfg(feature = "pg")] extern crate postgres;
+#[cfg(feature = "pg")] extern crate r2d2_postgres;
+
+#[macro_use] mod macros;
+pub mod errors;
+pub mod config;
+pub mod service;
+pub mod store;
+pub mod admin;
+pub mod backup;
+pub mod check;
+pub mod metrics;
+pub mod logging;
+pub mod limits;
+pub mod cors;
+pub mod idempotency;
+pub mod batch;
+pub mod etag;
+pub mod events;
+pub mod webhooks;
+pub mod graphql;
+pub mod formats;
+mod signals;
+pub mod tls;
+mod assets;
+
+pub use org_demo_client::{models, api};
+
+
+use errors::*;
+
+
+// ---------------
+// Traits for constructing `rouille::Response`s from other types
+// ---------------
+
+pub trait ToHtmlResponse {
+    fn to_html_resp(&self) -> rouille::Response;
+}
+
+pub trait ToTextResponse {
+    fn to_text_resp(&self) -> rouille::Response;
+}
+
+pub trait ToJsonResponse {
+    fn to_json_resp(&self) -> Result<rouille::Response>;
+}
+
+/// Consumes a list, e.g. `formats::Rows`, writing it as csv with a header row
+pub trait ToCsvResponse {
+    fn to_csv_resp(self) -> Result<rouille::Response>;
+}
+
+/// Consumes a list, e.g. `formats::Rows`, writing it as newline delimited json
+pub trait ToNdjsonResponse {
+    fn to_ndjson_resp(self) -> Result<rouille::Response>;
+}
+
+
+impl ToHtmlResponse for String {
+    fn to_html_resp(&self) -> rouille::Response {
+        rouille::Response::html(self.as_str())
+    }
+}
+impl ToTextResponse for String {
+    fn to_text_resp(&self) -> rouille::Response {
+        rouille::Response::text(self.as_str())
+    }
+}
+
+impl ToJsonResponse for serde_json::Value {
+    fn to_json_resp(&self) -> Result<rouille::Response> {
+        let s = serde_json::to_string(self)?;
+        let resp = rouille::Response::from_data("application/json", s.as_bytes());
+        Ok(resp)
+    }
+}
+
+
+/// Trait for parsing `json` from `rouille::Request` bodies into some type `T`
+///
+/// # Example
+///
+/// ```rust,ignore
+/// #[derive(Deserialize)]
+/// struct PostData {
+///     name: String,
+///     age: u32,
+/// }
+///```
+///
+/// For a request with a body containing `json`
+///
+/// ```rust,ignore
+/// let post_data = request.parse_json_body::<PostData>(64 * 1024)?;
+/// println!("{}", post_data.name);
+/// ```
+pub trait FromRequestBody {
+    /// Read a utf-8 body of at most `max_size` bytes
+    fn read_body(&self, max_size: u64) -> Result<String>;
+
+    /// Parse a json body of at most `max_size` bytes
+    fn parse_json_body<T: serde::de::DeserializeOwned>(&self, max_size: u64) -> Result<T> {
+        let s = self.read_body(max_size)?;
+        let data = serde_json::from_str::<T>(&s)
+            .map_err(|_| format_err!(ErrorKind::BadRequest, "malformed data"))?;
+        Ok(data)
+    }
+}
+
+impl FromRequestBody for rouille::Request {
+    fn read_body(&self, max_size: u64) -> Result<String> {
+        use std::io::Read;
+        let too_large = self.header("Content-Length")
+            .and_then(|len| len.parse::<u64>().ok())
+            .map(|len| len > max_size)
+            .unwrap_or(false);
+        if too_large { bail!(ErrorKind::PayloadTooLarge(max_size)) }
+        let body = self.data().expect("Can't read request body twice");
+        let mut s = String::new();
+        body.take(max_size + 1).read_to_string(&mut s)?;
+        if s.len() as u64 > max_size { bail!(ErrorKind::PayloadTooLarge(max_size)) }
+        Ok(s)
+    }
+}
+
+
+/// Migration function to insert some sample data
+fn migration_add_sample_data(config: migrant_lib::ConnConfig) -> std::result::Result<(), Box<std::error::Error>> {
+    match config.database_type() {
+        migrant_lib::DbKind::Sqlite => {
+            let mut conn = rusqlite::Connection::open(&config.database_path()?)?;
+            let trans = conn.transaction()?;
+            store::insert_sample_data(&*trans)?;
+            trans.commit()?;
+        }
+        #[cfg(feature = "pg")]
+        migrant_lib::DbKind::Postgres => {
+            let mut conn = postgres::Client::connect(&config.connect_string()?, postgres::NoTls)?;
+            let mut trans = conn.transaction()?;
+            store::insert_sample_data(&store::pg::PgRepo::new(&mut trans))?;
+            trans.commit()?;
+        }
+        kind => return Err(format!("Unsupported database type: {:?}", kind).into()),
+    }
+    Ok(())
+}
+
+fn migration_empty(_: migrant_lib::ConnConfig) -> std::result::Result<(), Box<std::error::Error>> {
+    Ok(())
+}
+
+
+/// Tags of the migrations set up by `migrant_config`, in the order they're applied
+pub static MIGRATIONS: &'static [&'static str] = &["init", "populate", "events", "webhooks", "profiles", "request-ids", "webhook-detach"];
+
+
+/// Sql migration embedded in the binary
+fn embedded(tag: &str, up: &'static str, down: &'static str) -> Box<migrant_lib::Migratable> {
+    migrant_lib::EmbeddedMigration::with_tag(tag)
+        .up(up)
+        .down(down)
+        .boxed()
+}
+
+
+/// Build a migrant database configuration
+///
+/// Sql migrations are embedded in the binary so the server doesn't
+/// need to be run from the project directory, no migration location is set.
+pub fn migrant_config(config: &config::Config) -> Result<migrant_lib::Config> {
+    // migrations applied after `init` and the sample data
+    let (settings, init, migrations) = match config.backend {
+        config::Backend::Sqlite => {
+            let settings = migrant_lib::Settings::configure_sqlite()
+                .database_path(&config.database_path)?
+                .build()?;
+            let init = embedded("init", include_str!("../migrations/init/up.sql"),
+                                include_str!("../migrations/init/down.sql"));
+            (settings, init, vec![
+                embedded("events", include_str!("../migrations/events/up.sql"),
+                         include_str!("../migrations/events/down.sql")),
+                embedded("webhooks", include_str!("../migrations/webhooks/up.sql"),
+                         include_str!("../migrations/webhooks/down.sql")),
+                embedded("profiles", include_str!("../migrations/profiles/up.sql"),
+                         include_str!("../migrations/profiles/down.sql")),
+                embedded("request-ids", include_str!("../migrations/request-ids/up.sql"),
+                         include_str!("../migrations/request-ids/down.sql")),
+                embedded("webhook-detach", include_str!("../migrations/webhook-detach/up.sql"),
+                         include_str!("../migrations/webhook-detach/down.sql")),
+            ])
+        }
+        #[cfg(feature = "pg")]
+        config::Backend::Postgres => {
+            let pg = &config.postgres;
+            let settings = migrant_lib::Settings::configure_postgres()
+                .database_host(&pg.host)
+                .database_port(pg.port)
+                .database_user(&pg.user)
+                .database_password(&pg.password)
+                .database_name(&pg.database)
+                .build()?;
+            let init = embedded("init", include_str!("../migrations/pg/init/up.sql"),
+                                include_str!("../migrations/pg/init/down.sql"));
+            (settings, init, vec![
+                embedded("events", include_str!("../migrations/pg/events/up.sql"),
+                         include_str!("../migrations/pg/events/down.sql")),
+                embedded("webhooks", include_str!("../migrations/pg/webhooks/up.sql"),
+                         include_str!("../migrations/pg/webhooks/down.sql")),
+                embedded("profiles", include_str!("../migrations/pg/profiles/up.sql"),
+                         include_str!("../migrations/pg/profiles/down.sql")),
+                embedded("request-ids", include_str!("../migrations/pg/request-ids/up.sql"),
+                         include_str!("../migrations/pg/request-ids/down.sql")),
+                embedded("webhook-detach", include_str!("../migrations/pg/webhook-detach/up.sql"),
+                         include_str!("../migrations/pg/webhook-detach/down.sql")),
+            ])
+        }
+        #[cfg(not(feature = "pg"))]
+        config::Backend::Postgres => {
+            bail!("The `postgres` backend requires building with the `pg` feature")
+        }
+        config::Backend::Memory => {
+            bail!("The `memory` backend is migrated on startup and has nothing to manage")
+        }
+    };
+    let populate = migrant_lib::FnMigration::with_tag("populate")
+        .up(migration_add_sample_data)
+        .down(migration_empty)
+        .boxed();
+    let mut all = vec![init, populate];
+    all.extend(migrations);
+    let mut config = migrant_lib::Config::with_settings(&settings);
+    config.use_migrations(&all)?;
+    Ok(config)
+}
@@ -0,0 +1,89 @@
+/*!
+Per-client request rate limiting
+
+Each client gets a token bucket per class of route. Clients are identified
+by ip address. The api doesn't authenticate `Authorization` tokens, so keying
+on them would let a client reset its limit by sending a new one per request.
+*/
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use rouille;
+
+
+/// Buckets kept before idle (full) ones are pruned
+const MAX_TRACKED: usize = 10_000;
+
+
+/// Token bucket parameters. A `per_minute` of `0` disables the limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+impl Bucket {
+    /// Refill for the time elapsed since the last update
+    fn refill(&mut self, rate: &Rate, now: Instant) {
+        let elapsed = now.duration_since(self.updated);
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.;
+        self.tokens = (self.tokens + secs * rate.per_minute as f64 / 60.).min(rate.burst as f64);
+        self.updated = now;
+    }
+}
+
+
+/// Which bucket a request draws from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Class {
+    Read,
+    Mutation,
+}
+
+
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<(Class, String), Bucket>>,
+}
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take a token for `client`, or return the number of
+    /// seconds until one is available
+    pub fn check(&self, class: Class, client: &str, rate: &Rate) -> ::std::result::Result<(), u64> {
+        if rate.per_minute == 0 { return Ok(()) }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("rate limiter lock poisoned");
+        if buckets.len() >= MAX_TRACKED {
+            buckets.retain(|&(bucket_class, _), bucket| {
+                if bucket_class != class { return true }
+                bucket.refill(rate, now);
+                bucket.tokens < rate.burst as f64
+            });
+        }
+        let bucket = buckets.entry((class, client.to_string()))
+            .or_insert_with(|| Bucket { tokens: rate.burst as f64, updated: now });
+        bucket.refill(rate, now);
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            Ok(())
+        } else {
+            let wait = (1. - bucket.tokens) * 60. / rate.per_minute as f64;
+            Err(wait.ceil().max(1.) as u64)
+        }
+    }
+}
+
+
+/// Key identifying the client making `request`
+pub fn client_key(request: &rouille::Request) -> String {
+    format!("ip:{}", request.remote_addr().ip())
+}
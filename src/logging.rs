@@ -0,0 +1,249 @@
+/*!
+Logging
+
+Log lines are written as text or json (`log_format`) to stderr or a
+size-rotated file (`log_file`). While a request is being handled its
+`X-Request-Id` is attached to every line logged on the handling thread.
+*/
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use chrono::{Local, Utc};
+use env_logger::filter;
+use log::{self, Log, Record, Metadata};
+use serde_json::{self, Value};
+
+use config::{Config, LogFormat};
+use errors::*;
+
+
+/// Target of access log lines
+pub static ACCESS_TARGET: &'static str = "org_demo::access";
+
+
+thread_local! {
+    /// Id of the request being handled on this thread
+    static REQUEST_ID: RefCell<Option<String>> = RefCell::new(None);
+
+    /// Extra json fields attached to the next line logged on this thread
+    static FIELDS: RefCell<Vec<(&'static str, Value)>> = RefCell::new(vec![]);
+}
+
+
+static REQUEST_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Generate a new random request id
+pub fn new_request_id() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_usize(REQUEST_COUNT.fetch_add(1, Ordering::Relaxed));
+    format!("{:016x}", hasher.finish())
+}
+
+
+/// Check if a client provided request id is reasonable to propagate
+pub fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+
+/// Id of the request currently being handled on this thread, if any
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+
+/// Clears the thread's request id when dropped, even if the handler panics
+struct RequestIdGuard;
+impl Drop for RequestIdGuard {
+    fn drop(&mut self) {
+        REQUEST_ID.with(|id| *id.borrow_mut() = None);
+    }
+}
+
+
+/// Run `f` with `id` attached to everything logged on this thread
+pub fn with_request_id<T, F: FnOnce() -> T>(id: &str, f: F) -> T {
+    REQUEST_ID.with(|current| *current.borrow_mut() = Some(id.to_string()));
+    let _guard = RequestIdGuard;
+    f()
+}
+
+
+/// Log a handled request
+pub fn access(method: &str, url: &str, status: u16, ms: f32) {
+    FIELDS.with(|fields| {
+        *fields.borrow_mut() = vec![
+            ("method", json!(method)),
+            ("url", json!(url)),
+            ("status", json!(status)),
+            ("duration_ms", json!(ms)),
+        ];
+    });
+    info!(target: ACCESS_TARGET, "{} {} -> {} ({}ms)", method, url, status, ms);
+    FIELDS.with(|fields| fields.borrow_mut().clear());
+}
+
+
+/// A log file that's rotated once it grows past `max_size` bytes.
+/// `path` is moved to `path.1`, `path.1` to `path.2`, and so on, keeping `keep` old files.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: u32,
+    file: fs::File,
+    size: u64,
+}
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, keep: u32) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), max_size: max_size, keep: keep, file: file, size: size })
+    }
+
+    fn numbered(&self, n: u32) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let oldest = self.numbered(self.keep);
+            if oldest.exists() { fs::remove_file(&oldest)?; }
+            for n in (1..self.keep).rev() {
+                let from = self.numbered(n);
+                if from.exists() { fs::rename(&from, self.numbered(n + 1))?; }
+            }
+            fs::rename(&self.path, self.numbered(1))?;
+        }
+        self.file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+
+struct Logger {
+    filter: Arc<RwLock<filter::Filter>>,
+    format: LogFormat,
+    out: Mutex<Box<Write + Send>>,
+}
+impl Logger {
+    fn format_text(&self, record: &Record) -> String {
+        let request_id = request_id().map(|id| format!(" [{}]", id)).unwrap_or_default();
+        format!("{} [{}] - [{}]{} -> {}\n",
+                Local::now().format("%Y-%m-%d_%H:%M:%S"),
+                record.level(),
+                record.module_path().unwrap_or("<unknown>"),
+                request_id,
+                record.args())
+    }
+
+    fn format_json(&self, record: &Record) -> String {
+        let mut line = serde_json::Map::new();
+        line.insert("time".into(), json!(Utc::now().to_rfc3339()));
+        line.insert("level".into(), json!(record.level().to_string()));
+        line.insert("target".into(), json!(record.target()));
+        if let Some(id) = request_id() {
+            line.insert("request_id".into(), json!(id));
+        }
+        line.insert("message".into(), json!(record.args().to_string()));
+        FIELDS.with(|fields| {
+            for &(key, ref value) in fields.borrow().iter() {
+                line.insert(key.into(), value.clone());
+            }
+        });
+        let mut line = Value::Object(line).to_string();
+        line.push('\n');
+        line
+    }
+}
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.read().map(|filter| filter.enabled(metadata)).unwrap_or(false)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.read().map(|filter| filter.matches(record)).unwrap_or(false) { return }
+        let line = match self.format {
+            LogFormat::Text => self.format_text(record),
+            LogFormat::Json => self.format_json(record),
+        };
+        if let Ok(mut out) = self.out.lock() {
+            out.write_all(line.as_bytes()).ok();
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut out) = self.out.lock() {
+            out.flush().ok();
+        }
+    }
+}
+
+
+/// Handle for changing the installed logger's filter
+pub struct LogHandle {
+    filter: Arc<RwLock<filter::Filter>>,
+}
+impl LogHandle {
+    /// Replace the log filter, e.g. `info` or `org_demo=debug`
+    pub fn set_filter(&self, filters: &str) {
+        let filter = filter::Builder::new().parse(filters).build();
+        log::set_max_level(filter.filter());
+        if let Ok(mut current) = self.filter.write() {
+            *current = filter;
+        }
+    }
+}
+
+
+/// Install the global logger, filtered by the configured `log` level
+/// e.g. ORG_DEMO_LOG=info org_demo serve
+pub fn init(config: &Config) -> Result<LogHandle> {
+    let filter = filter::Builder::new().parse(&config.log).build();
+    let out: Box<Write + Send> = match config.log_file {
+        Some(ref path) => {
+            let file = RotatingFile::open(path, config.log_max_size, config.log_keep)
+                .chain_err(|| format!("Unable to open log file: {:?}", path))?;
+            Box::new(file)
+        }
+        None => Box::new(io::stderr()),
+    };
+    log::set_max_level(filter.filter());
+    let filter = Arc::new(RwLock::new(filter));
+    log::set_boxed_logger(Box::new(Logger {
+        filter: filter.clone(),
+        format: config.log_format,
+        out: Mutex::new(out),
+    }))?;
+    Ok(LogHandle { filter: filter })
+}
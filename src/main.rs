@@ -1,185 +1,36 @@
-#![recursion_limit = "1024"]
-
 #[macro_use] extern crate error_chain;
 #[macro_use] extern crate clap;
-#[macro_use] extern crate rouille;
-#[macro_use] extern crate log;
-#[macro_use] extern crate serde_derive;
-#[macro_use] extern crate serde_json;
-extern crate serde;
-extern crate env_logger;
-extern crate chrono;
 extern crate migrant_lib;
-extern crate rusqlite;
-extern crate r2d2;
-extern crate r2d2_sqlite;
+extern crate org_demo;
 
-#[macro_use] mod macros;
-mod errors;
-mod service;
-mod models;
+use std::fs;
 
-use std::env;
 use clap::{App, Arg, SubCommand};
 
-use errors::*;
+use org_demo::{config, service, admin, backup, check, graphql, migrant_config};
+use org_demo::errors::*;
 
 
 static APPNAME: &'static str = "OrgDemo";
 
 
-// ---------------
-// Traits for constructing `rouille::Response`s from other types
-// ---------------
-
-pub trait ToHtmlResponse {
-    fn to_html_resp(&self) -> rouille::Response;
-}
-
-pub trait ToTextResponse {
-    fn to_text_resp(&self) -> rouille::Response;
-}
-
-pub trait ToJsonResponse {
-    fn to_json_resp(&self) -> Result<rouille::Response>;
-}
-
-
-impl ToHtmlResponse for String {
-    fn to_html_resp(&self) -> rouille::Response {
-        rouille::Response::html(self.as_str())
-    }
-}
-impl ToTextResponse for String {
-    fn to_text_resp(&self) -> rouille::Response {
-        rouille::Response::text(self.as_str())
-    }
-}
-
-impl ToJsonResponse for serde_json::Value {
-    fn to_json_resp(&self) -> Result<rouille::Response> {
-        let s = serde_json::to_string(self)?;
-        let resp = rouille::Response::from_data("application/json", s.as_bytes());
-        Ok(resp)
-    }
-}
-
-
-/// Trait for parsing `json` from `rouille::Request` bodies into some type `T`
-///
-/// # Example
-///
-/// ```rust,ignore
-/// #[derive(Deserialize)]
-/// struct PostData {
-///     name: String,
-///     age: u32,
-/// }
-///```
-///
-/// For a request with a body containing `json`
-///
-/// ```rust,ignore
-/// let post_data = request.parse_json_body::<PostData>()?;
-/// println!("{}", post_data.name);
-/// ```
-pub trait FromRequestBody {
-    fn parse_json_body<T: serde::de::DeserializeOwned>(&self) -> Result<T>;
-}
-
-impl FromRequestBody for rouille::Request {
-    fn parse_json_body<T: serde::de::DeserializeOwned>(&self) -> Result<T> {
-        use std::io::Read;
-        let mut body = self.data().expect("Can't read request body twice");
-        let mut s = String::new();
-        body.read_to_string(&mut s)?;
-        let data = serde_json::from_str::<T>(&s)
-            .map_err(|_| format_err!(ErrorKind::BadRequest, "malformed data"))?;
-        Ok(data)
-    }
-}
-
-
-/// Migration function to insert some sample data
-fn migration_add_sample_data(config: migrant_lib::ConnConfig) -> std::result::Result<(), Box<std::error::Error>> {
-    let org_names = ["James Inc", "Bean Group", "Cat Collective", "Dog Dancers"];
-    let emails = [
-        ("james@kominick.com", vec![0, 1, 2, 3]),
-        ("bean@burrito.org", vec![1, 3]),
-        ("cheese@pasta.io", vec![1, 2]),
-    ];
-    let linode_names = [
-        ("charlie", 0),
-        ("mac", 1),
-        ("frank", 2),
-        ("dennis", 3),
-        ("dee", 2),
-    ];
-
-    let db_path = config.database_path()?;
-    let mut conn = rusqlite::Connection::open(&db_path)?;
-    let trans = conn.transaction()?;
-
-    {
-        let stmt = "insert into org (name) values (?)";
-        let mut stmt = trans.prepare(&stmt)?;
-        let org_ids = org_names.iter().map(|name| {
-            Ok(stmt.insert(&[name])?)
-        }).collect::<Result<Vec<i64>>>()?;
-
-        let stmt = "insert into user (email) values (?)";
-        let mut stmt = trans.prepare(&stmt)?;
-        let user_ids = emails.iter().map(|&(email, _)| {
-            Ok(stmt.insert(&[&email])?)
-        }).collect::<Result<Vec<i64>>>()?;
-
-        let stmt = "insert into user_org (user, org) values (?, ?)";
-        let mut stmt = trans.prepare(&stmt)?;
-        for (user_id, link) in user_ids.iter().zip(emails.iter()) {
-            let org_indices = &link.1;
-            for ind in org_indices {
-                let org_id = org_ids[*ind];
-                stmt.insert(&[user_id, &org_id])?;
-            }
-        }
-
-        let stmt = "insert into linode (name, org) values (?, ?)";
-        let mut stmt = trans.prepare(&stmt)?;
-        for &(name, org_ind) in linode_names.iter() {
-            let org_id = org_ids[org_ind];
-            stmt.insert(&[&name, &org_id])?;
-        }
-    }
-    trans.commit()?;
-    Ok(())
-}
-
-fn migration_empty(_: migrant_lib::ConnConfig) -> std::result::Result<(), Box<std::error::Error>> {
-    Ok(())
-}
-
-
-/// Build a migrant database configuration
-pub fn migrant_config() -> Result<migrant_lib::Config> {
-    let dir = env::current_dir()?;
-    let db_path = dir.join("db/org_demo");
-    let migration_dir = dir.join("migrations");
-    let settings = migrant_lib::Settings::configure_sqlite()
-        .database_path(&db_path)?
-        .migration_location(&migration_dir)?
-        .build()?;
-    let mut config = migrant_lib::Config::with_settings(&settings);
-    config.use_migrations(&[
-        migrant_lib::FileMigration::with_tag("init")?
-            .up("migrations/init/up.sql")?
-            .up("migrations/init/up.sql")?
-            .boxed(),
-        migrant_lib::FnMigration::with_tag("populate")?
-            .up(migration_add_sample_data)
-            .down(migration_empty)
-            .boxed(),
-    ])?;
-    Ok(config)
+/// Build an admin subcommand with the `--server` and `--format` options shared by all admin commands
+fn admin_command<'a, 'b>(name: &'a str) -> App<'a, 'b> {
+    SubCommand::with_name(name)
+        .arg(Arg::with_name("server")
+            .long("server")
+            .takes_value(true)
+            .global(true)
+            .env("ORG_DEMO_SERVER")
+            .help("Url of a running server to manage, e.g. 'http://localhost:3002'. \
+                   Operates on the local database if not provided"))
+        .arg(Arg::with_name("format")
+            .long("format")
+            .takes_value(true)
+            .global(true)
+            .possible_values(&["table", "json"])
+            .default_value("table")
+            .help("Output format"))
 }
 
 
@@ -187,41 +38,281 @@ fn run() -> Result<()> {
     let matches = App::new(APPNAME)
         .version(crate_version!())
         .about("OrgDemo Sever")
+        .arg(Arg::with_name("config")
+            .long("config")
+            .short("c")
+            .takes_value(true)
+            .global(true)
+            .help("Path to a `toml` config file. Defaults to `org_demo.toml` in the current directory if it exists"))
+        .arg(Arg::with_name("database-path")
+            .long("database-path")
+            .takes_value(true)
+            .global(true)
+            .help("Path to the sqlite database file"))
+        .arg(Arg::with_name("backend")
+            .long("backend")
+            .takes_value(true)
+            .possible_values(&["sqlite", "postgres", "memory"])
+            .global(true)
+            .help("Storage backend. `postgres` requires building with the `pg` feature"))
+        .subcommand(SubCommand::with_name("config")
+            .about("Configuration functions")
+            .subcommand(SubCommand::with_name("show")
+                .about("Print the effective configuration")))
+        .subcommand(SubCommand::with_name("schema")
+            .about("Print the GraphQL schema served at `/graphql`")
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .help("Write the schema to this file instead of stdout")))
         .subcommand(SubCommand::with_name("database")
             .about("Database functions")
             .subcommand(SubCommand::with_name("migrate")
                 .about("Apply any available un-applied migrations"))
             .subcommand(SubCommand::with_name("shell")
-                .about("Open a database shell")))
+                .about("Open a database shell"))
+            .subcommand(SubCommand::with_name("check")
+                .about("Check the database for corruption, foreign key violations, duplicate memberships and orphaned rows")
+                .arg(Arg::with_name("fix")
+                    .long("fix")
+                    .help("Remove duplicate and orphaned memberships and detach linodes from missing orgs, in a single transaction")))
+            .subcommand(SubCommand::with_name("backup")
+                .about("Copy the database to a file. Safe to run while the server is running")
+                .arg(Arg::with_name("path")
+                    .required(true)
+                    .help("File to write the backup to")))
+            .subcommand(SubCommand::with_name("restore")
+                .about("Replace the database contents with a backup, after checking its integrity and migrations. \
+                        The current contents are saved alongside the database with a `.pre-restore` suffix")
+                .arg(Arg::with_name("path")
+                    .required(true)
+                    .help("Backup file to restore from"))))
+        .subcommand(admin_command("org")
+            .about("Manage orgs")
+            .subcommand(SubCommand::with_name("list")
+                .about("List orgs with their users and linodes"))
+            .subcommand(SubCommand::with_name("create")
+                .about("Create an org")
+                .arg(Arg::with_name("name").required(true)))
+            .subcommand(SubCommand::with_name("rename")
+                .about("Rename an org")
+                .arg(Arg::with_name("id").required(true))
+                .arg(Arg::with_name("name").required(true)))
+            .subcommand(SubCommand::with_name("delete")
+                .about("Delete an org along with its memberships and linodes")
+                .arg(Arg::with_name("id").required(true))))
+        .subcommand(admin_command("user")
+            .about("Manage users")
+            .subcommand(SubCommand::with_name("list")
+                .about("List users"))
+            .subcommand(SubCommand::with_name("create")
+                .about("Create a user")
+                .arg(Arg::with_name("email").required(true))
+                .arg(Arg::with_name("org")
+                    .long("org")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Id of an org to add the user to. Can be repeated")))
+            .subcommand(SubCommand::with_name("rename")
+                .about("Change a user's email")
+                .arg(Arg::with_name("id").required(true))
+                .arg(Arg::with_name("email").required(true)))
+            .subcommand(SubCommand::with_name("deactivate")
+                .about("Deactivate a user. They keep their memberships but can't authenticate or access linodes")
+                .arg(Arg::with_name("id").required(true)))
+            .subcommand(SubCommand::with_name("reactivate")
+                .about("Reactivate a deactivated user")
+                .arg(Arg::with_name("id").required(true)))
+            .subcommand(SubCommand::with_name("delete")
+                .about("Delete a user along with their memberships")
+                .arg(Arg::with_name("id").required(true))))
+        .subcommand(admin_command("linode")
+            .about("Manage linodes")
+            .subcommand(SubCommand::with_name("list")
+                .about("List linodes"))
+            .subcommand(SubCommand::with_name("create")
+                .about("Create a linode")
+                .arg(Arg::with_name("name").required(true))
+                .arg(Arg::with_name("org-id").required(true)))
+            .subcommand(SubCommand::with_name("rename")
+                .about("Rename a linode")
+                .arg(Arg::with_name("id").required(true))
+                .arg(Arg::with_name("name").required(true)))
+            .subcommand(SubCommand::with_name("move")
+                .about("Move a linode to another org")
+                .arg(Arg::with_name("id").required(true))
+                .arg(Arg::with_name("org-id").required(true)))
+            .subcommand(SubCommand::with_name("delete")
+                .about("Delete a linode")
+                .arg(Arg::with_name("id").required(true))))
+        .subcommand(admin_command("member")
+            .about("Manage org memberships")
+            .subcommand(SubCommand::with_name("add")
+                .about("Add a user to an org")
+                .arg(Arg::with_name("user-id").required(true))
+                .arg(Arg::with_name("org-id").required(true)))
+            .subcommand(SubCommand::with_name("remove")
+                .about("Remove a user from an org")
+                .arg(Arg::with_name("user-id").required(true))
+                .arg(Arg::with_name("org-id").required(true))))
         .subcommand(SubCommand::with_name("serve")
             .about("Initialize Server")
             .arg(Arg::with_name("port")
                 .long("port")
                 .short("p")
                 .takes_value(true)
-                .default_value("3002")
-                .help("Port to listen on."))
+                .help("Port to listen on. Defaults to 3002"))
+            .arg(Arg::with_name("host")
+                .long("host")
+                .takes_value(true)
+                .help("Address to listen on. Defaults to 'localhost'"))
             .arg(Arg::with_name("public")
                 .long("public")
+                .conflicts_with("host")
                 .help("Serve on '0.0.0.0' instead of 'localhost'"))
+            .arg(Arg::with_name("ephemeral")
+                .long("ephemeral")
+                .conflicts_with("backend")
+                .help("Serve from a throwaway in-memory database populated with sample data. Shortcut for `--backend memory`"))
+            .arg(Arg::with_name("backup-interval")
+                .long("backup-interval")
+                .takes_value(true)
+                .help("Back up the database every N minutes, keeping the latest `backup_keep` in `backup_dir`"))
+            .arg(Arg::with_name("static-root")
+                .long("static-root")
+                .takes_value(true)
+                .help("Directory to serve frontend files from. Defaults to 'static'"))
+            .arg(Arg::with_name("pool-size")
+                .long("pool-size")
+                .takes_value(true)
+                .help("Max number of database connections. Defaults to 10"))
+            .arg(Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .takes_value(true)
+                .requires("tls-key")
+                .help("PEM certificate chain to serve https with. Requires building with the `tls` feature"))
+            .arg(Arg::with_name("tls-key")
+                .long("tls-key")
+                .takes_value(true)
+                .requires("tls-cert")
+                .help("PEM private key for `--tls-cert`"))
+            .arg(Arg::with_name("tls-redirect-port")
+                .long("tls-redirect-port")
+                .takes_value(true)
+                .help("Also listen for plain http on this port, redirecting to https"))
+            .arg(Arg::with_name("max-body-size")
+                .long("max-body-size")
+                .takes_value(true)
+                .help("Max request body size in bytes. Larger requests are rejected with a 413. Defaults to 65536"))
+            .arg(Arg::with_name("rate-limit")
+                .long("rate-limit")
+                .takes_value(true)
+                .help("Api requests allowed per client per minute, 0 to disable. Defaults to 600"))
+            .arg(Arg::with_name("mutation-rate-limit")
+                .long("mutation-rate-limit")
+                .takes_value(true)
+                .help("Creates, updates and deletes allowed per client per minute, 0 to disable. Defaults to 60"))
+            .arg(Arg::with_name("cors-origins")
+                .long("cors-origins")
+                .takes_value(true)
+                .help("Comma separated origins allowed to make cross-origin api requests, or `*` for any"))
+            .arg(Arg::with_name("cors-credentials")
+                .long("cors-credentials")
+                .help("Allow cross-origin requests to include credentials"))
+            .arg(Arg::with_name("shutdown-timeout")
+                .long("shutdown-timeout")
+                .takes_value(true)
+                .help("Seconds to wait for in-flight requests on SIGTERM/SIGINT. Defaults to 30"))
+            .arg(Arg::with_name("log")
+                .long("log")
+                .takes_value(true)
+                .help("Log filter, e.g. 'info' or 'org_demo=debug'. Defaults to 'info'"))
+            .arg(Arg::with_name("log-format")
+                .long("log-format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .help("Log line format. Defaults to 'text'"))
+            .arg(Arg::with_name("log-file")
+                .long("log-file")
+                .takes_value(true)
+                .help("Log to a size-rotated file instead of stderr"))
             .arg(Arg::with_name("debug")
                 .long("debug")
-                .help("Output debug logging info. Shortcut for setting env-var LOG=debug")))
+                .conflicts_with("log")
+                .help("Output debug logging info. Shortcut for `--log debug`")))
         .get_matches();
 
+    let config = config::Config::load(&matches)?;
+
     match matches.subcommand() {
-        ("serve", Some(serve_matches)) => {
-            env::set_var("LOG", "info");
-            if serve_matches.is_present("debug") { env::set_var("LOG", "debug"); }
-            let port = serve_matches.value_of("port")
-                .expect("default port should be set by clap")
-                .parse::<u16>()
-                .chain_err(|| "`--port` expects an integer")?;
-            let host = if serve_matches.is_present("public") { "0.0.0.0" } else { "localhost" };
-            service::start(&host, port)?;
+        ("serve", Some(_)) => {
+            service::start(config, || config::Config::load(&matches))?;
+        }
+        ("config", Some(config_matches)) => {
+            match config_matches.subcommand() {
+                ("show", _) => {
+                    match config.source {
+                        Some(ref path) => println!("# loaded from {:?}", path),
+                        None => println!("# no config file found, using defaults"),
+                    }
+                    print!("{}", config.to_toml()?);
+                }
+                _ => {
+                    eprintln!("{}: see `config --help`", APPNAME);
+                }
+            }
+        }
+        ("schema", Some(schema_matches)) => {
+            let schema = graphql::schema_language();
+            match schema_matches.value_of("output") {
+                Some(path) => {
+                    fs::write(path, schema)?;
+                    println!("Wrote GraphQL schema to {:?}", path);
+                }
+                None => print!("{}", schema),
+            }
+        }
+        (command @ "org", Some(admin_matches)) |
+        (command @ "user", Some(admin_matches)) |
+        (command @ "linode", Some(admin_matches)) |
+        (command @ "member", Some(admin_matches)) => {
+            let format = admin::Format::parse(admin_matches.value_of("format").expect("default format set by clap"))?;
+            let admin = admin::connect(&config, admin_matches.value_of("server"))?;
+            admin::run(&*admin, format, command, admin_matches)?;
+        }
+        ("database", Some(db_matches)) if db_matches.subcommand_name() == Some("check") => {
+            let fix = db_matches.subcommand_matches("check").map(|m| m.is_present("fix")).unwrap_or(false);
+            let report = if fix {
+                let (before, after) = check::fix(&config)?;
+                before.print();
+                if !before.is_ok() {
+                    println!("\nAfter fixing:");
+                    after.print();
+                }
+                after
+            } else {
+                let report = check::check(&config)?;
+                report.print();
+                report
+            };
+            if !report.is_ok() {
+                bail!("Database check found problems{}", if fix { " that couldn't be fixed" } else { ", see `--fix`" });
+            }
+        }
+        ("database", Some(db_matches)) if db_matches.subcommand_name() == Some("backup") => {
+            let path = db_matches.subcommand_matches("backup").and_then(|m| m.value_of("path")).expect("required path");
+            backup::backup(&config, path)?;
+            println!("Backed up {:?} to {:?}", config.database_path, path);
+        }
+        ("database", Some(db_matches)) if db_matches.subcommand_name() == Some("restore") => {
+            let path = db_matches.subcommand_matches("restore").and_then(|m| m.value_of("path")).expect("required path");
+            let previous = backup::restore(&config, path)?;
+            println!("Restored {:?} from {:?}, previous contents saved to {:?}", config.database_path, path, previous);
         }
         ("database", Some(db_matches)) => {
-            let config = migrant_config()?;
+            let config = migrant_config(&config)?;
             config.setup()?;
             let config = config.reload()?;
 
@@ -0,0 +1,183 @@
+/*!
+Request metrics, rendered in the Prometheus text exposition format by `GET /metrics`
+
+Requests are labeled by route rather than raw url so ids and names
+in paths don't blow up the number of series.
+*/
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use models::Counts;
+use store::PoolStats;
+
+
+/// Upper bounds (seconds) of the request latency histogram buckets
+static LATENCY_BUCKETS: &'static [f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+
+struct Histogram {
+    /// Non-cumulative count per bucket, the last being `+Inf`
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+impl Histogram {
+    fn new() -> Self {
+        Self { buckets: vec![0; LATENCY_BUCKETS.len() + 1], sum: 0., count: 0 }
+    }
+
+    fn observe(&mut self, secs: f64) {
+        let ind = LATENCY_BUCKETS.iter().position(|bound| secs <= *bound).unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[ind] += 1;
+        self.sum += secs;
+        self.count += 1;
+    }
+}
+
+
+#[derive(Default)]
+struct Registry {
+    /// (method, route, status class) -> count
+    requests: BTreeMap<(String, String, &'static str), u64>,
+    /// (method, route) -> latency
+    latency: BTreeMap<(String, String), Histogram>,
+}
+
+
+/// Request counts and latencies collected by the server
+#[derive(Default)]
+pub struct Metrics {
+    registry: Mutex<Registry>,
+}
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a handled request
+    pub fn observe(&self, method: &str, url: &str, status: u16, elapsed: Duration) {
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.;
+        let route = route_label(url);
+        let mut registry = self.registry.lock().expect("metrics lock poisoned");
+        *registry.requests.entry((method.to_string(), route.clone(), status_class(status))).or_insert(0) += 1;
+        registry.latency.entry((method.to_string(), route)).or_insert_with(Histogram::new).observe(secs);
+    }
+
+    /// Render all metrics. `counts` are omitted if they couldn't be queried.
+    pub fn render(&self, pool: &PoolStats, counts: Option<&Counts>) -> String {
+        let mut out = String::new();
+        {
+            let registry = self.registry.lock().expect("metrics lock poisoned");
+
+            header(&mut out, "org_demo_http_requests_total", "counter", "Requests handled, by route and status class");
+            for (&(ref method, ref route, class), count) in &registry.requests {
+                writeln!(out, "org_demo_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                         method, escape(route), class, count).unwrap();
+            }
+
+            header(&mut out, "org_demo_http_request_duration_seconds", "histogram", "Request latency, by route");
+            for (&(ref method, ref route), hist) in &registry.latency {
+                let labels = format!("method=\"{}\",route=\"{}\"", method, escape(route));
+                let mut cumulative = 0;
+                for (ind, count) in hist.buckets.iter().enumerate() {
+                    cumulative += count;
+                    let bound = LATENCY_BUCKETS.get(ind).map(|b| b.to_string()).unwrap_or_else(|| "+Inf".into());
+                    writeln!(out, "org_demo_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                             labels, bound, cumulative).unwrap();
+                }
+                writeln!(out, "org_demo_http_request_duration_seconds_sum{{{}}} {}", labels, hist.sum).unwrap();
+                writeln!(out, "org_demo_http_request_duration_seconds_count{{{}}} {}", labels, hist.count).unwrap();
+            }
+        }
+
+        gauge(&mut out, "org_demo_db_pool_max_size", "Max connections in the pool", pool.max_size);
+        gauge(&mut out, "org_demo_db_pool_idle_connections", "Idle pooled connections", pool.idle_connections);
+        gauge(&mut out, "org_demo_db_pool_in_use_connections", "Pooled connections currently checked out",
+              pool.connections.saturating_sub(pool.idle_connections));
+        header(&mut out, "org_demo_db_pool_checkouts_total", "counter", "Connections handed out by the pool");
+        writeln!(out, "org_demo_db_pool_checkouts_total {}", pool.checkouts).unwrap();
+        header(&mut out, "org_demo_db_pool_wait_seconds_total", "counter", "Time spent waiting on the pool for a connection");
+        writeln!(out, "org_demo_db_pool_wait_seconds_total {}", pool.wait_seconds).unwrap();
+
+        if let Some(counts) = counts {
+            gauge(&mut out, "org_demo_orgs", "Total orgs", counts.orgs);
+            gauge(&mut out, "org_demo_users", "Total users", counts.users);
+            gauge(&mut out, "org_demo_linodes", "Total linodes", counts.linodes);
+        }
+        out
+    }
+}
+
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+
+fn gauge<T: ::std::fmt::Display>(out: &mut String, name: &str, help: &str, value: T) {
+    header(out, name, "gauge", help);
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+
+fn status_class(status: u16) -> &'static str {
+    match status / 100 {
+        1 => "1xx",
+        2 => "2xx",
+        3 => "3xx",
+        4 => "4xx",
+        _ => "5xx",
+    }
+}
+
+
+/// Api routes as labeled by `route_label`
+static API_ROUTES: &'static [&'static str] = &[
+    "/api/orgs", "/api/users", "/api/linodes",
+    "/api/org/{id}", "/api/user/{id}", "/api/linode/{id}",
+    "/api/authenticate", "/api/batch", "/api/events",
+    "/api/create/org", "/api/create/user", "/api/create/linode", "/api/create/member",
+    "/api/update/org/{id}", "/api/update/user/{id}", "/api/update/linode/{id}",
+    "/api/deactivate/user/{id}", "/api/reactivate/user/{id}",
+    "/api/delete/org/{id}", "/api/delete/user/{id}", "/api/delete/linode/{id}", "/api/delete/member",
+    "/api/admin/webhooks", "/api/admin/create/webhook", "/api/admin/delete/webhook/{id}",
+    "/api/admin/deliveries", "/api/admin/replay/delivery/{id}", "/api/admin/replay/deliveries",
+];
+
+/// Label shared by every unmatched api path, so clients can't add labels
+static UNKNOWN_API_ROUTE: &'static str = "/api/{unknown}";
+
+
+/// Collapse a request url to the route that handles it, e.g.
+/// `/api/user/3` -> `/api/user/{id}` and `/static/js/app.js` -> `/static/*`.
+/// Paths that don't match a route share a single label.
+pub fn route_label(url: &str) -> String {
+    let path = url.split('?').next().unwrap_or("");
+    if path.starts_with("/static/") { return "/static/*".into() }
+    if path.starts_with("/api/exists/") {
+        let kind = path.trim_start_matches("/api/exists/").split('/').next().unwrap_or("");
+        return match kind {
+            "org" | "user" | "linode" => format!("/api/exists/{}/{{name}}", kind),
+            _ => UNKNOWN_API_ROUTE.into(),
+        }
+    }
+    if !path.starts_with("/api/") {
+        return match path {
+            "/" | "/healthz" | "/readyz" | "/version" | "/metrics" | "/graphql" | "/favicon.ico" | "/robots.txt" => path.into(),
+            _ => "/{frontend}".into(),
+        }
+    }
+    let route = path.split('/')
+        .map(|segment| if !segment.is_empty() && segment.parse::<i64>().is_ok() { "{id}" } else { segment })
+        .collect::<Vec<_>>()
+        .join("/");
+    if API_ROUTES.contains(&route.as_str()) { route } else { UNKNOWN_API_ROUTE.into() }
+}
@@ -1,215 +1,997 @@
-use std::env;
 use std::time;
-use std::fs;
+use std::net::SocketAddr;
 use std::sync;
-use std::path::Path;
+use std::thread;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use rouille;
-use env_logger;
-use chrono::Local;
-use r2d2_sqlite::SqliteConnectionManager;
-use r2d2::Pool;
+use juniper;
+use chrono::{Utc, TimeZone};
 
-use {ToTextResponse, ToJsonResponse, FromRequestBody, migrant_config};
-use models;
+use {ToTextResponse, ToJsonResponse, ToCsvResponse, ToNdjsonResponse, FromRequestBody};
+use config::Config;
+use backup;
+use store::{self, Store, Repo};
+use metrics::Metrics;
+use limits::{self, RateLimiter};
+use cors;
+use idempotency::{self, IdempotencyStore};
+use logging;
+use signals::Signals;
+use tls;
+use api;
+use batch;
+use etag;
+use formats::{self, Format};
+use events::{self, Notifier};
+use webhooks;
+use graphql;
+use assets;
 use errors::*;
 
 
 // convenience wrapper types
-pub type DbPool = Pool<SqliteConnectionManager>;
 pub type State = sync::Arc<Resources>;
+pub type Handler = sync::Arc<Fn(&rouille::Request) -> rouille::Response + Send + Sync>;
 
 
-/// Resource bag with database access
+/// How often the server checks for new connections and signals
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(10);
+
+/// How often a listener that lost its socket tries to bind it again
+const REBIND_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+
+/// Resource bag with database access, runtime configuration, request metrics and rate limits
 pub struct Resources {
-    pub db: DbPool,
+    pub store: Store,
+    /// Updated in place when the configuration is reloaded
+    pub config: sync::RwLock<Config>,
+    pub metrics: Metrics,
+    pub limiter: RateLimiter,
+    pub idempotency: IdempotencyStore,
+    /// Wakes `/api/events` streams when changes are committed
+    pub events: Notifier,
+    /// Requests and event streams being served, waited for by `drain` on shutdown
+    pub in_flight: AtomicUsize,
 }
 impl Resources {
-    pub fn new(db: DbPool) -> Self {
+    pub fn new(store: Store, config: Config) -> Self {
         Self {
-            db: db,
+            store: store,
+            config: sync::RwLock::new(config),
+            metrics: Metrics::new(),
+            limiter: RateLimiter::new(),
+            idempotency: IdempotencyStore::new(),
+            events: Notifier::new(),
+            in_flight: AtomicUsize::new(0),
         }
     }
+
+    pub fn config(&self) -> sync::RwLockReadGuard<Config> {
+        self.config.read().expect("config lock poisoned")
+    }
 }
 
 
-fn establish_connection_pool<T: AsRef<Path>>(database_path: T) -> DbPool {
-    let manager = SqliteConnectionManager::file(database_path.as_ref());
-    Pool::new(manager).expect("Failed to create pool.")
+/// Build the shared server `State` for the given configuration
+pub fn build_state(config: Config) -> Result<State> {
+    let store = Store::from_config(&config)?;
+    Ok(sync::Arc::new(Resources::new(store, config)))
 }
 
 
-/// Initialize things
-/// - env logger
-/// - database connection pool
-/// - server
-/// - handle errors
-pub fn start(host: &str, port: u16) -> Result<()> {
-    // Set a custom logging format & change the env-var to "LOG"
-    // e.g. LOG=info org_demo serve
-    use std::io::Write;
-    env_logger::Builder::new()
-        .format(|buf, record| {
-            writeln!(buf, "{} [{}] - [{}] -> {}",
-                Local::now().format("%Y-%m-%d_%H:%M:%S"),
-                record.level(),
-                record.module_path().unwrap_or("<unknown>"),
-                record.args()
-                )
-            })
-        .parse(&env::var("LOG").unwrap_or_default())
-        .init();
-
-    let db_config = migrant_config()?;
-    let pool = establish_connection_pool(&db_config.database_path()?);
-    let state = sync::Arc::new(Resources::new(pool));
+/// Build the request handler: dispatch requests to the appropriate
+/// route, convert any errors into responses and add cors headers
+pub fn build_router(state: State) -> impl Fn(&rouille::Request) -> rouille::Response + Send + Sync + 'static {
+    move |request| {
+        let response = match route_request(request, state.clone()) {
+            Ok(resp) => rouille::content_encoding::apply(request, resp),
+            Err(e) => {
+                use self::ErrorKind::*;
+                error!("Handler Error: {}", e);
+                match *e {
+                    BadRequest(ref s) => {
+                        s.to_string().to_text_resp().with_status_code(400)
+                    }
+                    DoesNotExist(ref s) => {
+                        s.to_string().to_text_resp().with_status_code(404)
+                    }
+                    Conflict(ref s) => {
+                        s.to_string().to_text_resp().with_status_code(409)
+                    }
+                    Unprocessable(ref s) => {
+                        s.to_string().to_text_resp().with_status_code(422)
+                    }
+                    Forbidden(ref s) => {
+                        s.to_string().to_text_resp().with_status_code(403)
+                    }
+                    PreconditionFailed(ref s) => {
+                        s.to_string().to_text_resp().with_status_code(412)
+                    }
+                    PayloadTooLarge(limit) => {
+                        format!("Request body exceeds {} bytes", limit).to_text_resp().with_status_code(413)
+                    }
+                    RateLimited(retry_after) => {
+                        "Too many requests".to_string().to_text_resp()
+                            .with_status_code(429)
+                            .with_unique_header("Retry-After", retry_after.to_string())
+                    }
+                    _ => {
+                        let msg = match logging::request_id() {
+                            Some(id) => format!("Something went wrong, request id: {}", id),
+                            None => "Something went wrong".to_string(),
+                        };
+                        msg.to_text_resp().with_status_code(500)
+                    }
+                }
+            }
+        };
+        cors::apply(&state.config(), request, response)
+    }
+}
 
-    let addr = format!("{}:{}", host, port);
-    info!("** Listening on {} **", addr);
 
-    rouille::start_server(&addr, move |request| {
-        let state = state.clone();
+/// Wrap the router with request ids, access logging and metrics
+pub fn build_handler(state: State) -> impl Fn(&rouille::Request) -> rouille::Response + Send + Sync + 'static {
+    let router = build_router(state.clone());
+    move |request| {
+        // propagate the caller's request id, or generate one
+        let request_id = request.header("X-Request-Id")
+            .and_then(|id| if logging::is_valid_request_id(id) { Some(id.to_string()) } else { None })
+            .unwrap_or_else(logging::new_request_id);
 
-        let now = Local::now().format("%Y-%m-%d %H:%M%S");
         let log_ok = |req: &rouille::Request, resp: &rouille::Response, elap: time::Duration| {
             let ms = (elap.as_secs() * 1_000) as f32 + (elap.subsec_nanos() as f32 / 1_000_000.);
-            info!("[{}] {} {} -> {} ({}ms)", now, req.method(), req.raw_url(), resp.status_code, ms)
+            logging::access(req.method(), req.raw_url(), resp.status_code, ms);
+            state.metrics.observe(req.method(), &req.url(), resp.status_code, elap);
         };
         let log_err = |req: &rouille::Request, elap: time::Duration| {
             let ms = (elap.as_secs() * 1_000) as f32 + (elap.subsec_nanos() as f32 / 1_000_000.);
-            info!("[{}] Handler Panicked: {} {} ({}ms)", now, req.method(), req.raw_url(), ms)
+            error!("Handler Panicked: {} {}", req.method(), req.raw_url());
+            logging::access(req.method(), req.raw_url(), 500, ms);
+            state.metrics.observe(req.method(), &req.url(), 500, elap);
         };
 
-        // dispatch and handle errors
-        rouille::log_custom(request, log_ok, log_err, move || {
-            match route_request(request, state) {
-                Ok(resp) => rouille::content_encoding::apply(request, resp),
-                Err(e) => {
-                    use self::ErrorKind::*;
-                    error!("Handler Error: {}", e);
-                    match *e {
-                        BadRequest(ref s) => {
-                            s.to_string().to_text_resp().with_status_code(400)
-                        }
-                        DoesNotExist(ref s) => {
-                            s.to_string().to_text_resp().with_status_code(404)
-                        }
-                        _ => rouille::Response::text("Something went wrong").with_status_code(500),
-                    }
-                }
+        logging::with_request_id(&request_id, || {
+            rouille::log_custom(request, log_ok, log_err, || {
+                router(request).with_unique_header("X-Request-Id", request_id.clone())
+            })
+        })
+    }
+}
+
+
+/// Counts a request as in-flight until dropped
+pub struct InFlight<'a>(&'a AtomicUsize);
+impl<'a> InFlight<'a> {
+    pub fn enter(count: &'a AtomicUsize) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        InFlight(count)
+    }
+}
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+/// Apply a freshly loaded configuration to the running server.
+/// Only `log`, `static_root`, the request limits and cors settings can change without a restart.
+fn reload_config<R>(state: &State, log_handle: &logging::LogHandle, reload: &R)
+    where R: Fn() -> Result<Config>
+{
+    let new = match reload() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Unable to reload configuration, keeping current settings: {}", e);
+            return
+        }
+    };
+    let mut config = state.config.write().expect("config lock poisoned");
+    let mut restart_only = new.clone();
+    restart_only.log = config.log.clone();
+    restart_only.static_root = config.static_root.clone();
+    restart_only.max_body_size = config.max_body_size;
+    restart_only.rate_limit = config.rate_limit;
+    restart_only.rate_limit_burst = config.rate_limit_burst;
+    restart_only.mutation_rate_limit = config.mutation_rate_limit;
+    restart_only.mutation_rate_limit_burst = config.mutation_rate_limit_burst;
+    restart_only.cors_origins = config.cors_origins.clone();
+    restart_only.cors_methods = config.cors_methods.clone();
+    restart_only.cors_headers = config.cors_headers.clone();
+    restart_only.cors_credentials = config.cors_credentials;
+    restart_only.cors_max_age = config.cors_max_age;
+    restart_only.source = config.source.clone();
+    if restart_only != *config {
+        warn!("Some changed settings only take effect after a restart");
+    }
+    log_handle.set_filter(&new.log);
+    config.log = new.log;
+    config.static_root = new.static_root;
+    config.max_body_size = new.max_body_size;
+    config.rate_limit = new.rate_limit;
+    config.rate_limit_burst = new.rate_limit_burst;
+    config.mutation_rate_limit = new.mutation_rate_limit;
+    config.mutation_rate_limit_burst = new.mutation_rate_limit_burst;
+    config.cors_origins = new.cors_origins;
+    config.cors_methods = new.cors_methods;
+    config.cors_headers = new.cors_headers;
+    config.cors_credentials = new.cors_credentials;
+    config.cors_max_age = new.cors_max_age;
+    info!("** Configuration reloaded **");
+}
+
+
+/// Start listening on `addr`, serving https if an `identity` is given
+fn listen(addr: &str, handler: &Handler, identity: Option<&tls::Identity>) -> Result<Box<Serve>> {
+    let handler = handler.clone();
+    let handler = move |request: &rouille::Request| handler(request);
+    let server = match identity {
+        #[cfg(feature = "tls")]
+        Some(identity) => {
+            rouille::Server::new_ssl(addr, handler, identity.certificate.clone(), identity.private_key.clone())
+        }
+        #[cfg(not(feature = "tls"))]
+        Some(_) => bail!("Serving https requires building with the `tls` feature"),
+        None => rouille::Server::new(addr, handler),
+    };
+    let server = server.map_err(|e| format!("Unable to listen on {}: {}", addr, e))?;
+    Ok(Box::new(server))
+}
+
+
+/// A listening `rouille::Server`, whatever its handler's type
+trait Serve {
+    fn serve_pending(&self);
+    fn local_addr(&self) -> SocketAddr;
+}
+impl<F> Serve for rouille::Server<F>
+    where F: Fn(&rouille::Request) -> rouille::Response + Send + Sync + 'static
+{
+    fn serve_pending(&self) {
+        self.poll()
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.server_addr()
+    }
+}
+
+
+/// The server's listening socket, which is re-bound to swap tls certificates
+pub struct Listener {
+    addr: SocketAddr,
+    handler: Handler,
+    identity: Option<tls::Identity>,
+    /// `None` while the socket is being re-bound
+    server: Option<Box<Serve>>,
+    next_rebind: time::Instant,
+}
+impl Listener {
+    /// Listen on `addr`, serving https if an `identity` is given
+    pub fn bind(addr: &str, handler: Handler, identity: Option<tls::Identity>) -> Result<Self> {
+        let server = listen(addr, &handler, identity.as_ref())?;
+        Ok(Self {
+            // a port of `0` is resolved, so re-binds keep the same port
+            addr: server.local_addr(),
+            handler: handler,
+            identity: identity,
+            server: Some(server),
+            next_rebind: time::Instant::now(),
+        })
+    }
+
+    pub fn server_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn is_https(&self) -> bool {
+        self.identity.is_some()
+    }
+
+    /// Serve with a renewed certificate. Certificates can't be swapped on a
+    /// running listener and two listeners can't share a port, so the renewed
+    /// certificate is first checked on a listener bound to an unused port.
+    /// If that fails, the current listener is left untouched. Otherwise the
+    /// socket is re-bound, requests already accepted are unaffected.
+    pub fn reload(&mut self, renewed: Option<tls::Identity>) -> Result<()> {
+        let mut probe = self.addr;
+        probe.set_port(0);
+        listen(&probe.to_string(), &self.handler, renewed.as_ref())?;
+
+        self.server = None;
+        self.identity = renewed;
+        // the previous socket closes once its accept thread wakes up, usually
+        // within a few polls, so keep trying before leaving it to `poll`
+        let deadline = time::Instant::now() + REBIND_INTERVAL;
+        while time::Instant::now() < deadline {
+            if let Ok(server) = listen(&self.addr.to_string(), &self.handler, self.identity.as_ref()) {
+                self.server = Some(server);
+                return Ok(())
             }
+            thread::sleep(POLL_INTERVAL);
+        }
+        self.rebind();
+        Ok(())
+    }
+
+    fn rebind(&mut self) {
+        self.next_rebind = time::Instant::now() + REBIND_INTERVAL;
+        match listen(&self.addr.to_string(), &self.handler, self.identity.as_ref()) {
+            Ok(server) => self.server = Some(server),
+            // the previous socket can take a moment to close
+            Err(e) => warn!("Unable to re-bind {}, retrying: {}", self.addr, e),
+        }
+    }
+
+    /// Handle pending connections, re-binding first if the socket was lost
+    pub fn poll(&mut self) {
+        if self.server.is_none() && time::Instant::now() >= self.next_rebind {
+            self.rebind();
+        }
+        if let Some(ref server) = self.server {
+            server.serve_pending();
+        }
+    }
+}
+
+
+/// Wait for in-flight requests and event streams to finish, up to `timeout`
+fn drain(in_flight: &AtomicUsize, timeout: time::Duration) {
+    let deadline = time::Instant::now() + timeout;
+    let mut remaining = in_flight.load(Ordering::SeqCst);
+    if remaining > 0 {
+        info!("Waiting up to {}s for {} in-flight requests", timeout.as_secs(), remaining);
+    }
+    while remaining > 0 && time::Instant::now() < deadline {
+        thread::sleep(time::Duration::from_millis(50));
+        remaining = in_flight.load(Ordering::SeqCst);
+    }
+    if remaining > 0 {
+        warn!("Shutdown timed out with {} requests still in flight", remaining);
+    }
+}
+
+
+/// Initialize things
+/// - logger
+/// - signal handlers
+/// - storage backend
+/// - server
+/// - handle errors
+///
+/// Runs until `SIGTERM` or `SIGINT`, then stops accepting connections, ends event
+/// streams and waits up to `shutdown_timeout` seconds for in-flight requests before returning.
+/// On `SIGHUP` the configuration is re-read with `reload`.
+pub fn start<R>(config: Config, reload: R) -> Result<()>
+    where R: Fn() -> Result<Config>
+{
+    let log_handle = logging::init(&config)?;
+    let signals = Signals::register()?;
+
+    let addr = format!("{}:{}", config.host, config.port);
+    let identity = tls::Identity::load(&config)?;
+    let redirect = match config.tls_redirect_port {
+        Some(port) if identity.is_some() => {
+            let redirect_addr = format!("{}:{}", config.host, port);
+            let server = rouille::Server::new(&redirect_addr, tls::redirect_handler(config.port))
+                .map_err(|e| format!("Unable to listen on {}: {}", redirect_addr, e))?;
+            info!("** Redirecting http on {} to https **", redirect_addr);
+            Some(server)
+        }
+        Some(_) => bail!("`tls_redirect_port` requires `tls_cert` and `tls_key`"),
+        None => None,
+    };
+    let shutdown_timeout = time::Duration::from_secs(config.shutdown_timeout);
+    if config.backup_interval > 0 {
+        backup::spawn_scheduled(config.clone())?;
+    }
+    let state = build_state(config)?;
+    webhooks::spawn_worker(state.clone())?;
+
+    let handler: Handler = {
+        let handler = build_handler(state.clone());
+        let state = state.clone();
+        sync::Arc::new(move |request: &rouille::Request| {
+            let _in_flight = InFlight::enter(&state.in_flight);
+            handler(request)
         })
-    });
+    };
+    let mut listener = Listener::bind(&addr, handler, identity)?;
+
+    info!("** Listening on {}://{} **", if listener.is_https() { "https" } else { "http" }, addr);
+
+    while !signals.shutdown_requested() {
+        if signals.take_reload() {
+            reload_config(&state, &log_handle, &reload);
+            if listener.is_https() {
+                let reloaded = tls::Identity::load(&state.config())
+                    .and_then(|renewed| listener.reload(renewed));
+                match reloaded {
+                    Ok(()) => info!("** Reloaded tls certificate **"),
+                    Err(e) => error!("Unable to reload tls certificate, keeping the current one: {}", e),
+                }
+            }
+        }
+        listener.poll();
+        if let Some(ref redirect) = redirect {
+            redirect.poll();
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    info!("** Shutting down **");
+    // stop accepting connections
+    drop(redirect);
+    drop(listener);
+    state.events.close();
+    drain(&state.in_flight, shutdown_timeout);
+    drop(state);
+    info!("** Shutdown complete **");
+    Ok(())
+}
+
+
+/// Time the binary was built, rfc3339
+fn build_time() -> String {
+    let secs = env!("ORG_DEMO_BUILD_TIMESTAMP").parse::<i64>().unwrap_or(0);
+    Utc.timestamp_opt(secs, 0).unwrap().to_rfc3339()
+}
+
+
+/// Check that the store can hand out a connection and the database
+/// has all migrations applied, returning the reason if not
+fn check_ready(state: &State) -> ::std::result::Result<(), String> {
+    let applied = state.store.with(|repo| repo.applied_migrations())
+        .map_err(|e| format!("database unavailable: {}", e))?;
+    if !store::is_migrated(&applied) {
+        return Err(format!("database has migrations [{}] applied, see `database migrate`", applied.join(", ")))
+    }
+    Ok(())
+}
+
+
+/// Serve a frontend file, `path` being relative to the static root
+fn serve_static(request: &rouille::Request, state: &State, path: &str) -> Result<rouille::Response> {
+    match assets::serve(request, &state.config().static_root, path)? {
+        Some(resp) => Ok(resp),
+        None => bail_fmt!(ErrorKind::DoesNotExist, "File not found: {}", path),
+    }
+}
+
+
+/// Check if this is a request for a page of the frontend app that should
+/// be handled by the client-side router, i.e. a `GET` outside of `/api/` and
+/// `/static/` for something that doesn't look like a file
+fn is_frontend_route(request: &rouille::Request) -> bool {
+    let url = request.url();
+    let is_file = url.rsplit('/').next().map(|name| name.contains('.')).unwrap_or(false);
+    request.method() == "GET" && !is_file
+        && !url.starts_with("/api/") && !url.starts_with("/static/")
+}
+
+
+/// Take a token from the client's api bucket, and its mutation
+/// bucket for anything that isn't a `GET`. GraphQL `POST`s count
+/// as mutations since they may contain one.
+fn check_rate_limit(request: &rouille::Request, state: &State) -> Result<()> {
+    let url = request.url();
+    if !url.starts_with("/api/") && url != "/graphql" { return Ok(()) }
+    let (api_rate, mutation_rate) = {
+        let config = state.config();
+        (config.api_rate(), config.mutation_rate())
+    };
+    let client = limits::client_key(request);
+    let mut limited = state.limiter.check(limits::Class::Read, &client, &api_rate).err();
+    if limited.is_none() && request.method() != "GET" {
+        limited = state.limiter.check(limits::Class::Mutation, &client, &mutation_rate).err();
+    }
+    if let Some(retry_after) = limited {
+        warn!("Rate limited {} {} from {}", request.method(), request.raw_url(), request.remote_addr());
+        bail!(ErrorKind::RateLimited(retry_after));
+    }
+    Ok(())
+}
+
+
+/// Read a post body, limited to the configured `max_body_size`
+fn read_body(request: &rouille::Request, state: &State) -> Result<String> {
+    let max_size = state.config().max_body_size;
+    request.read_body(max_size).map_err(|e| {
+        let too_large = match *e.kind() { ErrorKind::PayloadTooLarge(_) => true, _ => false };
+        if too_large { e } else { ErrorKind::BadRequest("Invalid post data".to_string()).into() }
+    })
+}
+
+
+fn parse_post<T: ::serde::de::DeserializeOwned>(body: &str) -> Result<T> {
+    ::serde_json::from_str(body).map_err(|_| ErrorKind::BadRequest("Invalid post data".to_string()).into())
+}
+
+
+/// Parse a json post body, limited to the configured `max_body_size`
+fn read_post<T: ::serde::de::DeserializeOwned>(request: &rouille::Request, state: &State) -> Result<T> {
+    parse_post(&read_body(request, state)?)
+}
+
+
+/// Run a create request, honoring any `Idempotency-Key` header.
+///
+/// `create` is given the post body and returns the json response. Successful
+/// responses are replayed for retries with the same key and body, a different
+/// body under a used key is rejected with a 422.
+fn idempotent<F>(request: &rouille::Request, state: &State, create: F) -> Result<rouille::Response>
+    where F: FnOnce(&str) -> Result<::serde_json::Value>
+{
+    let body = read_body(request, state)?;
+    let key = match request.header("Idempotency-Key") {
+        Some(key) => key,
+        None => return create(&body)?.to_json_resp(),
+    };
+    if key.is_empty() || key.len() > idempotency::MAX_KEY_LEN {
+        bail_fmt!(ErrorKind::BadRequest, "Idempotency-Key must be 1 to {} characters", idempotency::MAX_KEY_LEN);
+    }
+    // keys are scoped to the client so they can't collide across clients
+    let key = format!("{} {}", limits::client_key(request), key);
+    let fingerprint = idempotency::fingerprint(request.method(), &request.url(), &body);
+    let window = time::Duration::from_secs(state.config().idempotency_window);
+    let pending = match state.idempotency.begin(&key, fingerprint, window) {
+        idempotency::Begin::New(pending) => pending,
+        idempotency::Begin::Replay(status, value) => {
+            return Ok(value.to_json_resp()?
+                .with_status_code(status)
+                .with_unique_header("Idempotent-Replayed", "true"))
+        }
+        idempotency::Begin::Conflict => {
+            bail_fmt!(ErrorKind::Unprocessable, "Idempotency-Key was already used for a different request")
+        }
+        idempotency::Begin::InProgress => {
+            bail_fmt!(ErrorKind::Conflict, "A request with this Idempotency-Key is still in progress")
+        }
+    };
+    let value = create(&body)?;
+    let response = value.to_json_resp()?;
+    pending.complete(response.status_code, value);
+    Ok(response)
 }
 
 
-fn serve_file<T: AsRef<Path>>(path: T) -> Result<rouille::Response> {
-    let path = path.as_ref();
-    let ext = path.extension().and_then(::std::ffi::OsStr::to_str).unwrap_or("");
-    let f = fs::File::open(&path).map_err(ErrorKind::FileOpen)?;
-    Ok(rouille::Response::from_file(rouille::extension_to_mime(ext), f))
+/// Serialize `value` with its ETag, or an empty `304` if the client's copy is current
+fn json_with_etag<T: ::serde::Serialize>(request: &rouille::Request, value: &T) -> Result<rouille::Response> {
+    let tag = etag::of(value)?;
+    if etag::not_modified(request, &tag) {
+        return Ok(etag::not_modified_response(tag))
+    }
+    Ok(json!(value).to_json_resp()?.with_unique_header("ETag", tag))
+}
+
+
+/// Respond with a list in the format the client negotiated (see `formats`).
+/// Json lists are loaded with `load` and served with an ETag, csv and ndjson
+/// lists are streamed from `visit` a page at a time.
+fn list<T, W, F>(request: &rouille::Request, state: &State, visit: formats::Visit<T>, load: F) -> Result<rouille::Response>
+    where T: formats::CsvRecord + ::serde::Serialize + Send + 'static,
+          W: ::serde::Serialize,
+          F: FnOnce(&Repo) -> Result<W>,
+{
+    let response = match formats::negotiate(request)? {
+        Format::Json => json_with_etag(request, &state.store.with(load)?)?,
+        Format::Csv => formats::Rows::new(state.clone(), visit).to_csv_resp()?,
+        Format::Ndjson => formats::Rows::new(state.clone(), visit).to_ndjson_resp()?,
+    };
+    Ok(response.with_unique_header("Vary", "Accept"))
+}
+
+
+/// Run a mutation in a transaction, waking event streams once it's committed
+pub fn mutate<T, F>(state: &State, f: F) -> Result<T>
+    where F: FnOnce(&Repo) -> Result<T>
+{
+    let out = state.store.transaction(f)?;
+    state.events.notify();
+    Ok(out)
+}
+
+
+/// Serialize a GraphQL result, with a `400` if the query couldn't be run at all
+fn graphql_response(response: &juniper::http::GraphQLResponse) -> Result<rouille::Response> {
+    let status = if response.is_ok() { 200 } else { 400 };
+    Ok(::serde_json::to_value(response)?.to_json_resp()?.with_status_code(status))
+}
+
+
+/// Id of the last event the client has seen, from the `Last-Event-ID` header
+/// sent when an `EventSource` reconnects, or a `last_event_id` query parameter
+/// for the initial connection. `None` if the client wants new events only.
+fn last_event_id(request: &rouille::Request) -> Result<Option<i64>> {
+    let id = request.header("Last-Event-ID").map(String::from)
+        .or_else(|| request.get_param("last_event_id"));
+    match id {
+        Some(id) => Ok(Some(id.trim().parse()
+            .map_err(|_| format_err!(ErrorKind::BadRequest, "Invalid Last-Event-ID `{}`", id))?)),
+        None => Ok(None),
+    }
+}
+
+
+// Single entities as served by their `GET` routes. Their ETags are
+// what `If-Match` headers on updates and deletes are checked against.
+
+fn find_org(repo: &Repo, id: i64) -> Result<Option<api::Org>> {
+    Ok(repo.get_org(id)?.map(|org| api::Org { org: org }))
+}
+
+
+fn find_user(repo: &Repo, id: i64) -> Result<Option<api::User>> {
+    Ok(repo.get_user(id)?.map(|user| api::User { user: user }))
+}
+
+
+fn find_linode(repo: &Repo, id: i64) -> Result<Option<api::Linode>> {
+    Ok(repo.get_linode(id)?.map(|linode| api::Linode { linode: linode }))
+}
+
+
+/// Require the configured `admin_token` as an `Authorization: Bearer <token>` header
+fn check_admin(request: &rouille::Request, state: &State) -> Result<()> {
+    let token = match state.config().admin_token {
+        Some(ref token) => token.clone(),
+        None => bail_fmt!(ErrorKind::Forbidden, "Admin routes are disabled, set `admin_token` to enable them"),
+    };
+    let sent = request.header("Authorization")
+        .and_then(|auth| if auth.starts_with("Bearer ") { Some(auth["Bearer ".len()..].trim()) } else { None });
+    match sent {
+        Some(sent) if constant_time_eq(sent.as_bytes(), token.as_bytes()) => Ok(()),
+        _ => bail_fmt!(ErrorKind::Forbidden, "Missing or invalid admin token"),
+    }
+}
+
+
+/// Compare secrets without returning early on the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() { return false }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
 
 /// Route the request to appropriate handler
 fn route_request(request: &rouille::Request, state: State) -> Result<rouille::Response> {
+    // cors preflights can be sent for any route
+    if request.method() == "OPTIONS" {
+        return Ok(cors::preflight(&state.config(), request))
+    }
+    check_rate_limit(request, &state)?;
+    if request.url().starts_with("/api/admin/") {
+        check_admin(request, &state)?;
+    }
     Ok(router!(request,
         (GET) ["/"] => {
-            serve_file("static/index.html")?
+            serve_static(request, &state, "index.html")?
+        },
+
+        // ---- Probes ----
+        (GET) ["/healthz"] => {
+            "ok".to_string().to_text_resp()
+        },
+        (GET) ["/readyz"] => {
+            match check_ready(&state) {
+                Ok(()) => "ready".to_string().to_text_resp(),
+                Err(reason) => {
+                    warn!("Not ready: {}", reason);
+                    format!("not ready: {}", reason).to_text_resp().with_status_code(503)
+                }
+            }
+        },
+        (GET) ["/version"] => {
+            let migrations = state.store.with(|repo| repo.applied_migrations())?;
+            json!(api::Version {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                git_sha: env!("ORG_DEMO_GIT_SHA").to_string(),
+                build_time: build_time(),
+                migrations: migrations,
+            }).to_json_resp()?
+        },
+
+        (GET) ["/metrics"] => {
+            let counts = state.store.with(|repo| repo.counts())
+                .map_err(|e| warn!("Unable to count rows for metrics: {}", e))
+                .ok();
+            let body = state.metrics.render(&state.store.pool_stats(), counts.as_ref());
+            rouille::Response::text(body)
+                .with_unique_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+        },
+
+        // ---- GraphQL ----
+        (GET) ["/graphql"] => {
+            let query = match request.get_param("query") {
+                Some(query) => query,
+                None => bail_fmt!(ErrorKind::BadRequest, "Missing `query` parameter"),
+            };
+            let variables = match request.get_param("variables") {
+                Some(variables) => Some(::serde_json::from_str(&variables)
+                    .map_err(|_| format_err!(ErrorKind::BadRequest, "Invalid `variables` parameter"))?),
+                None => None,
+            };
+            let query = juniper::http::GraphQLRequest::new(query, request.get_param("operationName"), variables);
+            let context = graphql::Context::new(state.clone());
+            graphql_response(&query.execute(&graphql::read_only_schema(), &context))?
+        },
+        (POST) ["/graphql"] => {
+            let query = read_post::<juniper::http::GraphQLRequest>(request, &state)?;
+            let context = graphql::Context::new(state.clone());
+            graphql_response(&query.execute(&graphql::schema(), &context))?
         },
 
         // ---- Grabbing data ----
         (GET) ["/api/orgs"] => {
-            let conn = state.db.get()?;
-            let orgs = models::OrgInfo::get_all_orgs(&conn)?;
-            json!({"orgs": orgs}).to_json_resp()?
+            list(request, &state,
+                 |repo, after, limit, f| repo.each_org(after, limit, f),
+                 |repo| Ok(api::Orgs { orgs: repo.get_all_orgs()? }))?
+        },
+        (GET) ["/api/org/{id}", id: i64] => {
+            match state.store.with(|repo| find_org(repo, id))? {
+                Some(org) => json_with_etag(request, &org)?,
+                None => bail_fmt!(ErrorKind::DoesNotExist, "No org found"),
+            }
         },
-        (GET) ["/api/user/{id}", id: u64] => {
-            let conn = state.db.get()?;
-            let user = models::UserInfo::get_user(id as i64, &conn)?;
-            if user.is_none() { bail_fmt!(ErrorKind::DoesNotExist, "No user found") }
-            json!({"user": user}).to_json_resp()?
+        (GET) ["/api/user/{id}", id: i64] => {
+            match state.store.with(|repo| find_user(repo, id))? {
+                Some(user) => json_with_etag(request, &user)?,
+                None => bail_fmt!(ErrorKind::DoesNotExist, "No user found"),
+            }
+        },
+        (GET) ["/api/users"] => {
+            list(request, &state,
+                 |repo, after, limit, f| repo.each_user(after, limit, f),
+                 |repo| Ok(api::Users { users: repo.get_all_users()? }))?
+        },
+        (GET) ["/api/linode/{id}", id: i64] => {
+            match state.store.with(|repo| find_linode(repo, id))? {
+                Some(linode) => json_with_etag(request, &linode)?,
+                None => bail_fmt!(ErrorKind::DoesNotExist, "No linode found"),
+            }
+        },
+        (GET) ["/api/linodes"] => {
+            list(request, &state,
+                 |repo, after, limit, f| repo.each_linode(after, limit, f),
+                 |repo| Ok(api::Linodes { linodes: repo.get_all_linodes()? }))?
+        },
+
+        (GET) ["/api/events"] => {
+            let last_id = match last_event_id(request)? {
+                Some(id) => id,
+                None => state.store.with(|repo| repo.last_event_id())?,
+            };
+            events::response(state.clone(), last_id)
+        },
+
+        // ---- Authenticating users ----
+        (POST) ["/api/authenticate"] => {
+            let post = read_post::<api::Authenticate>(request, &state)?;
+            let user = state.store.transaction(|repo| repo.authenticate(&post.email))?;
+            json!(api::User { user: user }).to_json_resp()?
         },
 
         // ---- Checking if things exist ----
         (GET) ["/api/exists/org/{name}", name: String] => {
-            let conn = state.db.get()?;
-            let exists = models::Org::exists(&conn, &name)?;
-            json!({"exists": exists}).to_json_resp()?
+            let exists = state.store.with(|repo| repo.org_exists(&name))?;
+            json!(api::Exists { exists: exists }).to_json_resp()?
         },
         (GET) ["/api/exists/user/{email}", email: String] => {
-            let conn = state.db.get()?;
-            let exists = models::User::exists(&conn, &email)?;
-            json!({"exists": exists}).to_json_resp()?
+            let exists = state.store.with(|repo| repo.user_exists(&email))?;
+            json!(api::Exists { exists: exists }).to_json_resp()?
         },
         (GET) ["/api/exists/linode/{name}", name: String] => {
-            let conn = state.db.get()?;
-            let exists = models::Linode::exists(&conn, &name)?;
-            json!({"exists": exists}).to_json_resp()?
+            let exists = state.store.with(|repo| repo.linode_exists(&name))?;
+            json!(api::Exists { exists: exists }).to_json_resp()?
         },
 
         // ---- Creating things ----
         (POST) ["/api/create/org"] => {
-            #[derive(Deserialize)]
-            struct Post {
-                name: String,
-            }
-            let post = request.parse_json_body::<Post>()
-                .map_err(|_| ErrorKind::BadRequest("Invalid post data".to_string()))?;
-            let conn = state.db.get()?;
-            if models::Org::exists(&conn, &post.name)? {
-                bail_fmt!(ErrorKind::BadRequest, "Org already exists, {}", post.name);
-            }
-            let org_id = models::NewOrg { name: post.name }.insert(&conn)?;
-            json!({"org_id": org_id}).to_json_resp()?
+            idempotent(request, &state, |body| {
+                let post = parse_post::<api::CreateOrg>(body)?;
+                let org_id = mutate(&state, |repo| {
+                    let org_id = repo.create_org(&post.name)?;
+                    events::record(repo, "org.created", json!({"id": org_id, "name": post.name}))?;
+                    Ok(org_id)
+                })?;
+                Ok(json!(api::OrgCreated { org_id: org_id }))
+            })?
         },
         (POST) ["/api/create/user"] => {
-            #[derive(Deserialize)]
-            struct Post {
-                org_ids: Vec<i64>,
-                email: String,
-            }
-            let post = request.parse_json_body::<Post>()
-                .map_err(|_| ErrorKind::BadRequest("Invalid post data".to_string()))?;
-            let mut conn = state.db.get()?;
-            let trans = conn.transaction()?;
-            if models::User::exists(&trans, &post.email)? {
-                bail_fmt!(ErrorKind::BadRequest, "User already exists, {}", post.email);
-            }
-            let user_id = models::NewUser { email: post.email }.insert(&trans)?;
-            for id in &post.org_ids {
-                models::NewUserOrg { org: *id, user: user_id }.insert(&trans)?;
-            }
-            trans.commit()?;
-            json!({"user_id": user_id}).to_json_resp()?
+            idempotent(request, &state, |body| {
+                let post = parse_post::<api::CreateUser>(body)?;
+                let user_id = mutate(&state, |repo| {
+                    let user_id = repo.create_user(&post.email, &post.org_ids)?;
+                    if post.display_name.is_some() {
+                        repo.update_user(user_id, None, post.display_name.as_ref().map(String::as_str))?;
+                    }
+                    events::record(repo, "user.created", json!({
+                        "id": user_id, "email": post.email, "display_name": post.display_name, "org_ids": post.org_ids
+                    }))?;
+                    Ok(user_id)
+                })?;
+                Ok(json!(api::UserCreated { user_id: user_id }))
+            })?
         },
         (POST) ["/api/create/linode"] => {
-            #[derive(Deserialize)]
-            struct Post {
-                org_id: i64,
-                name: String,
-            }
-            let post = request.parse_json_body::<Post>()
-                .map_err(|_| ErrorKind::BadRequest("Invalid post data".to_string()))?;
-            let conn = state.db.get()?;
-            if models::Linode::exists(&conn, &post.name)? {
-                bail_fmt!(ErrorKind::BadRequest, "Linode already exists, {}", post.name);
-            }
-            let linode_id = models::NewLinode { name: post.name, org: post.org_id }.insert(&conn)?;
-            json!({"linode_id": linode_id}).to_json_resp()?
+            idempotent(request, &state, |body| {
+                let post = parse_post::<api::CreateLinode>(body)?;
+                let linode_id = mutate(&state, |repo| {
+                    let linode_id = repo.create_linode(&post.name, post.org_id)?;
+                    events::record(repo, "linode.created",
+                                   json!({"id": linode_id, "name": post.name, "org_id": post.org_id}))?;
+                    Ok(linode_id)
+                })?;
+                Ok(json!(api::LinodeCreated { linode_id: linode_id }))
+            })?
+        },
+        (POST) ["/api/create/member"] => {
+            idempotent(request, &state, |body| {
+                let post = parse_post::<api::Member>(body)?;
+                mutate(&state, |repo| {
+                    repo.add_member(post.user_id, post.org_id)?;
+                    events::record(repo, "member.created", json!({"user_id": post.user_id, "org_id": post.org_id}))
+                })?;
+                Ok(json!(api::Success { success: true }))
+            })?
+        },
+
+        (POST) ["/api/batch"] => {
+            idempotent(request, &state, |body| {
+                let post = parse_post::<api::Batch>(body)?;
+                let results = mutate(&state, |repo| batch::apply(repo, &post.operations))?;
+                Ok(json!(api::BatchApplied { results: results }))
+            })?
+        },
+
+        // ---- Updating things ----
+        (POST) ["/api/update/org/{id}", id: i64] => {
+            let post = read_post::<api::UpdateOrg>(request, &state)?;
+            mutate(&state, |repo| {
+                etag::check_if_match(request, || find_org(repo, id))?;
+                repo.change_org_name(id, &post.name)?;
+                events::record(repo, "org.updated", json!({"id": id, "name": post.name}))
+            })?;
+            json!(api::Success { success: true }).to_json_resp()?
+        },
+        (POST) ["/api/update/user/{id}", id: i64] => {
+            let post = read_post::<api::UpdateUser>(request, &state)?;
+            mutate(&state, |repo| {
+                etag::check_if_match(request, || find_user(repo, id))?;
+                repo.update_user(id, post.email.as_ref().map(String::as_str), post.display_name.as_ref().map(String::as_str))?;
+                events::record(repo, "user.updated", json!({"id": id, "email": post.email, "display_name": post.display_name}))
+            })?;
+            json!(api::Success { success: true }).to_json_resp()?
+        },
+        (POST) ["/api/deactivate/user/{id}", id: i64] => {
+            mutate(&state, |repo| {
+                etag::check_if_match(request, || find_user(repo, id))?;
+                repo.set_user_active(id, false)?;
+                events::record(repo, "user.deactivated", json!({"id": id}))
+            })?;
+            json!(api::Success { success: true }).to_json_resp()?
+        },
+        (POST) ["/api/reactivate/user/{id}", id: i64] => {
+            mutate(&state, |repo| {
+                etag::check_if_match(request, || find_user(repo, id))?;
+                repo.set_user_active(id, true)?;
+                events::record(repo, "user.reactivated", json!({"id": id}))
+            })?;
+            json!(api::Success { success: true }).to_json_resp()?
+        },
+        (POST) ["/api/update/linode/{id}", id: i64] => {
+            let post = read_post::<api::UpdateLinode>(request, &state)?;
+            mutate(&state, |repo| {
+                etag::check_if_match(request, || find_linode(repo, id))?;
+                let previous_org_id = repo.get_linode(id)?.and_then(|linode| linode.org);
+                repo.update_linode(id, post.name.as_ref().map(String::as_str), post.org_id)?;
+                events::record(repo, "linode.updated", json!({
+                    "id": id, "name": post.name, "org_id": post.org_id, "previous_org_id": previous_org_id
+                }))
+            })?;
+            json!(api::Success { success: true }).to_json_resp()?
+        },
+
+        // ---- Deleting things ----
+        (POST) ["/api/delete/org/{id}", id: i64] => {
+            mutate(&state, |repo| {
+                etag::check_if_match(request, || find_org(repo, id))?;
+                // recorded first, while the org's webhooks still match it
+                events::record(repo, "org.deleted", json!({"id": id}))?;
+                repo.delete_org(id)
+            })?;
+            json!(api::Success { success: true }).to_json_resp()?
+        },
+        (POST) ["/api/delete/user/{id}", id: i64] => {
+            mutate(&state, |repo| {
+                etag::check_if_match(request, || find_user(repo, id))?;
+                repo.delete_user(id)?;
+                events::record(repo, "user.deleted", json!({"id": id}))
+            })?;
+            json!(api::Success { success: true }).to_json_resp()?
+        },
+        (POST) ["/api/delete/linode/{id}", id: i64] => {
+            mutate(&state, |repo| {
+                etag::check_if_match(request, || find_linode(repo, id))?;
+                let org_id = repo.get_linode(id)?.and_then(|linode| linode.org);
+                repo.delete_linode(id)?;
+                events::record(repo, "linode.deleted", json!({"id": id, "org_id": org_id}))
+            })?;
+            json!(api::Success { success: true }).to_json_resp()?
+        },
+        (POST) ["/api/delete/member"] => {
+            let post = read_post::<api::Member>(request, &state)?;
+            mutate(&state, |repo| {
+                repo.delete_member(post.user_id, post.org_id)?;
+                events::record(repo, "member.deleted", json!({"user_id": post.user_id, "org_id": post.org_id}))
+            })?;
+            json!(api::Success { success: true }).to_json_resp()?
+        },
+
+        // ---- Webhooks ----
+        (GET) ["/api/admin/webhooks"] => {
+            let webhooks = state.store.with(|repo| repo.get_webhooks())?;
+            json!(api::Webhooks { webhooks: webhooks }).to_json_resp()?
+        },
+        (POST) ["/api/admin/create/webhook"] => {
+            let post = read_post::<api::CreateWebhook>(request, &state)?;
+            webhooks::check_url(&post.url, state.config().webhook_allow_private_hosts)?;
+            let secret = match post.secret {
+                Some(ref secret) => secret.clone(),
+                None => webhooks::new_secret()?,
+            };
+            let webhook_id = state.store.transaction(|repo| {
+                if let Some(org_id) = post.org_id {
+                    if repo.get_org(org_id)?.is_none() {
+                        bail_fmt!(ErrorKind::DoesNotExist, "No org found with id {}", org_id);
+                    }
+                }
+                repo.insert_webhook(&post.url, &secret, post.org_id, &post.events.join(","))
+            })?;
+            json!(api::WebhookCreated { webhook_id: webhook_id, secret: secret }).to_json_resp()?
+        },
+        (POST) ["/api/admin/delete/webhook/{id}", id: i64] => {
+            state.store.transaction(|repo| repo.delete_webhook(id))?;
+            json!(api::Success { success: true }).to_json_resp()?
+        },
+        (GET) ["/api/admin/deliveries"] => {
+            let status = request.get_param("status");
+            let limit = match request.get_param("limit") {
+                Some(limit) => limit.parse::<i64>()
+                    .map_err(|_| format_err!(ErrorKind::BadRequest, "Invalid limit `{}`", limit))?,
+                None => 100,
+            };
+            let deliveries = state.store.with(|repo| repo.get_deliveries(status.as_ref().map(String::as_str), limit))?;
+            json!(api::Deliveries { deliveries: deliveries }).to_json_resp()?
+        },
+        (POST) ["/api/admin/replay/delivery/{id}", id: i64] => {
+            let now = Utc::now().timestamp();
+            state.store.transaction(|repo| repo.replay_delivery(id, now))?;
+            json!(api::Success { success: true }).to_json_resp()?
+        },
+        (POST) ["/api/admin/replay/deliveries"] => {
+            let now = Utc::now().timestamp();
+            let replayed = state.store.transaction(|repo| repo.replay_dead_deliveries(now))?;
+            json!(api::Replayed { replayed: replayed }).to_json_resp()?
         },
 
         // ---- misc ----
-        (GET) ["/favicon.ico"]  => { serve_file("static/favicon.ico")? },
-        (GET) ["/robots.txt"]   => { serve_file("static/robots.txt")? },
+        (GET) ["/favicon.ico"]  => { serve_static(request, &state, "favicon.ico")? },
+        (GET) ["/robots.txt"]   => { serve_static(request, &state, "robots.txt")? },
         _ => {
             // static files
             if let Some(req) = request.remove_prefix("/static") {
-                let static_resp = rouille::match_assets(&req, "static");
-                if static_resp.is_success() {
+                if let Some(static_resp) = assets::serve(request, &state.config().static_root, &req.url())? {
                     return Ok(static_resp)
                 }
             }
+            // client-side routes, e.g. `/org/3`, are handled by the frontend
+            if is_frontend_route(request) {
+                return serve_static(request, &state, "index.html")
+            }
             error!("{:?}", request);
             bail_fmt!(ErrorKind::DoesNotExist, "nothing here")
         }
@@ -0,0 +1,39 @@
+/*!
+Unix signal handling for the server
+
+- `SIGTERM`, `SIGINT`: stop accepting connections and shut down once in-flight requests finish
+- `SIGHUP`: reload configuration
+*/
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use signal_hook;
+
+use errors::*;
+
+
+/// Flags set by the registered signal handlers
+pub struct Signals {
+    shutdown: Arc<AtomicBool>,
+    reload: Arc<AtomicBool>,
+}
+impl Signals {
+    /// Install handlers for `SIGTERM`, `SIGINT` and `SIGHUP`
+    pub fn register() -> Result<Self> {
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let reload = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::SIGTERM, shutdown.clone())?;
+        signal_hook::flag::register(signal_hook::SIGINT, shutdown.clone())?;
+        signal_hook::flag::register(signal_hook::SIGHUP, reload.clone())?;
+        Ok(Self { shutdown: shutdown, reload: reload })
+    }
+
+    pub fn shutdown_requested(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Check if a reload was requested since the last call
+    pub fn take_reload(&self) -> bool {
+        self.reload.swap(false, Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,408 @@
+/*!
+Storage backends
+
+`Repo` covers every query and mutation against the database and is implemented
+for a single connection (or transaction) of each backend. `Backend`s hand out
+pooled connections as `Repo`s, and `Store` wraps a `Backend` with helpers for
+running a closure against a `Repo`, optionally inside a transaction.
+
+The backend is selected by the `backend` config setting:
+- `sqlite` (default)
+- `postgres`, requires the `pg` feature
+- `memory`, a throwaway sqlite database populated with the sample data
+*/
+use std::i64;
+use std::time::Instant;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use r2d2::{Pool, ManageConnection};
+
+use config::{self, Config};
+use models::{OrgInfo, UserInfo, User, LinodeInfo, Counts, Event, Webhook, Delivery};
+use MIGRATIONS;
+use errors::*;
+
+pub mod sqlite;
+#[cfg(feature = "pg")]
+pub mod pg;
+
+
+/// Queries and mutations supported by every storage backend.
+///
+/// The required methods map one-to-one to sql statements. The provided methods
+/// combine them into the validated operations exposed by the api and admin commands,
+/// and should be run inside a transaction (see `Store::transaction`).
+pub trait Repo {
+    // ---- Querying things ----
+    /// Pass up to `limit` orgs with ids greater than `after` to `f` as they're read,
+    /// ordered by id. Returns the id of the last one, `None` if there were none.
+    fn each_org(&self, after: i64, limit: i64, f: &mut FnMut(OrgInfo) -> Result<()>) -> Result<Option<i64>>;
+    /// Like `each_org`, for users with their profile
+    fn each_user(&self, after: i64, limit: i64, f: &mut FnMut(User) -> Result<()>) -> Result<Option<i64>>;
+    /// Like `each_org`, for linodes
+    fn each_linode(&self, after: i64, limit: i64, f: &mut FnMut(LinodeInfo) -> Result<()>) -> Result<Option<i64>>;
+    /// Orgs with the given ids, ordered by id. Ids that don't exist are skipped,
+    /// here and in the other lookups by id.
+    fn get_orgs(&self, ids: &[i64]) -> Result<Vec<OrgInfo>>;
+    /// Users with the given ids, with the orgs they're a member of and the linodes they can access
+    fn get_users(&self, ids: &[i64]) -> Result<Vec<UserInfo>>;
+    /// Look up a user with their profile by email
+    fn find_user_by_email(&self, email: &str) -> Result<Option<User>>;
+    fn get_linodes(&self, ids: &[i64]) -> Result<Vec<LinodeInfo>>;
+    /// Linodes owned by any of `orgs`, ordered by id
+    fn linodes_for_orgs(&self, orgs: &[i64]) -> Result<Vec<LinodeInfo>>;
+    fn counts(&self) -> Result<Counts>;
+
+    fn org_exists(&self, name: &str) -> Result<bool>;
+    fn user_exists(&self, email: &str) -> Result<bool>;
+    fn linode_exists(&self, name: &str) -> Result<bool>;
+    fn member_exists(&self, user: i64, org: i64) -> Result<bool>;
+
+    /// Tags of the migrations applied to the database, empty if migrations were never set up
+    fn applied_migrations(&self) -> Result<Vec<String>>;
+
+    // ---- Creating things ----
+    fn insert_org(&self, name: &str) -> Result<i64>;
+    fn insert_user(&self, email: &str) -> Result<i64>;
+    fn insert_member(&self, user: i64, org: i64) -> Result<i64>;
+    fn insert_linode(&self, name: &str, org: i64) -> Result<i64>;
+
+    // ---- Updating things ----
+    fn rename_org(&self, id: i64, name: &str) -> Result<()>;
+    fn update_user_email(&self, id: i64, email: &str) -> Result<()>;
+    fn update_user_display_name(&self, id: i64, display_name: Option<&str>) -> Result<()>;
+    fn set_user_active(&self, id: i64, active: bool) -> Result<()>;
+    /// Set the user's `last_seen` to now
+    fn touch_user(&self, id: i64) -> Result<()>;
+    fn rename_linode(&self, id: i64, name: &str) -> Result<()>;
+    fn set_linode_org(&self, id: i64, org: i64) -> Result<()>;
+
+    // ---- Deleting things ----
+    /// Delete an org along with its memberships and linodes,
+    /// detaching its webhooks so their pending deliveries are kept
+    fn delete_org(&self, id: i64) -> Result<()>;
+    /// Delete a user and their memberships
+    fn delete_user(&self, id: i64) -> Result<()>;
+    fn delete_linode(&self, id: i64) -> Result<()>;
+    fn delete_member(&self, user: i64, org: i64) -> Result<()>;
+
+    // ---- Change feed ----
+    /// Append an event recorded while handling request `request_id`, returning its id
+    fn insert_event(&self, kind: &str, data: &str, request_id: Option<&str>) -> Result<i64>;
+    /// Up to `limit` events with ids greater than `id`, oldest first
+    fn events_after(&self, id: i64, limit: i64) -> Result<Vec<Event>>;
+    /// Id of the latest event, `0` if there are none
+    fn last_event_id(&self) -> Result<i64>;
+    /// Delete events with ids up to and including `id`
+    fn delete_events_through(&self, id: i64) -> Result<()>;
+
+    // ---- Webhooks ----
+    /// `events` is a comma separated list of event kinds
+    fn insert_webhook(&self, url: &str, secret: &str, org: Option<i64>, events: &str) -> Result<i64>;
+    fn get_webhooks(&self) -> Result<Vec<Webhook>>;
+    /// Attached webhooks that are global or subscribe to one of `orgs`
+    fn webhooks_for_orgs(&self, orgs: &[i64]) -> Result<Vec<Webhook>>;
+    /// Delete a webhook along with its deliveries
+    fn delete_webhook(&self, id: i64) -> Result<()>;
+    fn insert_delivery(&self, webhook: i64, event: i64, kind: &str, payload: &str, next_attempt: i64) -> Result<i64>;
+    /// Latest `limit` deliveries, optionally only those with `status`
+    fn get_deliveries(&self, status: Option<&str>, limit: i64) -> Result<Vec<Delivery>>;
+    /// Pending deliveries due at `now`, oldest first
+    fn due_deliveries(&self, now: i64, limit: i64) -> Result<Vec<Delivery>>;
+    fn update_delivery(&self, id: i64, status: &str, attempts: i64, next_attempt: i64, last_error: Option<&str>) -> Result<()>;
+    /// Make a delivery pending again with a fresh set of attempts
+    fn replay_delivery(&self, id: i64, now: i64) -> Result<()>;
+    /// Make every dead delivery pending again, returning how many there were
+    fn replay_dead_deliveries(&self, now: i64) -> Result<u64>;
+
+    // ---- Whole tables ----
+    fn get_all_orgs(&self) -> Result<Vec<OrgInfo>> {
+        let mut orgs = vec![];
+        self.each_org(i64::MIN, i64::MAX, &mut |org| { orgs.push(org); Ok(()) })?;
+        Ok(orgs)
+    }
+
+    fn get_all_users(&self) -> Result<Vec<User>> {
+        let mut users = vec![];
+        self.each_user(i64::MIN, i64::MAX, &mut |user| { users.push(user); Ok(()) })?;
+        Ok(users)
+    }
+
+    fn get_all_linodes(&self) -> Result<Vec<LinodeInfo>> {
+        let mut linodes = vec![];
+        self.each_linode(i64::MIN, i64::MAX, &mut |linode| { linodes.push(linode); Ok(()) })?;
+        Ok(linodes)
+    }
+
+    // ---- Lookups by id ----
+    fn get_org(&self, id: i64) -> Result<Option<OrgInfo>> {
+        Ok(self.get_orgs(&[id])?.into_iter().next())
+    }
+
+    fn get_user(&self, id: i64) -> Result<Option<UserInfo>> {
+        Ok(self.get_users(&[id])?.into_iter().next())
+    }
+
+    fn get_linode(&self, id: i64) -> Result<Option<LinodeInfo>> {
+        Ok(self.get_linodes(&[id])?.into_iter().next())
+    }
+
+    // ---- Validated operations ----
+    fn create_org(&self, name: &str) -> Result<i64> {
+        if self.org_exists(name)? {
+            bail_fmt!(ErrorKind::BadRequest, "Org already exists, {}", name);
+        }
+        self.insert_org(name)
+    }
+
+    fn create_user(&self, email: &str, org_ids: &[i64]) -> Result<i64> {
+        if self.user_exists(email)? {
+            bail_fmt!(ErrorKind::BadRequest, "User already exists, {}", email);
+        }
+        let user_id = self.insert_user(email)?;
+        for org_id in org_ids {
+            self.insert_member(user_id, *org_id)?;
+        }
+        Ok(user_id)
+    }
+
+    fn create_linode(&self, name: &str, org_id: i64) -> Result<i64> {
+        if self.linode_exists(name)? {
+            bail_fmt!(ErrorKind::BadRequest, "Linode already exists, {}", name);
+        }
+        self.insert_linode(name, org_id)
+    }
+
+    fn add_member(&self, user_id: i64, org_id: i64) -> Result<()> {
+        if self.member_exists(user_id, org_id)? {
+            bail_fmt!(ErrorKind::BadRequest, "User {} is already a member of org {}", user_id, org_id);
+        }
+        self.insert_member(user_id, org_id)?;
+        Ok(())
+    }
+
+    fn change_org_name(&self, id: i64, name: &str) -> Result<()> {
+        if self.org_exists(name)? {
+            bail_fmt!(ErrorKind::BadRequest, "Org already exists, {}", name);
+        }
+        self.rename_org(id, name)
+    }
+
+    fn change_user_email(&self, id: i64, email: &str) -> Result<()> {
+        if self.user_exists(email)? {
+            bail_fmt!(ErrorKind::BadRequest, "User already exists, {}", email);
+        }
+        self.update_user_email(id, email)
+    }
+
+    /// Change a user's email and/or display name. An empty display name clears it.
+    fn update_user(&self, id: i64, email: Option<&str>, display_name: Option<&str>) -> Result<()> {
+        if let Some(email) = email {
+            self.change_user_email(id, email)?;
+        }
+        if let Some(display_name) = display_name {
+            let display_name = display_name.trim();
+            let display_name = if display_name.is_empty() { None } else { Some(display_name) };
+            self.update_user_display_name(id, display_name)?;
+        }
+        Ok(())
+    }
+
+    /// Check that `email` belongs to an active user and mark them as seen,
+    /// returning the user with the linodes they can access
+    fn authenticate(&self, email: &str) -> Result<UserInfo> {
+        let user = match self.find_user_by_email(email)? {
+            Some(user) => user,
+            None => bail_fmt!(ErrorKind::DoesNotExist, "No user found with email {}", email),
+        };
+        let id = user.id.expect("users are read with their id");
+        if user.active == Some(false) {
+            bail_fmt!(ErrorKind::Forbidden, "User {} is deactivated", id);
+        }
+        self.touch_user(id)?;
+        match self.get_user(id)? {
+            Some(user) => Ok(user),
+            None => bail_fmt!(ErrorKind::DoesNotExist, "No user found with id {}", id),
+        }
+    }
+
+    /// Rename and/or move a linode to another org
+    fn update_linode(&self, id: i64, name: Option<&str>, org_id: Option<i64>) -> Result<()> {
+        if let Some(name) = name {
+            if self.linode_exists(name)? {
+                bail_fmt!(ErrorKind::BadRequest, "Linode already exists, {}", name);
+            }
+            self.rename_linode(id, name)?;
+        }
+        if let Some(org_id) = org_id {
+            self.set_linode_org(id, org_id)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Snapshot of a backend's connection pool usage
+#[derive(Debug, Clone, Default)]
+pub struct PoolStats {
+    pub max_size: u32,
+    pub connections: u32,
+    pub idle_connections: u32,
+    /// Total connections handed out by the pool
+    pub checkouts: u64,
+    /// Total time spent waiting on the pool for a connection
+    pub wait_seconds: f64,
+}
+
+
+/// Tracks the number of connections checked out of a pool and the time spent waiting for them
+#[derive(Debug, Default)]
+pub struct CheckoutTimer {
+    checkouts: AtomicUsize,
+    wait_micros: AtomicUsize,
+}
+impl CheckoutTimer {
+    /// Time a pool checkout
+    pub fn time<T, F: FnOnce() -> T>(&self, checkout: F) -> T {
+        let start = Instant::now();
+        let conn = checkout();
+        let elapsed = start.elapsed();
+        let micros = elapsed.as_secs() as usize * 1_000_000 + elapsed.subsec_nanos() as usize / 1_000;
+        self.checkouts.fetch_add(1, Ordering::Relaxed);
+        self.wait_micros.fetch_add(micros, Ordering::Relaxed);
+        conn
+    }
+
+    pub fn stats<M: ManageConnection>(&self, pool: &Pool<M>) -> PoolStats {
+        let state = pool.state();
+        PoolStats {
+            max_size: pool.max_size(),
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            checkouts: self.checkouts.load(Ordering::Relaxed) as u64,
+            wait_seconds: self.wait_micros.load(Ordering::Relaxed) as f64 / 1_000_000.,
+        }
+    }
+}
+
+
+/// A source of database connections
+pub trait Backend: Send + Sync {
+    /// Current connection pool usage
+    fn pool_stats(&self) -> PoolStats;
+
+    /// Run `f` against a pooled connection
+    fn run(&self, f: &mut FnMut(&Repo) -> Result<()>) -> Result<()>;
+
+    /// Run `f` inside a transaction, committing only if `f` succeeds
+    fn run_in_transaction(&self, f: &mut FnMut(&Repo) -> Result<()>) -> Result<()>;
+}
+
+
+/// Handle to the configured storage backend
+pub struct Store {
+    backend: Box<Backend>,
+}
+impl Store {
+    pub fn new(backend: Box<Backend>) -> Self {
+        Self { backend: backend }
+    }
+
+    /// Connect to the backend selected by `config`
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let backend: Box<Backend> = match config.backend {
+            config::Backend::Sqlite => {
+                Box::new(sqlite::SqliteBackend::open(&config.database_path, config.pool_size)?)
+            }
+            config::Backend::Memory => {
+                Box::new(sqlite::SqliteBackend::memory()?)
+            }
+            #[cfg(feature = "pg")]
+            config::Backend::Postgres => {
+                Box::new(pg::PgBackend::connect(&config.postgres.connect_string(), config.pool_size)?)
+            }
+            #[cfg(not(feature = "pg"))]
+            config::Backend::Postgres => {
+                bail!("The `postgres` backend requires building with the `pg` feature")
+            }
+        };
+        Ok(Self::new(backend))
+    }
+
+    /// Current connection pool usage
+    pub fn pool_stats(&self) -> PoolStats {
+        self.backend.pool_stats()
+    }
+
+    /// Run `f` against a pooled connection
+    pub fn with<T, F>(&self, f: F) -> Result<T>
+        where F: FnOnce(&Repo) -> Result<T>
+    {
+        let mut f = Some(f);
+        let mut out = None;
+        self.backend.run(&mut |repo| {
+            let f = f.take().expect("`Backend::run` should only call its closure once");
+            out = Some(f(repo)?);
+            Ok(())
+        })?;
+        Ok(out.expect("`Backend::run` should call its closure"))
+    }
+
+    /// Run `f` inside a transaction, committing only if `f` succeeds
+    pub fn transaction<T, F>(&self, f: F) -> Result<T>
+        where F: FnOnce(&Repo) -> Result<T>
+    {
+        let mut f = Some(f);
+        let mut out = None;
+        self.backend.run_in_transaction(&mut |repo| {
+            let f = f.take().expect("`Backend::run_in_transaction` should only call its closure once");
+            out = Some(f(repo)?);
+            Ok(())
+        })?;
+        Ok(out.expect("`Backend::run_in_transaction` should call its closure"))
+    }
+}
+
+
+/// Check if exactly the embedded migrations (`MIGRATIONS`) have been applied
+pub fn is_migrated(applied: &[String]) -> bool {
+    let mut applied = applied.to_vec();
+    applied.sort();
+    let mut expected = MIGRATIONS.to_vec();
+    expected.sort();
+    applied == expected
+}
+
+
+/// Insert the sample orgs, users, memberships and linodes
+pub fn insert_sample_data(repo: &Repo) -> Result<()> {
+    let org_names = ["James Inc", "Bean Group", "Cat Collective", "Dog Dancers"];
+    let emails = [
+        ("james@kominick.com", vec![0, 1, 2, 3]),
+        ("bean@burrito.org", vec![1, 3]),
+        ("cheese@pasta.io", vec![1, 2]),
+    ];
+    let linode_names = [
+        ("charlie", 0),
+        ("mac", 1),
+        ("frank", 2),
+        ("dennis", 3),
+        ("dee", 2),
+    ];
+
+    let org_ids = org_names.iter().map(|name| {
+        repo.insert_org(name)
+    }).collect::<Result<Vec<i64>>>()?;
+
+    for &(email, ref org_indices) in emails.iter() {
+        let user_id = repo.insert_user(email)?;
+        for ind in org_indices {
+            repo.insert_member(user_id, org_ids[*ind])?;
+        }
+    }
+
+    for &(name, org_ind) in linode_names.iter() {
+        repo.insert_linode(name, org_ids[org_ind])?;
+    }
+    Ok(())
+}
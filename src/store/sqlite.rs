@@ -0,0 +1,510 @@
+/*!
+SQLite storage backend
+*/
+use std::path::Path;
+
+use rusqlite::{self, Connection, NO_PARAMS};
+use r2d2_sqlite::SqliteConnectionManager;
+use r2d2::{Pool, CustomizeConnection};
+
+use models::{OrgInfo, OrgInfoRows, UserInfo, User, LinodeInfo, Counts, Event, Webhook, Delivery};
+use store::{self, Repo, Backend, CheckoutTimer, PoolStats};
+use MIGRATIONS;
+use errors::*;
+
+
+pub type DbPool = Pool<SqliteConnectionManager>;
+
+
+/// Turns on foreign key enforcement, which sqlite leaves off for every new connection
+#[derive(Debug)]
+struct ForeignKeys;
+impl CustomizeConnection<Connection, rusqlite::Error> for ForeignKeys {
+    fn on_acquire(&self, conn: &mut Connection) -> ::std::result::Result<(), rusqlite::Error> {
+        conn.execute_batch("pragma foreign_keys = on;")
+    }
+}
+
+
+/// Pool of connections to a sqlite database file
+pub struct SqliteBackend {
+    pub pool: DbPool,
+    checkouts: CheckoutTimer,
+}
+impl SqliteBackend {
+    pub fn new(pool: DbPool) -> Self {
+        Self { pool: pool, checkouts: CheckoutTimer::default() }
+    }
+
+    pub fn open<T: AsRef<Path>>(database_path: T, pool_size: u32) -> Result<Self> {
+        let manager = SqliteConnectionManager::file(database_path.as_ref());
+        let pool = Pool::builder()
+            .max_size(pool_size)
+            .connection_customizer(Box::new(ForeignKeys))
+            .build(manager)?;
+        Ok(Self::new(pool))
+    }
+
+    /// Create a migrated in-memory database populated with the sample data.
+    /// Nothing is persisted, everything is dropped along with the backend.
+    pub fn memory() -> Result<Self> {
+        // Every `:memory:` connection is a separate database, so the
+        // pool holds exactly one connection that is never recycled
+        let pool = Pool::builder()
+            .max_size(1)
+            .max_lifetime(None)
+            .idle_timeout(None)
+            .connection_customizer(Box::new(ForeignKeys))
+            .build(SqliteConnectionManager::memory())?;
+        {
+            let mut conn = pool.get()?;
+            conn.execute_batch(include_str!("../../migrations/init/up.sql"))?;
+            conn.execute_batch(include_str!("../../migrations/events/up.sql"))?;
+            conn.execute_batch(include_str!("../../migrations/webhooks/up.sql"))?;
+            conn.execute_batch(include_str!("../../migrations/profiles/up.sql"))?;
+            conn.execute_batch(include_str!("../../migrations/request-ids/up.sql"))?;
+            conn.execute_batch(include_str!("../../migrations/webhook-detach/up.sql"))?;
+            let trans = conn.transaction()?;
+            store::insert_sample_data(&*trans)?;
+            // record the migrations the same way `migrant` does so readiness
+            // and version checks treat this like any other database
+            trans.execute("create table __migrant_migrations(tag text unique)", NO_PARAMS)?;
+            for tag in MIGRATIONS {
+                trans.execute("insert into __migrant_migrations (tag) values (?)", &[tag])?;
+            }
+            trans.commit()?;
+        }
+        Ok(Self::new(pool))
+    }
+}
+impl Backend for SqliteBackend {
+    fn pool_stats(&self) -> PoolStats {
+        self.checkouts.stats(&self.pool)
+    }
+
+    fn run(&self, f: &mut FnMut(&Repo) -> Result<()>) -> Result<()> {
+        let conn = self.checkouts.time(|| self.pool.get())?;
+        f(&*conn)
+    }
+
+    fn run_in_transaction(&self, f: &mut FnMut(&Repo) -> Result<()>) -> Result<()> {
+        let mut conn = self.checkouts.time(|| self.pool.get())?;
+        let trans = conn.transaction()?;
+        f(&*trans)?;
+        trans.commit()?;
+        Ok(())
+    }
+}
+
+
+impl Repo for Connection {
+    // ------------------------------------------
+    // ----------- Querying things --------------
+    // ------------------------------------------
+    fn get_orgs(&self, ids: &[i64]) -> Result<Vec<OrgInfo>> {
+        let stmt = org_info_query(&format!("org.id in ({})", placeholders(ids.len())));
+        let mut stmt = self.prepare(&stmt)?;
+        let rows = stmt.query_map(&id_params(ids), |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
+        })?.collect::<::std::result::Result<Vec<_>, _>>()?;
+        Ok(OrgInfo::from_rows(rows))
+    }
+
+    fn get_users(&self, ids: &[i64]) -> Result<Vec<UserInfo>> {
+        let stmt = format!("select user.id, user.email, org.id, org.name, linode.id, linode.name, linode.org, \
+                                user.display_name, user.active, user.created_at, user.updated_at, user.last_seen \
+                                from user \
+                                left outer join user_org on user_org.user=user.id \
+                                left outer join org on user_org.org=org.id \
+                                left outer join linode on user_org.org=linode.org and user.active = 1 \
+                                where user.id in ({}) \
+                                order by user.id, org.id, linode.id", placeholders(ids.len()));
+        let mut stmt = self.prepare(&stmt)?;
+        let rows = stmt.query_map(&id_params(ids), |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?,
+                (row.get(7)?, row.get(8)?, row.get(9)?, row.get(10)?, row.get(11)?)))
+        })?.collect::<::std::result::Result<Vec<_>, _>>()?;
+        Ok(UserInfo::from_user_rows(rows))
+    }
+
+    fn get_linodes(&self, ids: &[i64]) -> Result<Vec<LinodeInfo>> {
+        let stmt = format!("select id, name, org from linode where id in ({}) order by id", placeholders(ids.len()));
+        let mut stmt = self.prepare(&stmt)?;
+        let rows = stmt.query_map(&id_params(ids), |row| {
+            Ok(LinodeInfo { id: row.get(0)?, name: row.get(1)?, org: row.get(2)? })
+        })?;
+        Ok(rows.collect::<::std::result::Result<Vec<_>, _>>()?)
+    }
+
+    fn linodes_for_orgs(&self, orgs: &[i64]) -> Result<Vec<LinodeInfo>> {
+        let stmt = format!("select id, name, org from linode where org in ({}) order by id", placeholders(orgs.len()));
+        let mut stmt = self.prepare(&stmt)?;
+        let rows = stmt.query_map(&id_params(orgs), |row| {
+            Ok(LinodeInfo { id: row.get(0)?, name: row.get(1)?, org: row.get(2)? })
+        })?;
+        Ok(rows.collect::<::std::result::Result<Vec<_>, _>>()?)
+    }
+
+    fn each_org(&self, after: i64, limit: i64, f: &mut FnMut(OrgInfo) -> Result<()>) -> Result<Option<i64>> {
+        let stmt = org_info_query("org.id in (select id from org where id > ? order by id limit ?)");
+        let mut stmt = self.prepare(&stmt)?;
+        let rows = stmt.query_map(params![after, limit], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
+        })?;
+        let mut orgs = OrgInfoRows::new();
+        let mut last = None;
+        for row in rows {
+            if let Some(org) = orgs.push(row?) {
+                last = Some(org.id);
+                f(org)?;
+            }
+        }
+        if let Some(org) = orgs.finish() {
+            last = Some(org.id);
+            f(org)?;
+        }
+        Ok(last)
+    }
+
+    fn each_user(&self, after: i64, limit: i64, f: &mut FnMut(User) -> Result<()>) -> Result<Option<i64>> {
+        let stmt = "select id, email, display_name, active, created_at, updated_at, last_seen from user \
+                        where id > ? order by id limit ?";
+        let mut stmt = self.prepare(stmt)?;
+        let rows = stmt.query_map(params![after, limit], user_from_row)?;
+        let mut last = None;
+        for user in rows {
+            let user = user?;
+            last = user.id;
+            f(user)?;
+        }
+        Ok(last)
+    }
+
+    fn each_linode(&self, after: i64, limit: i64, f: &mut FnMut(LinodeInfo) -> Result<()>) -> Result<Option<i64>> {
+        let stmt = "select id, name, org from linode where id > ? order by id limit ?";
+        let mut stmt = self.prepare(stmt)?;
+        let rows = stmt.query_map(params![after, limit], |row| {
+            Ok(LinodeInfo { id: row.get(0)?, name: row.get(1)?, org: row.get(2)? })
+        })?;
+        let mut last = None;
+        for linode in rows {
+            let linode = linode?;
+            last = Some(linode.id);
+            f(linode)?;
+        }
+        Ok(last)
+    }
+
+    fn find_user_by_email(&self, email: &str) -> Result<Option<User>> {
+        let stmt = "select id, email, display_name, active, created_at, updated_at, last_seen from user where email = ?";
+        let mut stmt = self.prepare(stmt)?;
+        let mut rows = stmt.query_map(&[&email], user_from_row)?;
+        let user = match rows.next() {
+            Some(user) => Some(user?),
+            None => None,
+        };
+        Ok(user)
+    }
+
+    fn counts(&self) -> Result<Counts> {
+        let stmt = "select (select count(*) from org), (select count(*) from user), (select count(*) from linode)";
+        Ok(self.query_row(stmt, NO_PARAMS, |row| {
+            Ok(Counts { orgs: row.get(0)?, users: row.get(1)?, linodes: row.get(2)? })
+        })?)
+    }
+
+    fn org_exists(&self, name: &str) -> Result<bool> {
+        let stmt = "select exists(select 1 from org where name = ?)";
+        Ok(self.query_row(stmt, &[&name], |row| {
+            let i: u8 = row.get(0)?;
+            Ok(i == 1)
+        })?)
+    }
+
+    fn user_exists(&self, email: &str) -> Result<bool> {
+        let stmt = "select exists(select 1 from user where email = ?)";
+        Ok(self.query_row(stmt, &[&email], |row| {
+            let i: u8 = row.get(0)?;
+            Ok(i == 1)
+        })?)
+    }
+
+    fn linode_exists(&self, name: &str) -> Result<bool> {
+        let stmt = "select exists(select 1 from linode where name = ?)";
+        Ok(self.query_row(stmt, &[&name], |row| {
+            let i: u8 = row.get(0)?;
+            Ok(i == 1)
+        })?)
+    }
+
+    fn member_exists(&self, user: i64, org: i64) -> Result<bool> {
+        let stmt = "select exists(select 1 from user_org where user = ? and org = ?)";
+        Ok(self.query_row(stmt, params![user, org], |row| {
+            let i: u8 = row.get(0)?;
+            Ok(i == 1)
+        })?)
+    }
+
+    fn applied_migrations(&self) -> Result<Vec<String>> {
+        let stmt = "select exists(select 1 from sqlite_master where type = 'table' and name = '__migrant_migrations')";
+        let has_table = self.query_row(stmt, NO_PARAMS, |row| {
+            let i: u8 = row.get(0)?;
+            Ok(i == 1)
+        })?;
+        if !has_table { return Ok(vec![]) }
+        let mut stmt = self.prepare("select tag from __migrant_migrations order by rowid")?;
+        let tags = stmt.query_map(NO_PARAMS, |row| row.get(0))?;
+        Ok(tags.collect::<::std::result::Result<Vec<String>, _>>()?)
+    }
+
+    // ------------------------------------------
+    // ----------- Creating things --------------
+    // ------------------------------------------
+    fn insert_org(&self, name: &str) -> Result<i64> {
+        let stmt = "insert into org (name) values (?)";
+        Ok(try_insert!(self, stmt, &[&name]))
+    }
+
+    fn insert_user(&self, email: &str) -> Result<i64> {
+        let stmt = "insert into user (email) values (?)";
+        Ok(try_insert!(self, stmt, &[&email]))
+    }
+
+    fn insert_member(&self, user: i64, org: i64) -> Result<i64> {
+        let stmt = "insert into user_org (user, org) values (?, ?)";
+        Ok(try_insert!(self, stmt, params![user, org]))
+    }
+
+    fn insert_linode(&self, name: &str, org: i64) -> Result<i64> {
+        let stmt = "insert into linode (name, org) values (?, ?)";
+        Ok(try_insert!(self, stmt, params![name, org]))
+    }
+
+    // ------------------------------------------
+    // ----------- Updating things --------------
+    // ------------------------------------------
+    fn rename_org(&self, id: i64, name: &str) -> Result<()> {
+        let stmt = "update org set name = ? where id = ?";
+        let count = self.execute(stmt, params![name, id])?;
+        if count == 0 { bail_fmt!(ErrorKind::DoesNotExist, "No org found with id {}", id) }
+        Ok(())
+    }
+
+    fn update_user_email(&self, id: i64, email: &str) -> Result<()> {
+        let stmt = "update user set email = ?, updated_at = cast(strftime('%s', 'now') as integer) where id = ?";
+        let count = self.execute(stmt, params![email, id])?;
+        if count == 0 { bail_fmt!(ErrorKind::DoesNotExist, "No user found with id {}", id) }
+        Ok(())
+    }
+
+    fn update_user_display_name(&self, id: i64, display_name: Option<&str>) -> Result<()> {
+        let stmt = "update user set display_name = ?, updated_at = cast(strftime('%s', 'now') as integer) where id = ?";
+        let count = self.execute(stmt, params![display_name, id])?;
+        if count == 0 { bail_fmt!(ErrorKind::DoesNotExist, "No user found with id {}", id) }
+        Ok(())
+    }
+
+    fn set_user_active(&self, id: i64, active: bool) -> Result<()> {
+        let stmt = "update user set active = ?, updated_at = cast(strftime('%s', 'now') as integer) where id = ?";
+        let count = self.execute(stmt, params![active, id])?;
+        if count == 0 { bail_fmt!(ErrorKind::DoesNotExist, "No user found with id {}", id) }
+        Ok(())
+    }
+
+    fn touch_user(&self, id: i64) -> Result<()> {
+        let stmt = "update user set last_seen = cast(strftime('%s', 'now') as integer) where id = ?";
+        let count = self.execute(stmt, &[&id])?;
+        if count == 0 { bail_fmt!(ErrorKind::DoesNotExist, "No user found with id {}", id) }
+        Ok(())
+    }
+
+    fn rename_linode(&self, id: i64, name: &str) -> Result<()> {
+        let stmt = "update linode set name = ? where id = ?";
+        let count = self.execute(stmt, params![name, id])?;
+        if count == 0 { bail_fmt!(ErrorKind::DoesNotExist, "No linode found with id {}", id) }
+        Ok(())
+    }
+
+    fn set_linode_org(&self, id: i64, org: i64) -> Result<()> {
+        let stmt = "update linode set org = ? where id = ?";
+        let count = self.execute(stmt, params![org, id])?;
+        if count == 0 { bail_fmt!(ErrorKind::DoesNotExist, "No linode found with id {}", id) }
+        Ok(())
+    }
+
+    // ------------------------------------------
+    // ----------- Deleting things --------------
+    // ------------------------------------------
+    fn delete_org(&self, id: i64) -> Result<()> {
+        self.execute("update webhook set detached_org = org, org = null where org = ?", &[&id])?;
+        self.execute("delete from user_org where org = ?", &[&id])?;
+        self.execute("delete from linode where org = ?", &[&id])?;
+        let count = self.execute("delete from org where id = ?", &[&id])?;
+        if count == 0 { bail_fmt!(ErrorKind::DoesNotExist, "No org found with id {}", id) }
+        Ok(())
+    }
+
+    fn delete_user(&self, id: i64) -> Result<()> {
+        self.execute("delete from user_org where user = ?", &[&id])?;
+        let count = self.execute("delete from user where id = ?", &[&id])?;
+        if count == 0 { bail_fmt!(ErrorKind::DoesNotExist, "No user found with id {}", id) }
+        Ok(())
+    }
+
+    fn delete_linode(&self, id: i64) -> Result<()> {
+        let count = self.execute("delete from linode where id = ?", &[&id])?;
+        if count == 0 { bail_fmt!(ErrorKind::DoesNotExist, "No linode found with id {}", id) }
+        Ok(())
+    }
+
+    fn delete_member(&self, user: i64, org: i64) -> Result<()> {
+        let stmt = "delete from user_org where user = ? and org = ?";
+        let count = self.execute(stmt, params![user, org])?;
+        if count == 0 { bail_fmt!(ErrorKind::DoesNotExist, "User {} is not a member of org {}", user, org) }
+        Ok(())
+    }
+
+    // ------------------------------------------
+    // ----------- Change feed ------------------
+    // ------------------------------------------
+    fn insert_event(&self, kind: &str, data: &str, request_id: Option<&str>) -> Result<i64> {
+        let stmt = "insert into event (kind, data, request_id) values (?, ?, ?)";
+        Ok(try_insert!(self, stmt, params![kind, data, request_id]))
+    }
+
+    fn events_after(&self, id: i64, limit: i64) -> Result<Vec<Event>> {
+        let stmt = "select id, kind, data, request_id from event where id > ? order by id limit ?";
+        let mut stmt = self.prepare(stmt)?;
+        let rows = stmt.query_map(params![id, limit], |row| {
+            Ok(Event { id: row.get(0)?, kind: row.get(1)?, data: row.get(2)?, request_id: row.get(3)? })
+        })?;
+        Ok(rows.collect::<::std::result::Result<Vec<_>, _>>()?)
+    }
+
+    fn last_event_id(&self) -> Result<i64> {
+        Ok(self.query_row("select coalesce(max(id), 0) from event", NO_PARAMS, |row| row.get(0))?)
+    }
+
+    fn delete_events_through(&self, id: i64) -> Result<()> {
+        self.execute("delete from event where id <= ?", &[&id])?;
+        Ok(())
+    }
+    // ------------------------------------------
+    // ----------- Webhooks ---------------------
+    // ------------------------------------------
+    fn insert_webhook(&self, url: &str, secret: &str, org: Option<i64>, events: &str) -> Result<i64> {
+        let stmt = "insert into webhook (url, secret, org, events) values (?, ?, ?, ?)";
+        Ok(try_insert!(self, stmt, params![url, secret, org, events]))
+    }
+
+    fn get_webhooks(&self) -> Result<Vec<Webhook>> {
+        let stmt = "select id, url, secret, org, events, detached_org from webhook order by id";
+        let mut stmt = self.prepare(stmt)?;
+        let rows = stmt.query_map(NO_PARAMS, webhook_from_row)?;
+        Ok(rows.collect::<::std::result::Result<Vec<_>, _>>()?)
+    }
+
+    fn webhooks_for_orgs(&self, orgs: &[i64]) -> Result<Vec<Webhook>> {
+        let stmt = format!("select id, url, secret, org, events, detached_org from webhook \
+                                where detached_org is null and (org is null or org in ({})) \
+                                order by id", placeholders(orgs.len()));
+        let mut stmt = self.prepare(&stmt)?;
+        let rows = stmt.query_map(&id_params(orgs), webhook_from_row)?;
+        Ok(rows.collect::<::std::result::Result<Vec<_>, _>>()?)
+    }
+
+    fn delete_webhook(&self, id: i64) -> Result<()> {
+        let count = self.execute("delete from webhook where id = ?", &[&id])?;
+        if count == 0 { bail_fmt!(ErrorKind::DoesNotExist, "No webhook found with id {}", id) }
+        Ok(())
+    }
+
+    fn insert_delivery(&self, webhook: i64, event: i64, kind: &str, payload: &str, next_attempt: i64) -> Result<i64> {
+        let stmt = "insert into webhook_delivery (webhook, event, kind, payload, next_attempt) values (?, ?, ?, ?, ?)";
+        Ok(try_insert!(self, stmt, params![webhook, event, kind, payload, next_attempt]))
+    }
+
+    fn get_deliveries(&self, status: Option<&str>, limit: i64) -> Result<Vec<Delivery>> {
+        let stmt = "select id, webhook, event, kind, payload, status, attempts, next_attempt, last_error \
+                        from webhook_delivery \
+                        where ?1 is null or status = ?1 \
+                        order by id desc limit ?2";
+        let mut stmt = self.prepare(stmt)?;
+        let rows = stmt.query_map(params![status, limit], delivery_from_row)?;
+        Ok(rows.collect::<::std::result::Result<Vec<_>, _>>()?)
+    }
+
+    fn due_deliveries(&self, now: i64, limit: i64) -> Result<Vec<Delivery>> {
+        let stmt = "select id, webhook, event, kind, payload, status, attempts, next_attempt, last_error \
+                        from webhook_delivery \
+                        where status = 'pending' and next_attempt <= ? \
+                        order by next_attempt, id limit ?";
+        let mut stmt = self.prepare(stmt)?;
+        let rows = stmt.query_map(params![now, limit], delivery_from_row)?;
+        Ok(rows.collect::<::std::result::Result<Vec<_>, _>>()?)
+    }
+
+    fn update_delivery(&self, id: i64, status: &str, attempts: i64, next_attempt: i64, last_error: Option<&str>) -> Result<()> {
+        let stmt = "update webhook_delivery set status = ?, attempts = ?, next_attempt = ?, last_error = ? where id = ?";
+        self.execute(stmt, params![status, attempts, next_attempt, last_error, id])?;
+        Ok(())
+    }
+
+    fn replay_delivery(&self, id: i64, now: i64) -> Result<()> {
+        let stmt = "update webhook_delivery set status = 'pending', attempts = 0, next_attempt = ? where id = ?";
+        let count = self.execute(stmt, params![now, id])?;
+        if count == 0 { bail_fmt!(ErrorKind::DoesNotExist, "No delivery found with id {}", id) }
+        Ok(())
+    }
+
+    fn replay_dead_deliveries(&self, now: i64) -> Result<u64> {
+        let stmt = "update webhook_delivery set status = 'pending', attempts = 0, next_attempt = ? where status = 'dead'";
+        Ok(self.execute(stmt, &[&now])? as u64)
+    }
+}
+
+
+/// Orgs joined to their active members and linodes, read into `OrgInfo`s.
+/// `filter` is the `where` clause selecting the orgs.
+fn org_info_query(filter: &str) -> String {
+    format!("select org.id, org.name, user.id, user.email, linode.id, linode.name \
+                 from org \
+                 left outer join user_org on org.id=user_org.org \
+                 left outer join user on user_org.user=user.id and user.active = 1 \
+                 left outer join linode on user_org.org=linode.org \
+                 where {} \
+                 order by org.id, user.id, linode.id", filter)
+}
+
+
+/// `count` comma separated `?`s, for binding a list of ids with `in (...)`
+fn placeholders(count: usize) -> String {
+    vec!["?"; count].join(", ")
+}
+
+
+fn id_params(ids: &[i64]) -> Vec<&rusqlite::types::ToSql> {
+    ids.iter().map(|id| id as &rusqlite::types::ToSql).collect()
+}
+
+
+fn user_from_row(row: &rusqlite::Row) -> rusqlite::Result<User> {
+    Ok(User::with_profile(row.get(0)?, row.get(1)?, (row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?)))
+}
+
+
+fn webhook_from_row(row: &rusqlite::Row) -> rusqlite::Result<Webhook> {
+    let events: String = row.get(4)?;
+    Ok(Webhook {
+        id: row.get(0)?, url: row.get(1)?, secret: row.get(2)?, org: row.get(3)?,
+        events: Webhook::parse_events(&events), detached_org: row.get(5)?,
+    })
+}
+
+
+fn delivery_from_row(row: &rusqlite::Row) -> rusqlite::Result<Delivery> {
+    Ok(Delivery {
+        id: row.get(0)?, webhook: row.get(1)?, event: row.get(2)?, kind: row.get(3)?, payload: row.get(4)?,
+        status: row.get(5)?, attempts: row.get(6)?, next_attempt: row.get(7)?, last_error: row.get(8)?,
+    })
+}
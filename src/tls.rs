@@ -0,0 +1,64 @@
+/*!
+TLS support for `serve`, enabled with the `tls` feature
+
+Certificates and keys are PEM encoded and re-read on `SIGHUP`, so renewed
+certificates can be picked up without a restart.
+*/
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use rouille;
+
+use config::Config;
+use errors::*;
+
+
+/// PEM encoded certificate chain and private key
+pub struct Identity {
+    pub certificate: Vec<u8>,
+    pub private_key: Vec<u8>,
+}
+impl Identity {
+    /// Read the configured certificate and key, `None` if tls isn't configured
+    pub fn load(config: &Config) -> Result<Option<Self>> {
+        let (cert, key) = match (&config.tls_cert, &config.tls_key) {
+            (&Some(ref cert), &Some(ref key)) => (cert, key),
+            (&None, &None) => return Ok(None),
+            _ => bail!("`tls_cert` and `tls_key` must be set together"),
+        };
+        if cfg!(not(feature = "tls")) {
+            bail!("Serving https requires building with the `tls` feature");
+        }
+        Ok(Some(Self {
+            certificate: read(cert).chain_err(|| format!("Unable to read tls certificate: {:?}", cert))?,
+            private_key: read(key).chain_err(|| format!("Unable to read tls key: {:?}", key))?,
+        }))
+    }
+}
+
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    fs::File::open(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+
+/// Handler for the plain http listener, redirecting everything to https on `https_port`
+pub fn redirect_handler(https_port: u16) -> impl Fn(&rouille::Request) -> rouille::Response + Send + Sync + 'static {
+    move |request| {
+        let host = match request.header("Host") {
+            // strip any port, leaving ipv6 literals like `[::1]` intact
+            Some(host) if host.ends_with(']') => host.to_string(),
+            Some(host) => host.rsplitn(2, ':').last().unwrap_or(host).to_string(),
+            None => return rouille::Response::text("Missing Host header").with_status_code(400),
+        };
+        let location = if https_port == 443 {
+            format!("https://{}{}", host, request.raw_url())
+        } else {
+            format!("https://{}:{}{}", host, https_port, request.raw_url())
+        };
+        rouille::Response::redirect_301(location)
+    }
+}
@@ -0,0 +1,249 @@
+/*!
+Outbound webhooks
+
+Every recorded event is queued for delivery to the matching webhook
+subscriptions in the same transaction, and posted by a background worker.
+Failed deliveries are retried with exponential backoff and marked `dead`
+once `webhook_max_attempts` is reached, after which they can be replayed.
+
+Deliveries are signed with the subscription's secret:
+`X-OrgDemo-Signature: sha256=<hex hmac-sha256 of "{X-OrgDemo-Timestamp}.{body}">`
+
+Webhook urls must be http(s) and, unless `webhook_allow_private_hosts` is set,
+resolve to public addresses. Hosts are checked again before every delivery,
+and redirects aren't followed, so a receiver can't point deliveries inward later.
+*/
+use std::cmp;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::{OsRng, Rng};
+use reqwest;
+use serde_json::Value;
+use sha2::Sha256;
+
+use config::Config;
+use models::{Webhook, Delivery};
+use service::State;
+use store::Repo;
+use errors::*;
+
+
+pub static PENDING: &'static str = "pending";
+pub static DELIVERED: &'static str = "delivered";
+pub static DEAD: &'static str = "dead";
+
+
+/// How often the worker looks for due deliveries
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Deliveries attempted per poll
+const BATCH_SIZE: i64 = 50;
+
+/// Longest wait between attempts, in seconds
+const MAX_BACKOFF: u64 = 60 * 60;
+
+
+/// Orgs an event concerns, for matching per-org subscriptions
+fn event_orgs(kind: &str, data: &Value) -> Vec<i64> {
+    let mut orgs = vec![];
+    if kind.starts_with("org.") {
+        orgs.extend(data["id"].as_i64());
+    }
+    orgs.extend(data["org_id"].as_i64());
+    // a linode moved out of an org concerns its previous org too
+    orgs.extend(data["previous_org_id"].as_i64());
+    if let Some(org_ids) = data["org_ids"].as_array() {
+        orgs.extend(org_ids.iter().filter_map(Value::as_i64));
+    }
+    orgs
+}
+
+
+/// Check if `webhook` subscribes to `kind`. Subscribed kinds may end
+/// in a wildcard, e.g. `linode.*`
+fn subscribes(webhook: &Webhook, kind: &str, orgs: &[i64]) -> bool {
+    let org_matches = webhook.org.map(|org| orgs.contains(&org)).unwrap_or(true);
+    let kind_matches = webhook.events.is_empty() || webhook.events.iter().any(|sub| {
+        match sub.find(".*") {
+            Some(ind) if ind + 2 == sub.len() => kind.starts_with(&sub[..ind + 1]),
+            _ => sub == kind,
+        }
+    });
+    org_matches && kind_matches
+}
+
+
+/// Queue event `id` for delivery to every subscribed webhook
+pub fn enqueue(repo: &Repo, id: i64, kind: &str, data: &Value, request_id: Option<&str>) -> Result<()> {
+    let orgs = event_orgs(kind, data);
+    let payload = json!({"id": id, "kind": kind, "data": data, "request_id": request_id}).to_string();
+    let now = Utc::now().timestamp();
+    for webhook in repo.webhooks_for_orgs(&orgs)?.iter().filter(|webhook| subscribes(webhook, kind, &orgs)) {
+        repo.insert_delivery(webhook.id, id, kind, &payload, now)?;
+    }
+    Ok(())
+}
+
+
+/// Generate a signing secret for a new subscription, 32 random bytes hex encoded
+pub fn new_secret() -> Result<String> {
+    let mut bytes = [0u8; 32];
+    OsRng::new()?.fill_bytes(&mut bytes);
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+
+fn is_private_v4(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+        || ip.is_broadcast() || ip.is_multicast() || ip.is_documentation()
+        // shared address space (carrier-grade nat)
+        || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+        || octets[0] == 0
+}
+
+
+fn is_private(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ref ip) => is_private_v4(ip),
+        IpAddr::V6(ref ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                // unique local fc00::/7 and link local fe80::/10
+                || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+                || ipv4_in_v6(ip).map(|ip| is_private_v4(&ip)).unwrap_or(false)
+        }
+    }
+}
+
+
+/// The ipv4 address of an ipv4-mapped (`::ffff:a.b.c.d`) or -compatible address
+fn ipv4_in_v6(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    if segments[..5].iter().all(|s| *s == 0) && (segments[5] == 0 || segments[5] == 0xffff) {
+        ip.to_ipv4()
+    } else {
+        None
+    }
+}
+
+
+/// Check that `url` is an http(s) url, and that its host only resolves
+/// to public addresses unless `allow_private` is set
+pub fn check_url(url: &str, allow_private: bool) -> Result<()> {
+    let parsed = match reqwest::Url::parse(url) {
+        Ok(ref parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => parsed.clone(),
+        _ => bail_fmt!(ErrorKind::BadRequest, "Webhook urls must be http or https: {}", url),
+    };
+    let host = match parsed.host_str() {
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']').to_string(),
+        None => bail_fmt!(ErrorKind::BadRequest, "Webhook url has no host: {}", url),
+    };
+    if allow_private { return Ok(()) }
+    let port = parsed.port_or_known_default().unwrap_or(80);
+    let addrs = (host.as_str(), port).to_socket_addrs()
+        .map_err(|e| format_err!(ErrorKind::BadRequest, "Unable to resolve webhook host {}: {}", host, e))?
+        .collect::<Vec<_>>();
+    if addrs.is_empty() || addrs.iter().any(|addr| is_private(&addr.ip())) {
+        bail_fmt!(ErrorKind::BadRequest, "Webhook host {} is not a public address", host);
+    }
+    Ok(())
+}
+
+
+/// Hex encoded hmac-sha256 of `{timestamp}.{body}`
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("hmac accepts keys of any length");
+    mac.input(format!("{}.{}", timestamp, body).as_bytes());
+    mac.result().code().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+
+/// Seconds to wait after the `attempts`th failed attempt
+fn backoff(base: u64, attempts: i64) -> u64 {
+    let exp = cmp::min(attempts.saturating_sub(1), 20) as u32;
+    cmp::min(base.saturating_mul(2u64.pow(exp)), MAX_BACKOFF)
+}
+
+
+fn deliver(http: &reqwest::Client, webhook: &Webhook, delivery: &Delivery, allow_private: bool) -> ::std::result::Result<(), String> {
+    // the host may resolve differently than when the webhook was created
+    check_url(&webhook.url, allow_private).map_err(|e| e.to_string())?;
+    let timestamp = Utc::now().timestamp();
+    let resp = http.post(webhook.url.as_str())
+        .header("Content-Type", "application/json")
+        .header("X-OrgDemo-Event", delivery.kind.as_str())
+        .header("X-OrgDemo-Delivery", delivery.id.to_string())
+        .header("X-OrgDemo-Timestamp", timestamp.to_string())
+        .header("X-OrgDemo-Signature", format!("sha256={}", sign(&webhook.secret, timestamp, &delivery.payload)))
+        .body(delivery.payload.clone())
+        .send()
+        .map_err(|e| e.to_string())?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("Receiver responded with {}", resp.status()))
+    }
+}
+
+
+/// Attempt all due deliveries, recording the outcome of each.
+/// Run every second by the worker started with `spawn_worker`.
+pub fn run_due(state: &State, http: &reqwest::Client) -> Result<()> {
+    let now = Utc::now().timestamp();
+    let (max_attempts, base, allow_private) = {
+        let config = state.config();
+        (config.webhook_max_attempts as i64, config.webhook_backoff, config.webhook_allow_private_hosts)
+    };
+    let (due, webhooks) = state.store.with(|repo| Ok((repo.due_deliveries(now, BATCH_SIZE)?, repo.get_webhooks()?)))?;
+    for delivery in &due {
+        let webhook = match webhooks.iter().find(|webhook| webhook.id == delivery.webhook) {
+            Some(webhook) => webhook,
+            None => continue,
+        };
+        let attempts = delivery.attempts + 1;
+        let (status, next_attempt, error) = match deliver(http, webhook, delivery, allow_private) {
+            Ok(()) => (DELIVERED, now, None),
+            Err(e) => {
+                if attempts >= max_attempts {
+                    error!("Webhook delivery {} to {} is dead after {} attempts: {}", delivery.id, webhook.url, attempts, e);
+                    (DEAD, now, Some(e))
+                } else {
+                    warn!("Webhook delivery {} to {} failed (attempt {}): {}", delivery.id, webhook.url, attempts, e);
+                    (PENDING, Utc::now().timestamp() + backoff(base, attempts) as i64, Some(e))
+                }
+            }
+        };
+        state.store.with(|repo| {
+            repo.update_delivery(delivery.id, status, attempts, next_attempt, error.as_ref().map(String::as_str))
+        })?;
+    }
+    Ok(())
+}
+
+
+/// Http client used for deliveries. Redirects aren't followed.
+pub fn http_client(config: &Config) -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.webhook_timeout))
+        .redirect(reqwest::RedirectPolicy::none())
+        .build()?)
+}
+
+
+/// Spawn the delivery worker
+pub fn spawn_worker(state: State) -> Result<thread::JoinHandle<()>> {
+    let http = http_client(&state.config())?;
+    Ok(thread::spawn(move || {
+        loop {
+            if let Err(e) = run_due(&state, &http) {
+                error!("Webhook delivery failed: {}", e);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }))
+}
@@ -0,0 +1,189 @@
+extern crate rouille;
+extern crate org_demo;
+extern crate org_demo_client;
+
+mod support;
+
+use org_demo_client::{api, Client};
+use org_demo_client::errors::{Error, ErrorKind};
+
+
+fn client(url: &str) -> Client {
+    Client::new(url).expect("invalid test server url")
+}
+
+
+/// Serve a fixed error response for every request
+fn serve_status(status: u16, body: &'static str) -> String {
+    support::serve_fn(move |_| {
+        rouille::Response::text(body)
+            .with_status_code(status)
+            .with_unique_header("Retry-After", "7")
+    })
+}
+
+
+fn kind<T: ::std::fmt::Debug>(res: Result<T, Error>) -> ErrorKind {
+    match res {
+        Ok(value) => panic!("expected an error, got {:?}", value),
+        Err(e) => e.0,
+    }
+}
+
+
+#[test]
+fn creates_and_reads_back() {
+    let (_, url) = support::serve(support::config());
+    let client = client(&url);
+
+    let org_id = client.create_org("Otter Ocean").unwrap();
+    let user_id = client.create_user("otter@ocean.io", &[org_id]).unwrap();
+    let linode_id = client.create_linode("river", org_id).unwrap();
+
+    let org = client.orgs().unwrap().into_iter().find(|org| org.id == org_id).unwrap();
+    assert_eq!(org.name, "Otter Ocean");
+    assert_eq!(org.users.len(), 1);
+    assert_eq!(org.linodes.len(), 1);
+
+    let user = client.user(user_id).unwrap();
+    assert_eq!(user.email, "otter@ocean.io");
+    assert!(user.linodes.iter().any(|linode| linode.id == Some(linode_id)));
+
+    let org = client.org(org_id).unwrap();
+    assert_eq!(org.name, "Otter Ocean");
+    assert_eq!(org.users.len(), 1);
+    let linode = client.linode(linode_id).unwrap();
+    assert_eq!(linode.name, "river");
+    assert_eq!(linode.org, Some(org_id));
+    match kind(client.org(org_id + 1000)) {
+        ErrorKind::DoesNotExist(_) => (),
+        kind => panic!("expected DoesNotExist, got {:?}", kind),
+    }
+    match kind(client.linode(linode_id + 1000)) {
+        ErrorKind::DoesNotExist(_) => (),
+        kind => panic!("expected DoesNotExist, got {:?}", kind),
+    }
+
+    assert!(client.org_exists("Otter Ocean").unwrap());
+    assert!(client.user_exists("otter@ocean.io").unwrap());
+    assert!(client.linode_exists("river").unwrap());
+    assert!(!client.linode_exists("lake").unwrap());
+}
+
+
+#[test]
+fn updates_and_deletes() {
+    let (_, url) = support::serve(support::config());
+    let client = client(&url);
+
+    let org_id = client.create_org("Otter Ocean").unwrap();
+    let user_id = client.create_user("otter@ocean.io", &[]).unwrap();
+    client.add_member(user_id, org_id).unwrap();
+    client.rename_org(org_id, "Otter Sea").unwrap();
+    client.update_user_email(user_id, "otter@sea.io").unwrap();
+    assert!(client.org_exists("Otter Sea").unwrap());
+    assert_eq!(client.user(user_id).unwrap().email, "otter@sea.io");
+
+    client.remove_member(user_id, org_id).unwrap();
+    client.delete_user(user_id).unwrap();
+    client.delete_org(org_id).unwrap();
+    assert!(!client.org_exists("Otter Sea").unwrap());
+    match kind(client.user(user_id)) {
+        ErrorKind::DoesNotExist(_) => (),
+        kind => panic!("expected DoesNotExist, got {:?}", kind),
+    }
+}
+
+
+#[test]
+fn probes_and_admin_routes() {
+    let mut config = support::config();
+    config.admin_token = Some("otter-token".into());
+    config.webhook_allow_private_hosts = true;
+    let (_, url) = support::serve(config);
+
+    client(&url).healthz().unwrap();
+    let version = client(&url).version().unwrap();
+    assert_eq!(version.version, env!("CARGO_PKG_VERSION"));
+    assert!(!version.migrations.is_empty());
+
+    match kind(client(&url).webhooks()) {
+        ErrorKind::Forbidden(_) => (),
+        kind => panic!("expected Forbidden, got {:?}", kind),
+    }
+
+    let client = client(&url).with_admin_token("otter-token");
+    let created = client.create_webhook(&api::CreateWebhook {
+        url: "http://127.0.0.1:9/hook".into(),
+        secret: None,
+        org_id: None,
+        events: vec!["org.created".into()],
+    }).unwrap();
+    assert!(!created.secret.is_empty());
+    let webhooks = client.webhooks().unwrap();
+    assert_eq!(webhooks.len(), 1);
+    assert_eq!(webhooks[0].id, created.webhook_id);
+
+    assert!(client.deliveries(Some("dead"), Some(10)).unwrap().is_empty());
+    assert_eq!(client.replay_deliveries().unwrap(), 0);
+    client.delete_webhook(created.webhook_id).unwrap();
+    assert!(client.webhooks().unwrap().is_empty());
+}
+
+
+#[test]
+fn maps_server_errors() {
+    let (_, url) = support::serve(support::config());
+    let client = client(&url);
+
+    match kind(client.create_org("James Inc")) {
+        ErrorKind::BadRequest(_) => (),
+        kind => panic!("expected BadRequest, got {:?}", kind),
+    }
+
+    let user_id = client.create_user("otter@ocean.io", &[]).unwrap();
+    client.deactivate_user(user_id).unwrap();
+    match kind(client.authenticate("otter@ocean.io")) {
+        ErrorKind::Forbidden(_) => (),
+        kind => panic!("expected Forbidden, got {:?}", kind),
+    }
+    client.reactivate_user(user_id).unwrap();
+    assert_eq!(client.authenticate("otter@ocean.io").unwrap().id, user_id);
+}
+
+
+#[test]
+fn maps_body_and_rate_limits() {
+    let mut config = support::config();
+    config.max_body_size = 32;
+    let (_, url) = support::serve(config);
+    match kind(client(&url).create_org(&"otter".repeat(10))) {
+        ErrorKind::PayloadTooLarge(_) => (),
+        kind => panic!("expected PayloadTooLarge, got {:?}", kind),
+    }
+
+    let mut config = support::config();
+    config.rate_limit = 1;
+    config.rate_limit_burst = 1;
+    let (_, url) = support::serve(config);
+    let client = client(&url);
+    client.orgs().unwrap();
+    match kind(client.orgs()) {
+        ErrorKind::RateLimited(Some(retry_after)) => assert!(retry_after > 0),
+        kind => panic!("expected RateLimited, got {:?}", kind),
+    }
+}
+
+
+#[test]
+fn maps_statuses() {
+    let expect = |status: u16, check: fn(&ErrorKind) -> bool| {
+        let kind = kind(client(&serve_status(status, "nope")).orgs());
+        assert!(check(&kind), "status {} mapped to {:?}", status, kind);
+    };
+    expect(409, |kind| match *kind { ErrorKind::Conflict(ref s) => s == "nope", _ => false });
+    expect(412, |kind| match *kind { ErrorKind::PreconditionFailed(ref s) => s == "nope", _ => false });
+    expect(422, |kind| match *kind { ErrorKind::Unprocessable(ref s) => s == "nope", _ => false });
+    expect(429, |kind| match *kind { ErrorKind::RateLimited(Some(7)) => true, _ => false });
+    expect(503, |kind| match *kind { ErrorKind::Server(503, ref s) => s == "nope", _ => false });
+}
@@ -0,0 +1,113 @@
+//! Cors preflights and headers on simple requests, across read and write routes
+extern crate org_demo;
+extern crate rouille;
+
+mod support;
+
+use org_demo::config::Config;
+use org_demo::service;
+
+
+fn config() -> Config {
+    let mut config = support::config();
+    config.cors_origins = vec!["https://dash.example.com".into()];
+    config
+}
+
+
+fn header(resp: &rouille::Response, name: &str) -> Option<String> {
+    resp.headers.iter()
+        .find(|&&(ref key, _)| key.eq_ignore_ascii_case(name))
+        .map(|&(_, ref value)| value.to_string())
+}
+
+
+fn request(method: &str, url: &str, headers: &[(&str, &str)], body: &str) -> rouille::Request {
+    let headers = headers.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect();
+    rouille::Request::fake_http(method, url, headers, body.as_bytes().to_vec())
+}
+
+
+fn preflight(method: &str, origin: &str, request_headers: &str) -> rouille::Request {
+    request("OPTIONS", "/api/create/org", &[
+        ("Origin", origin),
+        ("Access-Control-Request-Method", method),
+        ("Access-Control-Request-Headers", request_headers),
+    ], "")
+}
+
+
+#[test]
+fn answers_preflights() {
+    let state = service::build_state(config()).unwrap();
+    let handler = service::build_handler(state);
+
+    for method in &["GET", "POST"] {
+        let resp = handler(&preflight(method, "https://dash.example.com", "Content-Type, Idempotency-Key"));
+        assert_eq!(resp.status_code, 204);
+        assert_eq!(header(&resp, "Access-Control-Allow-Origin").as_ref().map(String::as_str), Some("https://dash.example.com"));
+        assert_eq!(header(&resp, "Access-Control-Allow-Methods").as_ref().map(String::as_str), Some("GET, POST"));
+        assert_eq!(header(&resp, "Vary").as_ref().map(String::as_str), Some("Origin"));
+        assert!(header(&resp, "Access-Control-Max-Age").is_some());
+        assert!(header(&resp, "Access-Control-Allow-Credentials").is_none());
+    }
+
+    // refused: unknown origin, method, or header
+    for req in &[preflight("POST", "https://evil.example.com", ""),
+                 preflight("DELETE", "https://dash.example.com", ""),
+                 preflight("POST", "https://dash.example.com", "X-Secret")] {
+        let resp = handler(req);
+        assert_eq!(resp.status_code, 204);
+        assert!(header(&resp, "Access-Control-Allow-Origin").is_none());
+        assert!(header(&resp, "Access-Control-Allow-Methods").is_none());
+    }
+}
+
+
+#[test]
+fn adds_headers_to_simple_requests() {
+    let state = service::build_state(config()).unwrap();
+    let handler = service::build_handler(state);
+
+    let get = request("GET", "/api/orgs", &[("Origin", "https://dash.example.com")], "");
+    let post = request("POST", "/api/create/org",
+                       &[("Origin", "https://dash.example.com"), ("Content-Type", "application/json")],
+                       r#"{"name": "Cors Corp"}"#);
+    for req in &[get, post] {
+        let resp = handler(req);
+        assert_eq!(resp.status_code, 200);
+        assert_eq!(header(&resp, "Access-Control-Allow-Origin").as_ref().map(String::as_str), Some("https://dash.example.com"));
+        assert!(header(&resp, "Access-Control-Expose-Headers").unwrap().contains("ETag"));
+    }
+
+    let get = request("GET", "/api/orgs", &[("Origin", "https://evil.example.com")], "");
+    let post = request("POST", "/api/create/org",
+                       &[("Origin", "https://evil.example.com"), ("Content-Type", "application/json")],
+                       r#"{"name": "Evil Corp"}"#);
+    let same_origin = request("GET", "/api/orgs", &[], "");
+    for req in &[get, post, same_origin] {
+        let resp = handler(req);
+        assert!(header(&resp, "Access-Control-Allow-Origin").is_none());
+    }
+}
+
+
+#[test]
+fn wildcard_origins() {
+    let mut config = config();
+    config.cors_origins = vec!["*".into()];
+    assert!(config.validate().is_ok());
+    let state = service::build_state(config.clone()).unwrap();
+    let handler = service::build_handler(state);
+
+    let resp = handler(&request("GET", "/api/orgs", &[("Origin", "https://any.example.com")], ""));
+    assert_eq!(header(&resp, "Access-Control-Allow-Origin").as_ref().map(String::as_str), Some("*"));
+    // lists still vary by `Accept`, but not by origin
+    assert!(resp.headers.iter().all(|&(ref key, ref value)| !key.eq_ignore_ascii_case("Vary") || value != "Origin"));
+
+    let resp = handler(&preflight("POST", "https://any.example.com", "Content-Type"));
+    assert_eq!(header(&resp, "Access-Control-Allow-Origin").as_ref().map(String::as_str), Some("*"));
+
+    config.cors_credentials = true;
+    assert!(config.validate().is_err());
+}
@@ -0,0 +1,67 @@
+//! The `/api/events` stream, read from a live server
+extern crate org_demo;
+extern crate org_demo_client;
+extern crate rouille;
+
+mod support;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use org_demo_client::Client;
+
+
+/// Read lines until one satisfies `check`, returning the lines read
+fn read_until<R: BufRead>(reader: &mut R, check: &Fn(&str) -> bool) -> Vec<String> {
+    let mut lines = vec![];
+    loop {
+        let mut line = String::new();
+        assert!(reader.read_line(&mut line).unwrap() > 0, "stream ended after {:?}", lines);
+        let line = line.trim_end().to_string();
+        let done = check(&line);
+        lines.push(line);
+        if done {
+            return lines
+        }
+    }
+}
+
+
+#[test]
+fn streams_events_until_shutdown() {
+    let (state, url) = support::serve(support::config());
+    let mut socket = TcpStream::connect(url.trim_start_matches("http://")).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    socket.write_all(b"GET /api/events HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut reader = BufReader::new(socket);
+
+    let head = read_until(&mut reader, &|line| line.is_empty());
+    assert_eq!(head[0], "HTTP/1.1 200 OK");
+    let has = |name: &str, value: &str| head.iter().any(|line| {
+        let mut parts = line.splitn(2, ':');
+        parts.next().unwrap().eq_ignore_ascii_case(name) && parts.next().map(str::trim) == Some(value)
+    });
+    assert!(has("Content-Type", "text/event-stream"));
+    assert!(has("Transfer-Encoding", "chunked"));
+    assert!(!head.iter().any(|line| line.to_lowercase().starts_with("upgrade")));
+    assert!(!has("Connection", "upgrade"));
+
+    read_until(&mut reader, &|line| line == "retry: 3000");
+    let org_id = Client::new(&url).unwrap().create_org("Stream Co").unwrap();
+    let frame = read_until(&mut reader, &|line| line.starts_with("data: "));
+    assert!(frame.iter().any(|line| line == "event: org.created"));
+    assert!(frame.last().unwrap().contains(&format!("\"id\":{}", org_id)));
+    assert_eq!(state.in_flight.load(Ordering::SeqCst), 1);
+
+    // shutting down ends the body and the stream stops counting as in flight
+    state.events.close();
+    read_until(&mut reader, &|line| line == "0");
+    let start = Instant::now();
+    while state.in_flight.load(Ordering::SeqCst) > 0 {
+        assert!(start.elapsed() < Duration::from_secs(5), "stream still in flight");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,133 @@
+//! Negotiating list formats, and streaming long lists a page at a time
+extern crate org_demo;
+extern crate rouille;
+
+mod support;
+
+use std::io::Read;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use org_demo::{ToCsvResponse, ToNdjsonResponse};
+use org_demo::errors::*;
+use org_demo::formats::{self, Format, Rows};
+use org_demo::models::LinodeInfo;
+use org_demo::service::{self, State};
+use org_demo::store::Repo;
+
+
+fn negotiate(accept: &str) -> Format {
+    let request = rouille::Request::fake_http("GET", "/api/orgs", vec![("Accept".into(), accept.into())], vec![]);
+    formats::negotiate(&request).unwrap()
+}
+
+
+fn get(state: &State, url: &str) -> rouille::Response {
+    let request = rouille::Request::fake_http("GET", url, vec![], vec![]);
+    service::build_handler(state.clone())(&request)
+}
+
+
+fn body(resp: rouille::Response) -> String {
+    let (mut reader, _) = resp.data.into_reader_and_size();
+    let mut body = String::new();
+    reader.read_to_string(&mut body).unwrap();
+    body
+}
+
+
+#[test]
+fn negotiates_by_quality() {
+    assert_eq!(negotiate("text/csv"), Format::Csv);
+    assert_eq!(negotiate("text/csv;q=0, application/json"), Format::Json);
+    assert_eq!(negotiate("text/csv;q=0"), Format::Json);
+    assert_eq!(negotiate("application/json;q=0.5, application/x-ndjson"), Format::Ndjson);
+    assert_eq!(negotiate("text/csv; q=0.8, application/x-ndjson; Q=0.9"), Format::Ndjson);
+    // ties keep the order they're listed in
+    assert_eq!(negotiate("application/x-ndjson;q=0.5, text/csv;q=0.5"), Format::Ndjson);
+    assert_eq!(negotiate("text/html, */*;q=0.1, text/csv;q=0.2"), Format::Csv);
+    assert_eq!(negotiate("text/csv;q=nan, application/x-ndjson;q=2, text/html"), Format::Json);
+}
+
+
+#[test]
+fn streams_every_page() {
+    let state = service::build_state(support::config()).unwrap();
+    let created = 1200;
+    state.store.transaction(|repo| {
+        for i in 0..created {
+            repo.insert_linode(&format!("linode-{}", i), 1)?;
+        }
+        Ok(())
+    }).unwrap();
+
+    let csv = body(get(&state, "/api/linodes?format=csv"));
+    // header, sample linodes and the created ones
+    assert_eq!(csv.lines().count(), 1 + 5 + created);
+    assert!(csv.lines().last().unwrap().starts_with(&format!("{},linode-{},1", 5 + created, created - 1)));
+
+    let ndjson = body(get(&state, "/api/linodes?format=ndjson"));
+    assert_eq!(ndjson.lines().count(), 5 + created);
+}
+
+
+#[test]
+fn releases_the_connection_while_writing() {
+    let state = service::build_state(support::config()).unwrap();
+    state.store.transaction(|repo| {
+        for i in 0..5000 {
+            repo.insert_linode(&format!("a-linode-with-a-rather-long-name-{}", i), 1)?;
+        }
+        Ok(())
+    }).unwrap();
+
+    // a client that doesn't read its response, more of it than is buffered ahead
+    let stalled = get(&state, "/api/linodes?format=csv");
+    thread::sleep(Duration::from_millis(200));
+
+    // the memory backend has a single connection
+    let start = Instant::now();
+    let resp = get(&state, "/api/orgs");
+    assert_eq!(resp.status_code, 200);
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    assert_eq!(body(stalled).lines().count(), 1 + 5 + 5000);
+}
+
+
+/// The sample linodes two at a time, failing on any page after the first
+fn failing_after_first_page(repo: &Repo, after: i64, _limit: i64, f: &mut FnMut(LinodeInfo) -> Result<()>) -> Result<Option<i64>> {
+    if after > i64::MIN {
+        return Err("second page failed".into());
+    }
+    repo.each_linode(after, 2, f)
+}
+
+fn failing(_repo: &Repo, _after: i64, _limit: i64, _f: &mut FnMut(LinodeInfo) -> Result<()>) -> Result<Option<i64>> {
+    Err("first page failed".into())
+}
+
+
+#[test]
+fn marks_failed_streams() {
+    let state = service::build_state(support::config()).unwrap();
+
+    // nothing has been sent yet, so the handler gets the error
+    assert!(Rows::new(state.clone(), failing).to_csv_resp().is_err());
+    assert!(Rows::new(state.clone(), failing).to_ndjson_resp().is_err());
+
+    let resp = Rows::new(state.clone(), failing_after_first_page).to_csv_resp().unwrap();
+    assert_eq!(resp.status_code, 200);
+    let csv = body(resp);
+    let lines = csv.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 1 + 2 + 1);
+    assert!(lines[3].starts_with("error,Something went wrong"));
+
+    let ndjson = body(Rows::new(state.clone(), failing_after_first_page).to_ndjson_resp().unwrap());
+    let lines = ndjson.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2 + 1);
+    assert!(lines[2].starts_with(r#"{"error":"Something went wrong"#));
+
+    // complete lists don't end with a marker
+    assert!(!body(get(&state, "/api/linodes?format=csv")).contains("error"));
+}
@@ -0,0 +1,129 @@
+//! GraphQL queries, compared against the equivalent rest routes
+extern crate org_demo;
+extern crate rouille;
+#[macro_use] extern crate serde_json;
+
+mod support;
+
+use std::io::Read;
+
+use serde_json::Value;
+
+use org_demo::{graphql, metrics};
+use org_demo::service::{self, State};
+
+
+fn call(state: &State, method: &str, url: &str, body: Value) -> (u16, Value) {
+    let headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+    let request = rouille::Request::fake_http(method, url, headers, body.to_string().into_bytes());
+    let resp = service::build_handler(state.clone())(&request);
+    let status = resp.status_code;
+    let (mut reader, _) = resp.data.into_reader_and_size();
+    let mut body = String::new();
+    reader.read_to_string(&mut body).unwrap();
+    (status, serde_json::from_str(&body).unwrap_or(Value::Null))
+}
+
+
+fn query(state: &State, query: &str) -> Value {
+    let (status, body) = call(state, "POST", "/graphql", json!({"query": query}));
+    assert_eq!(status, 200, "{}", body);
+    assert!(body.get("errors").is_none(), "{}", body);
+    body["data"].clone()
+}
+
+
+/// Ids of the objects in a json array, as strings like GraphQL's ids
+fn ids(objects: &Value) -> Vec<String> {
+    objects.as_array().expect("array").iter()
+        .map(|object| match object["id"] {
+            Value::String(ref id) => id.clone(),
+            ref id => id.to_string(),
+        })
+        .collect()
+}
+
+
+/// Check that GraphQL's view of every user matches `GET /api/user/{id}`
+fn assert_users_match_rest(state: &State) {
+    let data = query(state, "{ users { id active orgs { id } linodes { id } } }");
+    for user in data["users"].as_array().unwrap() {
+        let (status, rest) = call(state, "GET", &format!("/api/user/{}", user["id"].as_str().unwrap()), Value::Null);
+        assert_eq!(status, 200);
+        let rest = &rest["user"];
+        assert_eq!(user["active"], rest["active"]);
+        assert_eq!(ids(&user["orgs"]), ids(&rest["orgs"]), "orgs of user {}", user["id"]);
+        assert_eq!(ids(&user["linodes"]), ids(&rest["linodes"]), "linodes of user {}", user["id"]);
+    }
+}
+
+
+#[test]
+fn resolves_relations() {
+    let state = service::build_state(support::config()).unwrap();
+    let data = query(&state, "{ orgs { id name users { email orgs { id } } linodes { name org { id } } } }");
+    let orgs = data["orgs"].as_array().unwrap();
+    assert_eq!(orgs.len(), 4);
+    for org in orgs {
+        for linode in org["linodes"].as_array().unwrap() {
+            assert_eq!(linode["org"]["id"], org["id"]);
+        }
+        for user in org["users"].as_array().unwrap() {
+            assert!(ids(&user["orgs"]).contains(&org["id"].as_str().unwrap().to_string()));
+        }
+    }
+
+    let data = query(&state, "{ org(id: \"1\") { name } linode(id: \"1000\") { name } }");
+    assert_eq!(data["org"]["name"], "James Inc");
+    assert_eq!(data["linode"], Value::Null);
+    let (status, body) = call(&state, "POST", "/graphql", json!({"query": "{ user(id: \"x1\") { email } }"}));
+    assert_eq!(status, 200);
+    assert!(body["errors"][0]["message"].as_str().unwrap().contains("Invalid id"));
+}
+
+
+#[test]
+fn deactivated_users_keep_their_orgs() {
+    let state = service::build_state(support::config()).unwrap();
+    assert_users_match_rest(&state);
+
+    let data = query(&state, "{ user(id: \"1\") { orgs { id } linodes { id } } }");
+    let orgs = ids(&data["user"]["orgs"]);
+    assert!(!orgs.is_empty());
+    let (status, _) = call(&state, "POST", "/api/deactivate/user/1", Value::Null);
+    assert_eq!(status, 200);
+
+    let data = query(&state, "{ user(id: \"1\") { active orgs { id } linodes { id } } }");
+    assert_eq!(data["user"]["active"], false);
+    assert_eq!(ids(&data["user"]["orgs"]), orgs);
+    assert!(ids(&data["user"]["linodes"]).is_empty());
+    assert_users_match_rest(&state);
+}
+
+
+#[test]
+fn mutations_see_their_changes() {
+    let state = service::build_state(support::config()).unwrap();
+    let data = query(&state, "{ org(id: \"2\") { linodes { id } } }");
+    let before = data["org"]["linodes"].as_array().unwrap().len();
+    let data = query(&state, "mutation { createLinode(name: \"river\", orgId: \"2\") { name org { linodes { name } } } }");
+    let linodes = data["createLinode"]["org"]["linodes"].as_array().unwrap();
+    assert_eq!(linodes.len(), before + 1);
+    assert!(linodes.iter().any(|linode| linode["name"] == "river"));
+}
+
+
+#[test]
+fn labels_the_graphql_route() {
+    assert_eq!(metrics::route_label("/graphql?query={orgs{id}}"), "/graphql");
+}
+
+
+#[test]
+fn exports_the_schema() {
+    let schema = graphql::schema_language();
+    assert!(schema.starts_with("schema {\n  query: Query\n  mutation: Mutation\n}\n"));
+    assert!(schema.contains("type Org {\n  id: ID!\n  name: String!\n"));
+    assert!(schema.contains("  createLinode(name: String!, orgId: ID!): Linode!\n"));
+    assert!(!schema.contains("__"));
+}
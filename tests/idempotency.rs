@@ -0,0 +1,65 @@
+//! Claiming, replaying and forgetting idempotency keys
+extern crate org_demo;
+#[macro_use] extern crate serde_json;
+
+use std::thread;
+use std::time::Duration;
+
+use org_demo::idempotency::{self, Begin, IdempotencyStore};
+
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+
+fn claim(store: &IdempotencyStore, key: &str, fingerprint: u64) {
+    match store.begin(key, fingerprint, WINDOW) {
+        Begin::New(pending) => pending.complete(201, json!({"key": key})),
+        _ => panic!("expected {:?} to be unclaimed", key),
+    }
+}
+
+
+#[test]
+fn replays_original_status() {
+    let store = IdempotencyStore::new();
+    let fingerprint = idempotency::fingerprint("POST", "/api/create/org", "{}");
+    claim(&store, "a", fingerprint);
+    match store.begin("a", fingerprint, WINDOW) {
+        Begin::Replay(status, value) => {
+            assert_eq!(status, 201);
+            assert_eq!(value, json!({"key": "a"}));
+        }
+        _ => panic!("expected a replay"),
+    }
+    let other = idempotency::fingerprint("POST", "/api/create/org", "{\"name\": \"x\"}");
+    assert!(match store.begin("a", other, WINDOW) { Begin::Conflict => true, _ => false });
+}
+
+
+#[test]
+fn releases_failed_claims() {
+    let store = IdempotencyStore::new();
+    {
+        let pending = store.begin("a", 1, WINDOW);
+        assert!(match store.begin("a", 1, WINDOW) { Begin::InProgress => true, _ => false });
+        drop(pending);
+    }
+    claim(&store, "a", 1);
+}
+
+
+#[test]
+fn forgets_expired_and_oldest_keys() {
+    let store = IdempotencyStore::new();
+    claim(&store, "old", 1);
+    thread::sleep(Duration::from_millis(20));
+    assert!(match store.begin("old", 1, Duration::from_millis(10)) { Begin::New(_) => true, _ => false });
+
+    let store = IdempotencyStore::new();
+    for i in 0..10_000 {
+        claim(&store, &i.to_string(), 1);
+    }
+    // the oldest was evicted to make room, the newest is still remembered
+    claim(&store, "0", 1);
+    assert!(match store.begin("9999", 1, WINDOW) { Begin::Replay(..) => true, _ => false });
+}
@@ -0,0 +1,21 @@
+//! Rate limit buckets and how clients are keyed
+extern crate org_demo;
+extern crate rouille;
+
+use org_demo::limits::{self, Class, Rate, RateLimiter};
+
+
+#[test]
+fn keys_on_ip_not_authorization() {
+    let first = rouille::Request::fake_http("GET", "/api/orgs", vec![("Authorization".into(), "Bearer a".into())], vec![]);
+    let second = rouille::Request::fake_http("GET", "/api/orgs", vec![("Authorization".into(), "Bearer b".into())], vec![]);
+    let anonymous = rouille::Request::fake_http("GET", "/api/orgs", vec![], vec![]);
+    assert_eq!(limits::client_key(&first), limits::client_key(&second));
+    assert_eq!(limits::client_key(&first), limits::client_key(&anonymous));
+
+    let limiter = RateLimiter::new();
+    let rate = Rate { per_minute: 1, burst: 1 };
+    assert!(limiter.check(Class::Read, &limits::client_key(&first), &rate).is_ok());
+    assert!(limiter.check(Class::Read, &limits::client_key(&second), &rate).is_err());
+    assert!(limiter.check(Class::Mutation, &limits::client_key(&second), &rate).is_ok());
+}
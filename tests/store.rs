@@ -0,0 +1,465 @@
+//! `Repo` test suite, run against every storage backend
+//!
+//! The sqlite backend is tested against a fresh in-memory database for each case.
+//! With the `pg` feature, the same suite runs against the postgres database at
+//! `ORG_DEMO_TEST_POSTGRES` (e.g. `postgres://postgres@localhost/org_demo_test`),
+//! whose `public` schema is dropped and recreated for each case.
+extern crate org_demo;
+#[cfg(feature = "pg")] extern crate postgres;
+
+use std::fmt::Debug;
+
+use org_demo::admin::Admin;
+use org_demo::store::{self, Store, Repo};
+use org_demo::store::sqlite::SqliteBackend;
+use org_demo::errors::*;
+
+
+/// A named case of the suite
+type Case = (&'static str, fn(&Store));
+
+
+/// Cases of the suite, each run against a freshly populated store
+static CASES: &'static [Case] = &[
+    ("sample_data", sample_data),
+    ("gets_by_id", gets_by_id),
+    ("gets_batches", gets_batches),
+    ("creates", creates),
+    ("rejects_duplicates", rejects_duplicates),
+    ("updates", updates),
+    ("deletes", deletes),
+    ("deactivates", deactivates),
+    ("visits_rows", visits_rows),
+    ("events", events),
+    ("admin_records_events", admin_records_events),
+    ("webhooks", webhooks),
+    ("detaches_webhooks", detaches_webhooks),
+];
+
+
+fn run_suite<F: Fn() -> Store>(backend: &str, fresh: F) {
+    for &(name, case) in CASES {
+        println!("{}: {}", backend, name);
+        case(&fresh());
+    }
+}
+
+
+#[test]
+fn sqlite() {
+    run_suite("sqlite", || Store::new(Box::new(SqliteBackend::memory().expect("failed to open sqlite"))));
+}
+
+
+#[cfg(feature = "pg")]
+#[test]
+fn postgres() {
+    use std::env;
+    use org_demo::store::pg::{PgBackend, PgRepo};
+    use org_demo::MIGRATIONS;
+
+    let url = match env::var("ORG_DEMO_TEST_POSTGRES") {
+        Ok(url) => url,
+        Err(_) => {
+            println!("ORG_DEMO_TEST_POSTGRES is not set, skipping the postgres suite");
+            return
+        }
+    };
+    run_suite("postgres", || {
+        let backend = PgBackend::connect(&url, 2).expect("failed to connect to postgres");
+        {
+            let mut conn = backend.pool.get().expect("failed to connect to postgres");
+            conn.batch_execute("drop schema public cascade; create schema public;").unwrap();
+            conn.batch_execute(include_str!("../migrations/pg/init/up.sql")).unwrap();
+            conn.batch_execute(include_str!("../migrations/pg/events/up.sql")).unwrap();
+            conn.batch_execute(include_str!("../migrations/pg/webhooks/up.sql")).unwrap();
+            conn.batch_execute(include_str!("../migrations/pg/profiles/up.sql")).unwrap();
+            conn.batch_execute(include_str!("../migrations/pg/request-ids/up.sql")).unwrap();
+            conn.batch_execute(include_str!("../migrations/pg/webhook-detach/up.sql")).unwrap();
+            let mut trans = conn.transaction().unwrap();
+            store::insert_sample_data(&PgRepo::new(&mut trans)).unwrap();
+            trans.execute("create table __migrant_migrations(tag text unique)", &[]).unwrap();
+            for tag in MIGRATIONS {
+                trans.execute("insert into __migrant_migrations (tag) values ($1)", &[tag]).unwrap();
+            }
+            trans.commit().unwrap();
+        }
+        Store::new(Box::new(backend))
+    });
+}
+
+
+fn assert_bad_request<T: Debug>(res: Result<T>) {
+    match res {
+        Err(ref e) => match *e.kind() {
+            ErrorKind::BadRequest(_) => (),
+            ref kind => panic!("expected BadRequest, got {:?}", kind),
+        },
+        Ok(value) => panic!("expected BadRequest, got {:?}", value),
+    }
+}
+
+
+fn org_id(repo: &Repo, name: &str) -> i64 {
+    repo.get_all_orgs().unwrap().into_iter()
+        .find(|org| org.name == name)
+        .map(|org| org.id)
+        .expect("org not found")
+}
+
+
+// ------------------------------------------
+// ----------- Cases ------------------------
+// ------------------------------------------
+
+fn sample_data(store: &Store) {
+    store.with(|repo| {
+        let counts = repo.counts()?;
+        assert_eq!((counts.orgs, counts.users, counts.linodes), (4, 3, 5));
+
+        let orgs = repo.get_all_orgs()?;
+        let names = orgs.iter().map(|org| org.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["James Inc", "Bean Group", "Cat Collective", "Dog Dancers"]);
+        let cats = &orgs[2];
+        assert_eq!(cats.users.len(), 2);
+        assert_eq!(cats.linodes.len(), 2);
+
+        assert_eq!(repo.get_all_users()?.len(), 3);
+        assert_eq!(repo.get_all_linodes()?.len(), 5);
+        assert!(store::is_migrated(&repo.applied_migrations()?));
+        Ok(())
+    }).unwrap();
+}
+
+
+fn gets_by_id(store: &Store) {
+    store.with(|repo| {
+        for org in repo.get_all_orgs()? {
+            let found = repo.get_org(org.id)?.expect("org by id");
+            assert_eq!(format!("{:?}", found), format!("{:?}", org));
+        }
+        for linode in repo.get_all_linodes()? {
+            let found = repo.get_linode(linode.id)?.expect("linode by id");
+            assert_eq!((found.id, found.name, found.org), (linode.id, linode.name, linode.org));
+        }
+        assert!(repo.get_org(1000)?.is_none());
+        assert!(repo.get_linode(1000)?.is_none());
+        Ok(())
+    }).unwrap();
+}
+
+
+fn gets_batches(store: &Store) {
+    store.with(|repo| {
+        let orgs = repo.get_all_orgs()?;
+        let mut org_ids = orgs.iter().map(|org| org.id).collect::<Vec<_>>();
+        org_ids.push(1000);
+        let found = repo.get_orgs(&org_ids)?;
+        assert_eq!(format!("{:?}", found), format!("{:?}", orgs));
+
+        let user_ids = repo.get_all_users()?.iter().filter_map(|user| user.id).collect::<Vec<_>>();
+        let users = repo.get_users(&user_ids)?;
+        assert_eq!(users.len(), user_ids.len());
+        for user in &users {
+            let found = repo.get_user(user.id)?.expect("user by id");
+            assert_eq!(format!("{:?}", found), format!("{:?}", user));
+        }
+
+        let linodes = repo.get_all_linodes()?;
+        let linode_ids = linodes.iter().map(|linode| linode.id).collect::<Vec<_>>();
+        assert_eq!(repo.get_linodes(&linode_ids)?.len(), linodes.len());
+        let owned = repo.linodes_for_orgs(&org_ids[..2])?;
+        assert_eq!(owned.iter().map(|linode| linode.id).collect::<Vec<_>>(),
+                   linodes.iter().filter(|linode| linode.org.map(|org| org_ids[..2].contains(&org)).unwrap_or(false))
+                       .map(|linode| linode.id).collect::<Vec<_>>());
+
+        assert!(repo.get_orgs(&[])?.is_empty());
+        assert!(repo.get_users(&[])?.is_empty());
+        assert!(repo.linodes_for_orgs(&[])?.is_empty());
+        Ok(())
+    }).unwrap();
+}
+
+
+fn creates(store: &Store) {
+    let (org, user, linode) = store.transaction(|repo| {
+        let org = repo.create_org("Otter Ocean")?;
+        let user = repo.create_user("otter@ocean.io", &[org])?;
+        let linode = repo.create_linode("river", org)?;
+        Ok((org, user, linode))
+    }).unwrap();
+
+    store.with(|repo| {
+        assert!(repo.org_exists("Otter Ocean")?);
+        assert!(repo.user_exists("otter@ocean.io")?);
+        assert!(repo.linode_exists("river")?);
+        assert!(repo.member_exists(user, org)?);
+        assert!(!repo.linode_exists("lake")?);
+
+        let info = repo.get_user(user)?.expect("created user");
+        assert_eq!(info.email, "otter@ocean.io");
+        assert!(info.active);
+        assert_eq!(info.orgs.iter().map(|org| org.id).collect::<Vec<_>>(), [Some(org)]);
+        assert_eq!(info.linodes.iter().map(|linode| linode.id).collect::<Vec<_>>(), [Some(linode)]);
+        assert!(repo.get_user(user + 1000)?.is_none());
+
+        let found = repo.find_user_by_email("otter@ocean.io")?.expect("created user");
+        assert_eq!(found.id, Some(user));
+        assert!(repo.find_user_by_email("lake@ocean.io")?.is_none());
+
+        let linodes = repo.get_all_linodes()?;
+        let created = linodes.iter().find(|l| l.id == linode).expect("created linode");
+        assert_eq!(created.org, Some(org));
+        Ok(())
+    }).unwrap();
+}
+
+
+fn rejects_duplicates(store: &Store) {
+    store.transaction(|repo| {
+        let org = org_id(repo, "James Inc");
+        let user = repo.find_user_by_email("james@kominick.com")?.and_then(|user| user.id).expect("sample user");
+        assert_bad_request(repo.create_org("James Inc"));
+        assert_bad_request(repo.create_user("james@kominick.com", &[]));
+        assert_bad_request(repo.create_linode("charlie", org));
+        assert_bad_request(repo.add_member(user, org));
+        assert_bad_request(repo.change_org_name(org, "Bean Group"));
+        assert_bad_request(repo.change_user_email(user, "bean@burrito.org"));
+        Ok(())
+    }).unwrap();
+}
+
+
+fn updates(store: &Store) {
+    let user = store.transaction(|repo| {
+        let org = org_id(repo, "James Inc");
+        repo.change_org_name(org, "Jim Inc")?;
+
+        let user = repo.create_user("otter@ocean.io", &[])?;
+        repo.update_user(user, Some("otter@sea.io"), Some(" Otto "))?;
+
+        let linode = repo.get_all_linodes()?.into_iter().find(|l| l.name == "charlie").expect("sample linode");
+        let dogs = org_id(repo, "Dog Dancers");
+        repo.update_linode(linode.id, Some("charles"), Some(dogs))?;
+        Ok(user)
+    }).unwrap();
+
+    store.with(|repo| {
+        assert!(repo.org_exists("Jim Inc")?);
+        assert!(!repo.org_exists("James Inc")?);
+
+        let info = repo.get_user(user)?.expect("created user");
+        assert_eq!(info.email, "otter@sea.io");
+        assert_eq!(info.display_name, Some("Otto".to_string()));
+
+        let linode = repo.get_all_linodes()?.into_iter().find(|l| l.name == "charles").expect("renamed linode");
+        assert_eq!(linode.org, Some(org_id(repo, "Dog Dancers")));
+        Ok(())
+    }).unwrap();
+
+    // an empty display name clears it
+    store.transaction(|repo| repo.update_user(user, None, Some(""))).unwrap();
+    let info = store.with(|repo| repo.get_user(user)).unwrap().expect("created user");
+    assert_eq!(info.display_name, None);
+}
+
+
+fn deletes(store: &Store) {
+    store.transaction(|repo| {
+        let cats = org_id(repo, "Cat Collective");
+        repo.delete_org(cats)?;
+
+        let bean = repo.find_user_by_email("bean@burrito.org")?.and_then(|user| user.id).expect("sample user");
+        repo.delete_user(bean)?;
+
+        let james = repo.find_user_by_email("james@kominick.com")?.and_then(|user| user.id).expect("sample user");
+        let dogs = org_id(repo, "Dog Dancers");
+        repo.delete_member(james, dogs)?;
+
+        let mac = repo.get_all_linodes()?.into_iter().find(|l| l.name == "mac").expect("sample linode");
+        repo.delete_linode(mac.id)
+    }).unwrap();
+
+    store.with(|repo| {
+        let counts = repo.counts()?;
+        // the org's linodes go with it
+        assert_eq!((counts.orgs, counts.users, counts.linodes), (3, 2, 2));
+        assert!(!repo.user_exists("bean@burrito.org")?);
+        let james = repo.find_user_by_email("james@kominick.com")?.and_then(|user| user.id).expect("sample user");
+        assert!(!repo.member_exists(james, org_id(repo, "Dog Dancers"))?);
+        let dogs = repo.get_all_orgs()?.into_iter().find(|org| org.name == "Dog Dancers").expect("sample org");
+        assert!(dogs.users.is_empty());
+        Ok(())
+    }).unwrap();
+}
+
+
+fn deactivates(store: &Store) {
+    let cheese = store.with(|repo| {
+        Ok(repo.find_user_by_email("cheese@pasta.io")?.and_then(|user| user.id).expect("sample user"))
+    }).unwrap();
+
+    let seen = store.transaction(|repo| repo.authenticate("cheese@pasta.io")).unwrap();
+    assert!(seen.last_seen.is_some());
+
+    store.transaction(|repo| repo.set_user_active(cheese, false)).unwrap();
+    store.with(|repo| {
+        let info = repo.get_user(cheese)?.expect("sample user");
+        assert!(!info.active);
+        assert!(info.linodes.is_empty());
+        // deactivated users aren't listed as members
+        let bean_group = repo.get_all_orgs()?.into_iter().find(|org| org.name == "Bean Group").expect("sample org");
+        assert!(bean_group.users.iter().all(|user| user.id != Some(cheese)));
+        Ok(())
+    }).unwrap();
+
+    match store.transaction(|repo| repo.authenticate("cheese@pasta.io")) {
+        Err(ref e) => match *e.kind() {
+            ErrorKind::Forbidden(_) => (),
+            ref kind => panic!("expected Forbidden, got {:?}", kind),
+        },
+        Ok(user) => panic!("deactivated user authenticated: {:?}", user),
+    }
+
+    store.transaction(|repo| repo.set_user_active(cheese, true)).unwrap();
+    let info = store.transaction(|repo| repo.authenticate("cheese@pasta.io")).unwrap();
+    assert_eq!(info.linodes.len(), 3);
+}
+
+
+fn visits_rows(store: &Store) {
+    store.with(|repo| {
+        // two at a time, each page starting after the last id of the one before
+        let mut orgs = vec![];
+        let mut after = i64::min_value();
+        loop {
+            let last = repo.each_org(after, 2, &mut |org| { orgs.push(org.id); Ok(()) })?;
+            match last {
+                Some(last) => {
+                    assert_eq!(orgs.last(), Some(&last));
+                    after = last;
+                }
+                None => break,
+            }
+        }
+        assert_eq!(orgs, repo.get_all_orgs()?.into_iter().map(|org| org.id).collect::<Vec<_>>());
+        assert_eq!(orgs.len(), 4);
+
+        let mut users = vec![];
+        assert!(repo.each_user(i64::min_value(), 2, &mut |user| { users.push(user.id); Ok(()) })?.is_some());
+        let all = repo.get_all_users()?.into_iter().map(|user| user.id).collect::<Vec<_>>();
+        assert_eq!(users, &all[..2]);
+        let last = all.last().unwrap().unwrap();
+        assert_eq!(repo.each_user(last, 2, &mut |_| panic!("no users after the last"))?, None);
+
+        let mut linodes = vec![];
+        let last = repo.each_linode(2, 100, &mut |linode| { linodes.push(linode.id); Ok(()) })?;
+        assert_eq!(linodes, [3, 4, 5]);
+        assert_eq!(last, Some(5));
+        Ok(())
+    }).unwrap();
+}
+
+
+fn events(store: &Store) {
+    store.transaction(|repo| {
+        assert_eq!(repo.last_event_id()?, 0);
+        let first = repo.insert_event("org.created", r#"{"id":1}"#, Some("3f2a9c"))?;
+        let second = repo.insert_event("org.deleted", r#"{"id":1}"#, None)?;
+        assert_eq!(repo.last_event_id()?, second);
+
+        let events = repo.events_after(0, 10)?;
+        assert_eq!(events.iter().map(|e| e.id).collect::<Vec<_>>(), [first, second]);
+        assert_eq!(events[0].kind, "org.created");
+        assert_eq!(events[0].request_id, Some("3f2a9c".to_string()));
+        assert_eq!(events[1].request_id, None);
+        assert_eq!(repo.events_after(first, 10)?.len(), 1);
+        assert_eq!(repo.events_after(0, 1)?.len(), 1);
+
+        repo.delete_events_through(first)?;
+        assert_eq!(repo.events_after(0, 10)?.iter().map(|e| e.id).collect::<Vec<_>>(), [second]);
+        Ok(())
+    }).unwrap();
+}
+
+
+fn admin_records_events(store: &Store) {
+    let org = store.create_org("Otter Ocean").unwrap();
+    let user = store.create_user("otter@ocean.io", &[org]).unwrap();
+    store.set_user_active(user, false).unwrap();
+    store.delete_user(user).unwrap();
+    store.delete_org(org).unwrap();
+
+    store.with(|repo| {
+        let events = repo.events_after(0, 10)?;
+        let kinds = events.iter().map(|e| e.kind.as_str()).collect::<Vec<_>>();
+        assert_eq!(kinds, ["org.created", "user.created", "user.deactivated", "user.deleted", "org.deleted"]);
+        assert!(events[0].data.contains("Otter Ocean"));
+        assert!(events.iter().all(|e| e.request_id.is_none()));
+        Ok(())
+    }).unwrap();
+}
+
+
+fn webhooks(store: &Store) {
+    store.transaction(|repo| {
+        let cats = org_id(repo, "Cat Collective");
+        let hook = repo.insert_webhook("http://hooks.example.com", "secret", Some(cats), "org.created,linode.created")?;
+        let webhooks = repo.get_webhooks()?;
+        assert_eq!(webhooks.len(), 1);
+        assert_eq!(webhooks[0].org, Some(cats));
+        assert_eq!(webhooks[0].secret, "secret");
+        assert_eq!(webhooks[0].events, ["org.created", "linode.created"]);
+
+        let event = repo.insert_event("org.created", "{}", None)?;
+        let delivery = repo.insert_delivery(hook, event, "org.created", "{}", 100)?;
+        assert!(repo.due_deliveries(99, 10)?.is_empty());
+        assert_eq!(repo.due_deliveries(100, 10)?.iter().map(|d| d.id).collect::<Vec<_>>(), [delivery]);
+
+        repo.update_delivery(delivery, "dead", 5, 100, Some("connection refused"))?;
+        let dead = repo.get_deliveries(Some("dead"), 10)?;
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].attempts, 5);
+        assert_eq!(dead[0].last_error, Some("connection refused".to_string()));
+        assert!(repo.due_deliveries(200, 10)?.is_empty());
+
+        assert_eq!(repo.replay_dead_deliveries(200)?, 1);
+        let pending = repo.due_deliveries(200, 10)?;
+        assert_eq!((pending[0].status.as_str(), pending[0].attempts), ("pending", 0));
+
+        repo.delete_webhook(hook)?;
+        assert!(repo.get_webhooks()?.is_empty());
+        assert!(repo.get_deliveries(None, 10)?.is_empty());
+        Ok(())
+    }).unwrap();
+}
+
+
+fn detaches_webhooks(store: &Store) {
+    store.transaction(|repo| {
+        let cats = org_id(repo, "Cat Collective");
+        let dogs = org_id(repo, "Dog Dancers");
+        let global = repo.insert_webhook("http://hooks.example.com/all", "secret", None, "")?;
+        let cat_hook = repo.insert_webhook("http://hooks.example.com/cats", "secret", Some(cats), "")?;
+        let dog_hook = repo.insert_webhook("http://hooks.example.com/dogs", "secret", Some(dogs), "")?;
+
+        let ids = |orgs: &[i64]| -> Result<Vec<i64>> {
+            Ok(repo.webhooks_for_orgs(orgs)?.iter().map(|webhook| webhook.id).collect())
+        };
+        assert_eq!(ids(&[])?, [global]);
+        assert_eq!(ids(&[cats])?, [global, cat_hook]);
+        assert_eq!(ids(&[cats, dogs])?, [global, cat_hook, dog_hook]);
+
+        let event = repo.insert_event("org.deleted", "{}", None)?;
+        let delivery = repo.insert_delivery(cat_hook, event, "org.deleted", "{}", 100)?;
+        repo.delete_org(cats)?;
+
+        // detached, not deleted, and no longer matched as a global webhook
+        let detached = repo.get_webhooks()?.into_iter().find(|webhook| webhook.id == cat_hook).expect("detached webhook");
+        assert_eq!((detached.org, detached.detached_org), (None, Some(cats)));
+        assert_eq!(ids(&[])?, [global]);
+        assert_eq!(ids(&[cats, dogs])?, [global, dog_hook]);
+        assert_eq!(repo.due_deliveries(100, 10)?.iter().map(|d| d.id).collect::<Vec<_>>(), [delivery]);
+        Ok(())
+    }).unwrap();
+}
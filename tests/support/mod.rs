@@ -0,0 +1,38 @@
+//! Helpers shared by the integration tests
+#![allow(dead_code)]
+use std::thread;
+
+use rouille;
+
+use org_demo::config::{Config, Backend};
+use org_demo::service::{self, State};
+
+
+/// Configuration of a throwaway in-memory server without rate limits
+pub fn config() -> Config {
+    Config {
+        backend: Backend::Memory,
+        rate_limit: 0,
+        mutation_rate_limit: 0,
+        ..Config::default()
+    }
+}
+
+
+/// Serve `handler` on an ephemeral local port, returning its base url
+pub fn serve_fn<F>(handler: F) -> String
+    where F: Fn(&rouille::Request) -> rouille::Response + Send + Sync + 'static
+{
+    let server = rouille::Server::new("127.0.0.1:0", handler).expect("failed to bind test server");
+    let url = format!("http://{}", server.server_addr());
+    thread::spawn(move || server.run());
+    url
+}
+
+
+/// Serve the api with `config` on an ephemeral local port
+pub fn serve(config: Config) -> (State, String) {
+    let state = service::build_state(config).expect("failed to build server state");
+    let url = serve_fn(service::build_handler(state.clone()));
+    (state, url)
+}
@@ -0,0 +1,216 @@
+//! Serving https with certificates generated here, and re-binding on reload
+extern crate org_demo;
+#[macro_use] extern crate rouille;
+#[cfg(feature = "tls")] extern crate openssl;
+
+use std::env;
+use std::fs;
+use std::sync;
+
+use org_demo::config::Config;
+use org_demo::tls::{self, Identity};
+
+
+#[test]
+fn loads_identity() {
+    let config = Config::default();
+    assert!(Identity::load(&config).unwrap().is_none());
+
+    let cert_only = Config {
+        tls_cert: Some(env::temp_dir().join("org-demo-test-cert-only.pem")),
+        ..Config::default()
+    };
+    assert!(Identity::load(&cert_only).is_err());
+
+    let missing = Config {
+        tls_cert: Some(env::temp_dir().join("org-demo-test-missing-cert.pem")),
+        tls_key: Some(env::temp_dir().join("org-demo-test-missing-key.pem")),
+        ..Config::default()
+    };
+    assert!(Identity::load(&missing).is_err());
+}
+
+
+#[test]
+fn redirects_to_https() {
+    let redirect = tls::redirect_handler(8443);
+    let request = rouille::Request::fake_http("GET", "/api/orgs?page=2",
+                                              vec![("Host".into(), "example.com:8080".into())], vec![]);
+    let resp = redirect(&request);
+    assert_eq!(resp.status_code, 301);
+    let location = resp.headers.iter().find(|&&(ref k, _)| k == "Location").map(|&(_, ref v)| v.to_string());
+    assert_eq!(location, Some("https://example.com:8443/api/orgs?page=2".to_string()));
+
+    let redirect = tls::redirect_handler(443);
+    let request = rouille::Request::fake_http("GET", "/", vec![("Host".into(), "[::1]".into())], vec![]);
+    let resp = redirect(&request);
+    let location = resp.headers.iter().find(|&&(ref k, _)| k == "Location").map(|&(_, ref v)| v.to_string());
+    assert_eq!(location, Some("https://[::1]/".to_string()));
+
+    let request = rouille::Request::fake_http("GET", "/", vec![], vec![]);
+    assert_eq!(redirect(&request).status_code, 400);
+}
+
+
+#[cfg(feature = "tls")]
+mod https {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+    use openssl::x509::{X509, X509NameBuilder};
+
+    use org_demo::service::Listener;
+    use org_demo::tls::Identity;
+
+    use super::{handler, loads_from_files};
+
+
+    /// Self-signed certificate with the common name `cn`
+    fn identity(cn: &str) -> Identity {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", cn).unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+        Identity {
+            certificate: cert.build().to_pem().unwrap(),
+            private_key: key.private_key_to_pem_pkcs8().unwrap(),
+        }
+    }
+
+
+    /// `GET /healthz` over https, returning the common name of the
+    /// certificate that was served and the response's status line
+    fn get(addr: SocketAddr) -> (String, String) {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let mut stream = connector.build().connect("localhost", stream).unwrap();
+
+        let cn = {
+            let cert = stream.ssl().peer_certificate().expect("no peer certificate");
+            let entry = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next().expect("no common name");
+            entry.data().to_string().unwrap()
+        };
+        stream.write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).ok();
+        let status = resp.lines().next().unwrap_or("").to_string();
+        (cn, status)
+    }
+
+
+    /// Make a request from another thread while polling `listener` on this one
+    fn request(listener: &mut Listener) -> (String, String) {
+        let addr = listener.server_addr();
+        let (send, recv) = mpsc::channel();
+        thread::spawn(move || send.send(get(addr)).unwrap());
+        for _ in 0..1000 {
+            listener.poll();
+            if let Ok(resp) = recv.try_recv() { return resp }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("request to {} timed out", addr);
+    }
+
+
+    fn assert_served_by(listener: &mut Listener, cn: &str) {
+        let (served, status) = request(listener);
+        assert_eq!(served, cn);
+        assert!(status.starts_with("HTTP/1.1 200"), "unexpected status: {:?}", status);
+    }
+
+
+    #[test]
+    fn serves_https() {
+        let mut listener = Listener::bind("127.0.0.1:0", handler(), Some(identity("first"))).unwrap();
+        assert!(listener.is_https());
+        assert_served_by(&mut listener, "first");
+    }
+
+
+    #[test]
+    fn reloads_certificate_on_the_same_port() {
+        let mut listener = Listener::bind("127.0.0.1:0", handler(), Some(identity("first"))).unwrap();
+        let addr = listener.server_addr();
+        assert_served_by(&mut listener, "first");
+
+        listener.reload(Some(identity("renewed"))).unwrap();
+        assert_eq!(listener.server_addr(), addr);
+        assert_served_by(&mut listener, "renewed");
+    }
+
+
+    #[test]
+    fn keeps_serving_on_bad_certificate() {
+        let mut listener = Listener::bind("127.0.0.1:0", handler(), Some(identity("first"))).unwrap();
+        let bad = Identity { certificate: b"not a certificate".to_vec(), private_key: b"not a key".to_vec() };
+        assert!(listener.reload(Some(bad)).is_err());
+        assert_served_by(&mut listener, "first");
+    }
+
+
+    #[test]
+    fn loads_generated_files() {
+        let generated = identity("from-files");
+        let loaded = loads_from_files("generated", &generated.certificate, &generated.private_key);
+        assert_eq!(loaded.certificate, generated.certificate);
+        assert_eq!(loaded.private_key, generated.private_key);
+
+        let mut listener = Listener::bind("127.0.0.1:0", handler(), Some(loaded)).unwrap();
+        assert_served_by(&mut listener, "from-files");
+    }
+}
+
+
+/// Handler answering `/healthz`, like the api's
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+fn handler() -> org_demo::service::Handler {
+    sync::Arc::new(|request: &rouille::Request| {
+        router!(request,
+            (GET) ["/healthz"] => { rouille::Response::text("ok") },
+            _ => rouille::Response::empty_404()
+        )
+    })
+}
+
+
+/// Write `certificate` and `private_key` to temp files and load them back
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+fn loads_from_files(name: &str, certificate: &[u8], private_key: &[u8]) -> Identity {
+    let cert_path = env::temp_dir().join(format!("org-demo-test-{}-cert.pem", name));
+    let key_path = env::temp_dir().join(format!("org-demo-test-{}-key.pem", name));
+    fs::write(&cert_path, certificate).unwrap();
+    fs::write(&key_path, private_key).unwrap();
+
+    let config = Config {
+        tls_cert: Some(cert_path.clone()),
+        tls_key: Some(key_path.clone()),
+        ..Config::default()
+    };
+    let loaded = Identity::load(&config);
+    fs::remove_file(&cert_path).ok();
+    fs::remove_file(&key_path).ok();
+    loaded.unwrap().expect("tls is configured")
+}
@@ -0,0 +1,293 @@
+//! Webhook subscriptions and deliveries to a local receiver
+extern crate org_demo;
+extern crate rouille;
+#[macro_use] extern crate serde_json;
+
+mod support;
+
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use org_demo::config::Config;
+use org_demo::service::{self, State};
+use org_demo::webhooks;
+
+
+static TOKEN: &'static str = "admin-token";
+
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("clock before epoch").as_secs() as i64
+}
+
+
+fn config() -> Config {
+    let mut config = support::config();
+    config.admin_token = Some(TOKEN.into());
+    config.webhook_allow_private_hosts = true;
+    config.webhook_backoff = 0;
+    config.webhook_max_attempts = 2;
+    config
+}
+
+
+/// Request received by the `Receiver`: (timestamp, signature, body)
+type Received = (String, String, String);
+
+
+/// Local webhook receiver, answering with `status`
+struct Receiver {
+    url: String,
+    status: Arc<AtomicUsize>,
+    received: Arc<Mutex<Vec<Received>>>,
+}
+impl Receiver {
+    fn start() -> Self {
+        let status = Arc::new(AtomicUsize::new(200));
+        let received = Arc::new(Mutex::new(vec![]));
+        let url = {
+            let status = status.clone();
+            let received = received.clone();
+            support::serve_fn(move |request| {
+                let mut body = String::new();
+                request.data().expect("body").read_to_string(&mut body).unwrap();
+                let timestamp = request.header("X-OrgDemo-Timestamp").unwrap_or("").to_string();
+                let signature = request.header("X-OrgDemo-Signature").unwrap_or("").to_string();
+                received.lock().unwrap().push((timestamp, signature, body));
+                rouille::Response::text("").with_status_code(status.load(Ordering::SeqCst) as u16)
+            })
+        };
+        Self { url: format!("{}/hook", url), status: status, received: received }
+    }
+
+    fn respond_with(&self, status: u16) {
+        self.status.store(status as usize, Ordering::SeqCst);
+    }
+
+    fn received(&self) -> Vec<Received> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+
+/// Send a request through the api handler, returning the status and json body
+fn call(state: &State, method: &str, url: &str, token: Option<&str>, body: Value) -> (u16, Value) {
+    let mut headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+    if let Some(token) = token {
+        headers.push(("Authorization".to_string(), format!("Bearer {}", token)));
+    }
+    let request = rouille::Request::fake_http(method, url, headers, body.to_string().into_bytes());
+    let resp = service::build_handler(state.clone())(&request);
+    let status = resp.status_code;
+    let (mut reader, _) = resp.data.into_reader_and_size();
+    let mut body = String::new();
+    reader.read_to_string(&mut body).unwrap();
+    (status, serde_json::from_str(&body).unwrap_or(Value::Null))
+}
+
+
+fn create_webhook(state: &State, body: Value) -> (i64, String) {
+    let (status, created) = call(state, "POST", "/api/admin/create/webhook", Some(TOKEN), body);
+    assert_eq!(status, 200, "{}", created);
+    (created["webhook_id"].as_i64().unwrap(), created["secret"].as_str().unwrap().to_string())
+}
+
+
+fn run_due(state: &State) {
+    let http = webhooks::http_client(&state.config()).unwrap();
+    webhooks::run_due(state, &http).unwrap();
+}
+
+
+fn deliveries(state: &State, status: Option<&str>) -> Vec<Value> {
+    let url = match status {
+        Some(status) => format!("/api/admin/deliveries?status={}", status),
+        None => "/api/admin/deliveries".to_string(),
+    };
+    let (code, body) = call(state, "GET", &url, Some(TOKEN), Value::Null);
+    assert_eq!(code, 200);
+    body["deliveries"].as_array().unwrap().clone()
+}
+
+
+#[test]
+fn requires_admin_token() {
+    let state = service::build_state(config()).unwrap();
+    assert_eq!(call(&state, "GET", "/api/admin/webhooks", None, Value::Null).0, 403);
+    assert_eq!(call(&state, "GET", "/api/admin/webhooks", Some("wrong"), Value::Null).0, 403);
+    assert_eq!(call(&state, "GET", "/api/admin/webhooks", Some(TOKEN), Value::Null).0, 200);
+
+    let mut disabled = config();
+    disabled.admin_token = None;
+    let state = service::build_state(disabled).unwrap();
+    assert_eq!(call(&state, "GET", "/api/admin/webhooks", Some(TOKEN), Value::Null).0, 403);
+}
+
+
+#[test]
+fn validates_urls() {
+    let mut config = config();
+    config.webhook_allow_private_hosts = false;
+    let state = service::build_state(config).unwrap();
+    for url in &["ftp://hooks.example.com", "not a url", "http://127.0.0.1:9000/hook", "http://10.1.2.3/hook",
+                 "http://169.254.169.254/latest", "http://[::1]/hook", "http://[::ffff:192.168.0.1]/hook",
+                 "http://localhost/hook"] {
+        let (status, _) = call(&state, "POST", "/api/admin/create/webhook", Some(TOKEN), json!({"url": url}));
+        assert_eq!(status, 400, "{} should be rejected", url);
+    }
+    assert!(webhooks::check_url("http://127.0.0.1:9000/hook", true).is_ok());
+    assert!(webhooks::check_url("ftp://127.0.0.1/hook", true).is_err());
+}
+
+
+#[test]
+fn generates_secrets() {
+    let state = service::build_state(config()).unwrap();
+    let (_, first) = create_webhook(&state, json!({"url": "http://127.0.0.1:1/hook"}));
+    let (_, second) = create_webhook(&state, json!({"url": "http://127.0.0.1:1/hook"}));
+    assert_eq!(first.len(), 64);
+    assert!(first.chars().all(|c| c.is_digit(16)));
+    assert_ne!(first, second);
+}
+
+
+#[test]
+fn delivers_signed_events() {
+    let receiver = Receiver::start();
+    let state = service::build_state(config()).unwrap();
+    let (_, secret) = create_webhook(&state, json!({"url": receiver.url, "events": ["org.*"]}));
+
+    call(&state, "POST", "/api/create/org", None, json!({"name": "Otter Ocean"}));
+    call(&state, "POST", "/api/create/user", None, json!({"email": "otter@ocean.io", "org_ids": [1]}));
+    run_due(&state);
+
+    let received = receiver.received();
+    assert_eq!(received.len(), 1);
+    let (ref timestamp, ref signature, ref body) = received[0];
+    let timestamp = timestamp.parse::<i64>().unwrap();
+    assert!((now() - timestamp).abs() < 60);
+    assert_eq!(*signature, format!("sha256={}", webhooks::sign(&secret, timestamp, body)));
+    let payload = serde_json::from_str::<Value>(body).unwrap();
+    assert_eq!(payload["kind"], "org.created");
+    assert_eq!(payload["data"]["name"], "Otter Ocean");
+    assert_eq!(deliveries(&state, Some("delivered")).len(), 1);
+}
+
+
+#[test]
+fn retries_dead_letters_and_replays() {
+    let receiver = Receiver::start();
+    receiver.respond_with(500);
+    let state = service::build_state(config()).unwrap();
+    create_webhook(&state, json!({"url": receiver.url}));
+    call(&state, "POST", "/api/create/org", None, json!({"name": "Otter Ocean"}));
+
+    // with no backoff the retry is due right away
+    run_due(&state);
+    let pending = deliveries(&state, Some("pending"));
+    assert_eq!(pending[0]["attempts"], 1);
+    assert!(pending[0]["last_error"].as_str().unwrap().contains("500"));
+    run_due(&state);
+    assert_eq!(deliveries(&state, Some("dead")).len(), 1);
+    run_due(&state);
+    assert_eq!(receiver.received().len(), 2);
+
+    receiver.respond_with(200);
+    let (status, replayed) = call(&state, "POST", "/api/admin/replay/deliveries", Some(TOKEN), Value::Null);
+    assert_eq!((status, replayed["replayed"].as_u64()), (200, Some(1)));
+    run_due(&state);
+    assert_eq!(receiver.received().len(), 3);
+    assert_eq!(deliveries(&state, Some("delivered")).len(), 1);
+}
+
+
+#[test]
+fn backs_off_between_attempts() {
+    let receiver = Receiver::start();
+    receiver.respond_with(503);
+    let state = service::build_state(config()).unwrap();
+    state.config.write().unwrap().webhook_backoff = 30;
+    create_webhook(&state, json!({"url": receiver.url}));
+    call(&state, "POST", "/api/create/org", None, json!({"name": "Otter Ocean"}));
+
+    let before = now();
+    run_due(&state);
+    let pending = deliveries(&state, Some("pending"));
+    let next_attempt = pending[0]["next_attempt"].as_i64().unwrap();
+    assert!(next_attempt >= before + 30 && next_attempt <= now() + 30);
+    // not due again yet
+    run_due(&state);
+    assert_eq!(receiver.received().len(), 1);
+
+    let id = pending[0]["id"].as_i64().unwrap();
+    call(&state, "POST", &format!("/api/admin/replay/delivery/{}", id), Some(TOKEN), Value::Null);
+    run_due(&state);
+    assert_eq!(receiver.received().len(), 2);
+}
+
+
+#[test]
+fn notifies_orgs_of_linodes_and_deletes() {
+    let receiver = Receiver::start();
+    let state = service::build_state(config()).unwrap();
+    let (_, org) = call(&state, "POST", "/api/create/org", None, json!({"name": "Otter Ocean"}));
+    let org = org["org_id"].as_i64().unwrap();
+    let (_, other) = call(&state, "POST", "/api/create/org", None, json!({"name": "Seal Sea"}));
+    let other = other["org_id"].as_i64().unwrap();
+    create_webhook(&state, json!({"url": receiver.url, "org_id": org}));
+
+    let (_, linode) = call(&state, "POST", "/api/create/linode", None, json!({"name": "river", "org_id": org}));
+    let linode = linode["linode_id"].as_i64().unwrap();
+    // moved away, then deleted from the other org: both concern the webhook's org
+    call(&state, "POST", &format!("/api/update/linode/{}", linode), None, json!({"org_id": other}));
+    call(&state, "POST", "/api/create/linode", None, json!({"name": "lake", "org_id": org}));
+    let (status, _) = call(&state, "POST", &format!("/api/delete/org/{}", org), None, Value::Null);
+    assert_eq!(status, 200);
+    run_due(&state);
+
+    let kinds = receiver.received().iter()
+        .map(|&(_, _, ref body)| serde_json::from_str::<Value>(body).unwrap())
+        .map(|payload| {
+            if payload["kind"] == "linode.updated" {
+                assert_eq!(payload["data"]["previous_org_id"], org);
+                assert_eq!(payload["data"]["org_id"], other);
+            }
+            payload["kind"].as_str().unwrap().to_string()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(kinds, ["linode.created", "linode.updated", "linode.created", "org.deleted"]);
+
+    let (_, webhooks) = call(&state, "GET", "/api/admin/webhooks", Some(TOKEN), Value::Null);
+    assert_eq!(webhooks["webhooks"][0]["detached_org"], org);
+    assert_eq!(webhooks["webhooks"][0]["org"], Value::Null);
+
+    // detached webhooks don't become global ones
+    call(&state, "POST", "/api/create/org", None, json!({"name": "Walrus Waves"}));
+    run_due(&state);
+    assert_eq!(receiver.received().len(), 4);
+}
+
+
+#[test]
+fn deleted_linodes_carry_their_org() {
+    let receiver = Receiver::start();
+    let state = service::build_state(config()).unwrap();
+    let (_, org) = call(&state, "POST", "/api/create/org", None, json!({"name": "Otter Ocean"}));
+    let org = org["org_id"].as_i64().unwrap();
+    let (_, linode) = call(&state, "POST", "/api/create/linode", None, json!({"name": "river", "org_id": org}));
+    let linode = linode["linode_id"].as_i64().unwrap();
+    create_webhook(&state, json!({"url": receiver.url, "org_id": org}));
+
+    let (status, _) = call(&state, "POST", &format!("/api/delete/linode/{}", linode), None, Value::Null);
+    assert_eq!(status, 200);
+    run_due(&state);
+    let received = receiver.received();
+    assert_eq!(received.len(), 1);
+    let payload = serde_json::from_str::<Value>(&received[0].2).unwrap();
+    assert_eq!(payload["kind"], "linode.deleted");
+    assert_eq!(payload["data"]["org_id"], org);
+}